@@ -1,14 +1,14 @@
-use std::hash::{Hash, Hasher};
 use std::path::{Path, PathBuf};
 use std::process::{Command, ExitStatus};
 use std::{env, fmt};
 
-use toml::{map::Map, Value};
+use toml::{map::Map, value::Table, Value};
 
 use cli::Args;
 use errors::*;
-use extensions::CommandExt;
-use sysroot::XargoMode;
+use extensions::{CommandExt, Verbosity};
+use rustc::{self, Toolchain};
+use sysroot::{HashInputs, XargoMode};
 use util;
 use xargo::Home;
 
@@ -18,28 +18,32 @@ pub struct Rustflags {
 }
 
 impl Rustflags {
-    pub fn hash<H>(&self, hasher: &mut H)
-    where
-        H: Hasher,
-    {
+    pub fn hash(&self, inputs: &mut HashInputs) {
+        let mut hashed = vec![];
         let mut flags = self.flags.iter();
 
         while let Some(flag) = flags.next() {
             if flag == "-C" {
                 if let Some(next) = flags.next() {
-                    if next.starts_with("link-arg=") || next.starts_with("link-args=") {
-                        // don't hash linker arguments
+                    if next.starts_with("link-arg=")
+                        || next.starts_with("link-args=")
+                        || next.starts_with("linker=")
+                    {
+                        // don't hash the linker, nor its arguments: the
+                        // sysroot is made of rlibs, which aren't linked
                     } else {
-                        flag.hash(hasher);
-                        next.hash(hasher);
+                        hashed.push(&**flag);
+                        hashed.push(&**next);
                     }
                 } else {
-                    flag.hash(hasher);
+                    hashed.push(&**flag);
                 }
             } else {
-                flag.hash(hasher);
+                hashed.push(&**flag);
             }
         }
+
+        inputs.add("rustflags", &hashed, hashed.join(" "));
     }
 
     pub fn push(&mut self, flags: &[&str]) {
@@ -86,123 +90,220 @@ pub fn rustdocflags(config: Option<&Config>, target: &str) -> Result<Rustdocflag
 
 /// Returns the flags for `tool` (e.g. rustflags)
 ///
-/// This looks into the environment and into `.cargo/config`
+/// This looks into the environment and into `.cargo/config(.toml)`, in the
+/// same order as Cargo: `CARGO_ENCODED_RUSTFLAGS`, `RUSTFLAGS`,
+/// `target.<triple>.rustflags`, and finally `build.rustflags`, which
+/// `CARGO_BUILD_RUSTFLAGS` overrides.
 fn flags(config: Option<&Config>, target: &str, tool: &str) -> Result<Vec<String>> {
-    // TODO: would be nice to also support the CARGO_ENCODED_ env vars
-    if let Some(t) = env::var_os(tool.to_uppercase()) {
-        return Ok(t
-            .to_string_lossy()
-            .split_whitespace()
-            .map(|w| w.to_owned())
-            .collect());
-    }
-
-    if let Some(config) = config.as_ref() {
-        let mut build = false;
-        if let Some(array) = config
+    let var = tool.to_uppercase();
+
+    if let Some(t) = env::var_os(format!("CARGO_ENCODED_{}", var)) {
+        let t = t.to_string_lossy();
+
+        // An empty string means no flags rather than one empty flag
+        return Ok(if t.is_empty() {
+            vec![]
+        } else {
+            t.split('\x1f').map(|w| w.to_owned()).collect()
+        });
+    }
+
+    if let Some(t) = env::var_os(&var) {
+        return Ok(split(&t.to_string_lossy()));
+    }
+
+    if let Some(config) = config {
+        if let Some(value) = config
             .table
             .get("target")
             .and_then(|t| t.get(target))
             .and_then(|t| t.get(tool))
-            .or_else(|| {
-                build = true;
-                config.table.get("build").and_then(|t| t.get(tool))
-            })
         {
-            let mut flags = vec![];
+            return config.strings(value, &format!("target.{}.{}", target, tool));
+        }
+    }
 
-            let mut error = false;
-            if let Some(array) = array.as_array() {
-                for value in array {
-                    if let Some(flag) = value.as_str() {
-                        flags.push(flag.to_owned());
-                    } else {
-                        error = true;
-                        break;
-                    }
-                }
-            } else {
-                error = true;
-            }
+    if let Some(t) = env::var_os(format!("CARGO_BUILD_{}", var)) {
+        return Ok(split(&t.to_string_lossy()));
+    }
 
-            if error {
-                if build {
-                    Err(format!(
-                        ".cargo/config: build.{} must be an array \
-                         of strings",
-                        tool
-                    ))?
-                } else {
-                    Err(format!(
-                        ".cargo/config: target.{}.{} must be an \
-                         array of strings",
-                        target, tool
-                    ))?
-                }
-            } else {
-                Ok(flags)
-            }
-        } else {
-            Ok(vec![])
+    if let Some(config) = config {
+        if let Some(value) = config.table.get("build").and_then(|t| t.get(tool)) {
+            return config.strings(value, &format!("build.{}", tool));
         }
-    } else {
-        Ok(vec![])
     }
+
+    Ok(vec![])
+}
+
+/// Splits a space separated list of flags, like Cargo does for `RUSTFLAGS`
+fn split(flags: &str) -> Vec<String> {
+    flags.split_whitespace().map(|w| w.to_owned()).collect()
 }
 
 pub fn command() -> Command {
+    if let Some(cmd) = rustc::pinned(Toolchain::cargo) {
+        return cmd;
+    }
+
     env::var_os("CARGO")
         .map(Command::new)
         .unwrap_or_else(|| Command::new("cargo"))
 }
 
-pub fn run(args: &Args, verbose: bool) -> Result<ExitStatus> {
-    command().args(args.all()).run_and_get_status(verbose)
+pub fn run(args: &Args, verbosity: Verbosity) -> Result<ExitStatus> {
+    command().args(args.all()).run_and_get_status(verbosity)
 }
 
 pub struct Config {
+    path: PathBuf,
     table: Value,
 }
 
 impl Config {
+    /// Returns `value`, the value of `key`, as an array of strings
+    fn strings(&self, value: &Value, key: &str) -> Result<Vec<String>> {
+        value
+            .as_array()
+            .and_then(|array| {
+                array
+                    .iter()
+                    .map(|v| v.as_str().map(|s| s.to_owned()))
+                    .collect()
+            })
+            .ok_or_else(|| {
+                format!(
+                    "{}: {} must be an array of strings",
+                    self.path.display(),
+                    key
+                )
+                .into()
+            })
+    }
+
     pub fn target(&self) -> Result<Option<&str>> {
         if let Some(v) = self.table.get("build").and_then(|t| t.get("target")) {
             Ok(Some(v.as_str().ok_or_else(|| {
-                format!(".cargo/config: build.target must be a string")
+                format!("{}: build.target must be a string", self.path.display())
             })?))
         } else {
             Ok(None)
         }
     }
+
+    /// The directory relative paths in this configuration are relative to,
+    /// i.e. the parent of its `.cargo` directory
+    pub fn root(&self) -> &Path {
+        self.path
+            .parent()
+            .and_then(Path::parent)
+            .unwrap_or_else(|| Path::new("."))
+    }
 }
 
 pub fn config() -> Result<Option<Config>> {
     let cd = env::current_dir().chain_err(|| "couldn't get the current directory")?;
 
-    if let Some(p) = util::search(&cd, ".cargo/config") {
-        Ok(Some(Config {
-            table: util::parse(&p.join(".cargo/config"))?,
-        }))
-    } else {
-        Ok(None)
+    for dir in cd.ancestors() {
+        // Like Cargo, prefer `config` when both files exist
+        for file in &["config", "config.toml"] {
+            let path = dir.join(".cargo").join(file);
+
+            if path.is_file() {
+                return Ok(Some(Config {
+                    table: util::parse(&path)?,
+                    path,
+                }));
+            }
+        }
     }
+
+    Ok(None)
 }
 
-pub struct Profile<'t> {
-    table: &'t Value,
+pub struct Profile {
+    name: String,
+    table: Value,
 }
 
-impl<'t> Profile<'t> {
-    pub fn hash<H>(&self, hasher: &mut H)
-    where
-        H: Hasher,
-    {
+impl Profile {
+    pub fn new(name: &str, table: Table) -> Profile {
+        Profile {
+            name: name.to_owned(),
+            table: Value::Table(table),
+        }
+    }
+
+    /// The name of this profile, e.g. `release`
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The directory of `target/$triple` Cargo puts the artifacts of this
+    /// profile in, e.g. `debug` for `dev`
+    pub fn dir(&self) -> &str {
+        match &*self.name {
+            "dev" | "test" => "debug",
+            "bench" => "release",
+            name => name,
+        }
+    }
+
+    /// Overrides the settings of this profile with the ones in `table`
+    pub fn merge(&mut self, table: &Table) {
+        if let Value::Table(ref mut profile) = self.table {
+            for (k, v) in table {
+                profile.insert(k.clone(), v.clone());
+            }
+        }
+    }
+
+    /// Returns the value of `key` in this profile
+    pub fn get(&self, key: &str) -> Option<&Value> {
+        self.table.get(key)
+    }
+
+    /// The `panic` strategy of this profile, if set
+    pub fn panic(&self) -> Option<&str> {
+        self.table.get("panic").and_then(Value::as_str)
+    }
+
+    /// Whether the sysroot crates must embed LLVM bitcode
+    ///
+    /// That's needed when the application uses (fat or thin) LTO, unless
+    /// `embed-bitcode` says otherwise.
+    pub fn embed_bitcode(&self) -> bool {
+        if let Some(embed) = self.table.get("embed-bitcode").and_then(Value::as_bool) {
+            return embed;
+        }
+
+        match self.table.get("lto") {
+            Some(Value::Boolean(lto)) => *lto,
+            Some(Value::String(lto)) => lto != "off",
+            _ => false,
+        }
+    }
+
+    /// Whether this profile has no settings
+    pub fn is_empty(&self) -> bool {
+        match self.table {
+            Value::Table(ref table) => table.is_empty(),
+            _ => true,
+        }
+    }
+
+    pub fn hash(&self, inputs: &mut HashInputs) {
         let mut v = self.table.clone();
 
-        // Don't include `lto` in the hash because it doesn't affect compilation
-        // of `.rlib`s
+        // `lto` itself doesn't affect compilation of `.rlib`s, only whether
+        // they embed bitcode does
+        if self.embed_bitcode() {
+            inputs.add("embed-bitcode", &true, "yes");
+        }
+
         if let Value::Table(ref mut table) = v {
             table.remove("lto");
+            table.remove("embed-bitcode");
 
             // don't hash an empty map
             if table.is_empty() {
@@ -210,16 +311,22 @@ impl<'t> Profile<'t> {
             }
         }
 
-        v.to_string().hash(hasher);
+        let v = v.to_string();
+        inputs.add("profile", &v, &v);
     }
 }
 
-impl<'t> fmt::Display for Profile<'t> {
+impl fmt::Display for Profile {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         let mut map = Map::new();
         map.insert("profile".to_owned(), {
             let mut map = Map::new();
-            map.insert("release".to_owned(), self.table.clone());
+            let mut table = self.table.clone();
+            // Not a Cargo setting
+            if let Value::Table(ref mut table) = table {
+                table.remove("embed-bitcode");
+            }
+            map.insert(self.name.clone(), table);
             Value::Table(map)
         });
 
@@ -232,12 +339,82 @@ pub struct Toml {
 }
 
 impl Toml {
-    /// `profile.release` part of `Cargo.toml`
-    pub fn profile(&self) -> Option<Profile<'_>> {
+    /// The names of the profiles `Cargo.toml` defines, along with the
+    /// builtin `dev` and `release`
+    pub fn profiles(&self) -> Vec<&str> {
+        let mut names = vec!["dev", "release"];
+        if let Some(profiles) = self.table.get("profile").and_then(Value::as_table) {
+            names.extend(profiles.keys().map(|k| &**k));
+        }
+        names.sort();
+        names.dedup();
+
+        names
+    }
+
+    /// `profile.$name` part of `Cargo.toml`, along with the settings of the
+    /// profiles it `inherits` from that `Cargo.toml` also defines
+    ///
+    /// The `inherits` of the result is the builtin profile at the end of the
+    /// chain, so the profile can be used in another `Cargo.toml` on its own.
+    pub fn profile(&self, name: &str) -> Option<Profile> {
+        let profiles = self.table.get("profile")?;
+
+        // `test` and `bench` inherit from `dev` and `release` without saying so
+        let mut chain = vec![];
+        let mut visited = vec![];
+        let mut builtin = name;
+        let mut next = Some(name);
+        while let Some(p) = next {
+            // Cargo rejects cycles, we only need to not loop forever
+            if visited.contains(&p) {
+                break;
+            }
+            visited.push(p);
+            builtin = p;
+
+            let table = profiles.get(p).and_then(Value::as_table);
+            chain.extend(table);
+            next = match table
+                .and_then(|t| t.get("inherits"))
+                .and_then(Value::as_str)
+            {
+                Some(parent) => Some(parent),
+                None => match p {
+                    "test" => Some("dev"),
+                    "bench" => Some("release"),
+                    _ => None,
+                },
+            };
+        }
+
+        if chain.is_empty() {
+            return None;
+        }
+
+        let mut profile = Profile::new(name, Table::new());
+        for table in chain.iter().rev() {
+            profile.merge(table);
+        }
+        if let Value::Table(ref mut table) = profile.table {
+            match name {
+                "dev" | "release" | "test" | "bench" => table.remove("inherits"),
+                _ => table.insert("inherits".to_owned(), Value::String(builtin.to_owned())),
+            };
+        }
+
+        Some(profile)
+    }
+
+    /// `package.metadata.xargo.target.$triple` part of `Cargo.toml`, a target
+    /// specification given inline
+    pub fn target_spec(&self, triple: &str) -> Option<&Value> {
         self.table
-            .get("profile")
-            .and_then(|t| t.get("release"))
-            .map(|t| Profile { table: t })
+            .get("package")
+            .and_then(|t| t.get("metadata"))
+            .and_then(|t| t.get("xargo"))
+            .and_then(|t| t.get("target"))
+            .and_then(|t| t.get(triple))
     }
 }
 
@@ -247,22 +424,154 @@ pub fn toml(root: &Root) -> Result<Toml> {
 
 pub struct Root {
     path: PathBuf,
+    /// The features of the crate that Cargo is asked to enable
+    features: Vec<String>,
+    all_features: bool,
+    default_features: bool,
+    /// The Cargo profile the crate is built with
+    profile: String,
+    /// `--sysroot-cache-dir`, made absolute
+    sysroot_cache_dir: Option<PathBuf>,
 }
 
 impl Root {
     pub fn path(&self) -> &Path {
         &self.path
     }
+
+    /// The directory of `--sysroot-cache-dir`, if passed
+    pub fn sysroot_cache_dir(&self) -> Option<&Path> {
+        self.sysroot_cache_dir.as_deref()
+    }
+
+    /// The Cargo profile the crate is built with, e.g. `dev`, or `release`
+    /// with `--release`
+    pub fn profile(&self) -> &str {
+        &self.profile
+    }
+
+    /// This root, with the crate built with the Cargo profile `name`
+    pub fn with_profile(&self, name: &str) -> Root {
+        Root {
+            path: self.path.clone(),
+            features: self.features.clone(),
+            all_features: self.all_features,
+            default_features: self.default_features,
+            profile: name.to_owned(),
+            sysroot_cache_dir: self.sysroot_cache_dir.clone(),
+        }
+    }
+
+    /// Whether the feature `name` of the crate is enabled, by `--features`,
+    /// `--all-features`, the `default` features, or another enabled feature
+    /// of `Cargo.toml` that turns it on
+    pub fn feature_enabled(&self, name: &str) -> Result<bool> {
+        if self.all_features {
+            return Ok(true);
+        }
+
+        // `xargo-check` doesn't need a `Cargo.toml`
+        let manifest = self.path.join("Cargo.toml");
+        let table = if manifest.is_file() {
+            util::parse(&manifest)?
+                .get("features")
+                .and_then(Value::as_table)
+                .cloned()
+                .unwrap_or_default()
+        } else {
+            Table::new()
+        };
+
+        let mut enabled = vec![];
+        let mut pending = self.features.clone();
+        if self.default_features {
+            pending.push("default".to_owned());
+        }
+        while let Some(feature) = pending.pop() {
+            if enabled.contains(&feature) {
+                continue;
+            }
+
+            // Only the features of the crate itself, not `dep:..` or
+            // `dep/feature`
+            if let Some(implied) = table.get(&feature).and_then(Value::as_array) {
+                pending.extend(
+                    implied
+                        .iter()
+                        .filter_map(Value::as_str)
+                        .filter(|f| !f.contains(':') && !f.contains('/'))
+                        .map(String::from),
+                );
+            }
+            enabled.push(feature);
+        }
+
+        Ok(enabled.iter().any(|f| f == name))
+    }
+
+    /// The root of the workspace the crate is a member of, unless that's the
+    /// crate itself
+    ///
+    /// Like for Cargo, that's the directory `package.workspace` points to, or
+    /// else the closest parent directory with a `Cargo.toml` that has a
+    /// `[workspace]`, unless that workspace `exclude`s the crate.
+    pub fn workspace(&self) -> Result<Option<PathBuf>> {
+        let path = env::current_dir()
+            .chain_err(|| "couldn't get the current directory")?
+            .join(&self.path);
+        let manifest = path.join("Cargo.toml");
+        if !manifest.is_file() {
+            return Ok(None);
+        }
+
+        let toml = util::parse(&manifest)?;
+        if toml.get("workspace").is_some() {
+            return Ok(None);
+        }
+        if let Some(dir) = toml
+            .get("package")
+            .and_then(|p| p.get("workspace"))
+            .and_then(Value::as_str)
+        {
+            return Ok(Some(path.join(dir)));
+        }
+
+        for dir in path.ancestors().skip(1) {
+            let manifest = dir.join("Cargo.toml");
+            if !manifest.is_file() {
+                continue;
+            }
+
+            let workspace = match util::parse(&manifest)?.get("workspace") {
+                Some(workspace) => workspace.clone(),
+                None => continue,
+            };
+            let excluded = workspace
+                .get("exclude")
+                .and_then(Value::as_array)
+                .is_some_and(|exclude| {
+                    exclude
+                        .iter()
+                        .filter_map(Value::as_str)
+                        .any(|e| path.starts_with(dir.join(e)))
+                });
+
+            return Ok(if excluded { None } else { Some(dir.to_owned()) });
+        }
+
+        Ok(None)
+    }
 }
 
-pub fn root(mode: XargoMode, manifest_path: Option<&str>) -> Result<Option<Root>> {
+/// The root of the Cargo project of `args`, with the features `args` enables
+pub fn root(mode: XargoMode, args: &Args) -> Result<Option<Root>> {
     // Don't require a 'Cargo.toml' to exist when 'xargo-check' is used
     let name = match mode {
         XargoMode::Build => "Cargo.toml",
         XargoMode::Check => "Xargo.toml",
     };
 
-    let cd = match manifest_path {
+    let cd = match args.manifest_path() {
         None => env::current_dir().chain_err(|| "couldn't get the current directory")?,
         Some(p) => {
             let mut pb = PathBuf::from(p);
@@ -270,18 +579,35 @@ pub fn root(mode: XargoMode, manifest_path: Option<&str>) -> Result<Option<Root>
             pb
         }
     };
-    Ok(util::search(&cd, name).map(|p| Root { path: p.to_owned() }))
+    let sysroot_cache_dir = match args.sysroot_cache_dir() {
+        Some(dir) => Some(
+            env::current_dir()
+                .chain_err(|| "couldn't get the current directory")?
+                .join(dir),
+        ),
+        None => None,
+    };
+    Ok(util::search(&cd, name).map(|p| Root {
+        path: p.to_owned(),
+        features: args.features(),
+        all_features: args.all_features(),
+        default_features: !args.no_default_features(),
+        profile: args.profile().to_owned(),
+        sysroot_cache_dir,
+    }))
 }
 
 #[derive(Clone, Copy, PartialEq)]
 pub enum Subcommand {
     Clean,
     Doc,
+    Info,
     Init,
     New,
     Other,
     Search,
     Update,
+    ValidateTarget,
 }
 
 impl Subcommand {
@@ -289,7 +615,7 @@ impl Subcommand {
         use self::Subcommand::*;
 
         match *self {
-            Clean | Init | New | Search | Update => false,
+            Clean | Info | Init | New | Search | Update | ValidateTarget => false,
             _ => true,
         }
     }
@@ -300,10 +626,12 @@ impl<'a> From<&'a str> for Subcommand {
         match s {
             "clean" => Subcommand::Clean,
             "doc" => Subcommand::Doc,
+            "info" => Subcommand::Info,
             "init" => Subcommand::Init,
             "new" => Subcommand::New,
             "search" => Subcommand::Search,
             "update" => Subcommand::Update,
+            "validate-target" => Subcommand::ValidateTarget,
             _ => Subcommand::Other,
         }
     }
@@ -0,0 +1,222 @@
+//! `cfg(..)` expressions, as used in `[target.'cfg(..)'.dependencies]`
+
+use std::fmt;
+use std::iter::Peekable;
+use std::str::Chars;
+
+use errors::*;
+
+/// A `cfg` of a target, e.g. `unix` or `target_os = "none"`
+#[derive(Debug, Hash, PartialEq)]
+pub enum Cfg {
+    Name(String),
+    KeyPair(String, String),
+}
+
+impl Cfg {
+    /// Parses a line of `rustc --print cfg`
+    pub fn from_line(line: &str) -> Option<Cfg> {
+        let line = line.trim();
+
+        if line.is_empty() {
+            return None;
+        }
+
+        match line.find('=') {
+            Some(i) => Some(Cfg::KeyPair(
+                line[..i].trim().to_owned(),
+                line[i + 1..].trim().trim_matches('"').to_owned(),
+            )),
+            None => Some(Cfg::Name(line.to_owned())),
+        }
+    }
+}
+
+impl fmt::Display for Cfg {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Cfg::Name(ref name) => f.write_str(name),
+            Cfg::KeyPair(ref key, ref value) => write!(f, "{}=\"{}\"", key, value),
+        }
+    }
+}
+
+/// A `cfg(..)` expression
+#[derive(Debug)]
+pub enum Expr {
+    Not(Box<Expr>),
+    All(Vec<Expr>),
+    Any(Vec<Expr>),
+    Value(Cfg),
+}
+
+impl Expr {
+    /// Parses `key`, e.g. `cfg(target_os = "none")`
+    ///
+    /// Returns `None` if `key` isn't a `cfg(..)` expression at all, like a
+    /// target triple.
+    pub fn from_key(key: &str) -> Result<Option<Expr>> {
+        let inner = match key
+            .trim()
+            .strip_prefix("cfg(")
+            .and_then(|k| k.strip_suffix(')'))
+        {
+            Some(inner) => inner,
+            None => return Ok(None),
+        };
+
+        let mut parser = Parser {
+            chars: inner.chars().peekable(),
+        };
+        let expr = parser
+            .expr()
+            .and_then(|e| match parser.token()? {
+                None => Ok(e),
+                Some(t) => Err(format!("unexpected {}", t)),
+            })
+            .map_err(|e| format!("`{}` is not a valid cfg expression: {}", key, e))?;
+
+        Ok(Some(expr))
+    }
+
+    /// Whether this expression holds for a target with the given `cfgs`
+    pub fn matches(&self, cfgs: &[Cfg]) -> bool {
+        match *self {
+            Expr::Not(ref e) => !e.matches(cfgs),
+            Expr::All(ref es) => es.iter().all(|e| e.matches(cfgs)),
+            Expr::Any(ref es) => es.iter().any(|e| e.matches(cfgs)),
+            Expr::Value(ref cfg) => cfgs.contains(cfg),
+        }
+    }
+}
+
+enum Token {
+    LeftParen,
+    RightParen,
+    Comma,
+    Equals,
+    Ident(String),
+    String(String),
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Token::LeftParen => f.write_str("`(`"),
+            Token::RightParen => f.write_str("`)`"),
+            Token::Comma => f.write_str("`,`"),
+            Token::Equals => f.write_str("`=`"),
+            Token::Ident(ref s) => write!(f, "`{}`", s),
+            Token::String(ref s) => write!(f, "`\"{}\"`", s),
+        }
+    }
+}
+
+struct Parser<'a> {
+    chars: Peekable<Chars<'a>>,
+}
+
+impl<'a> Parser<'a> {
+    fn expr(&mut self) -> ::std::result::Result<Expr, String> {
+        let name = match self.token()? {
+            Some(Token::Ident(name)) => name,
+            Some(t) => return Err(format!("expected an identifier, found {}", t)),
+            None => return Err("expected an identifier".to_owned()),
+        };
+
+        self.skip_whitespace();
+        match self.chars.peek() {
+            Some(&'(') if name == "all" || name == "any" || name == "not" => {
+                self.chars.next();
+
+                let mut es = vec![];
+                loop {
+                    self.skip_whitespace();
+                    if self.chars.peek() == Some(&')') {
+                        self.chars.next();
+                        break;
+                    }
+
+                    es.push(self.expr()?);
+
+                    match self.token()? {
+                        Some(Token::Comma) => {}
+                        Some(Token::RightParen) => break,
+                        Some(t) => return Err(format!("expected `,` or `)`, found {}", t)),
+                        None => return Err("expected `)`".to_owned()),
+                    }
+                }
+
+                match &*name {
+                    "all" => Ok(Expr::All(es)),
+                    "any" => Ok(Expr::Any(es)),
+                    _ => {
+                        if es.len() != 1 {
+                            return Err("`not` takes exactly one expression".to_owned());
+                        }
+
+                        Ok(Expr::Not(Box::new(es.remove(0))))
+                    }
+                }
+            }
+            Some(&'=') => {
+                self.chars.next();
+
+                match self.token()? {
+                    Some(Token::String(value)) => Ok(Expr::Value(Cfg::KeyPair(name, value))),
+                    Some(t) => Err(format!("expected a string, found {}", t)),
+                    None => Err("expected a string".to_owned()),
+                }
+            }
+            _ => Ok(Expr::Value(Cfg::Name(name))),
+        }
+    }
+
+    fn skip_whitespace(&mut self) {
+        while self.chars.peek().map(|c| c.is_whitespace()) == Some(true) {
+            self.chars.next();
+        }
+    }
+
+    fn token(&mut self) -> ::std::result::Result<Option<Token>, String> {
+        self.skip_whitespace();
+
+        let c = match self.chars.next() {
+            Some(c) => c,
+            None => return Ok(None),
+        };
+
+        let token = match c {
+            '(' => Token::LeftParen,
+            ')' => Token::RightParen,
+            ',' => Token::Comma,
+            '=' => Token::Equals,
+            '"' => {
+                let mut s = String::new();
+                loop {
+                    match self.chars.next() {
+                        Some('"') => break,
+                        Some(c) => s.push(c),
+                        None => return Err("unterminated string".to_owned()),
+                    }
+                }
+                Token::String(s)
+            }
+            c if c == '_' || c.is_alphabetic() => {
+                let mut s = c.to_string();
+                while let Some(&c) = self.chars.peek() {
+                    if c == '_' || c.is_alphanumeric() {
+                        s.push(c);
+                        self.chars.next();
+                    } else {
+                        break;
+                    }
+                }
+                Token::Ident(s)
+            }
+            c => return Err(format!("unexpected character `{}`", c)),
+        };
+
+        Ok(Some(token))
+    }
+}
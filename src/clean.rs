@@ -0,0 +1,383 @@
+//! `xargo clean --sysroot` and `xargo clean --list`
+
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde_json::{self, Map, Value};
+use walkdir::WalkDir;
+
+use cargo::Root;
+use cli::Args;
+use errors::*;
+use rustc::Target;
+use sysroot::{self, XargoMode};
+use {cargo, cmode, rustc, src, xargo};
+
+/// Removes the sysroots in Xargo's home that the project wouldn't use as
+/// they are, see `up_to_date`, or all of them with `--all`
+///
+/// Only the sysroots Xargo built are touched, see `Home::rustlibs`. Xargo's
+/// home is shared by all projects, and whether another project uses the
+/// sysroot of a triple this one doesn't resolve can't be told, so those are
+/// only removed with `--all`.
+pub fn sysroots(args: &Args, cargo_mode: XargoMode) -> Result<()> {
+    let root = cargo::root(cargo_mode, args)?;
+    let (triples, keep) = if args.clean_all() {
+        (None, vec![])
+    } else {
+        let root = root.as_ref().ok_or(
+            "`xargo clean --sysroot` tells the stale sysroots apart from the \
+             ones of the project it's run in, but there's no project here. \
+             Pass `--all` to remove all the sysroots.",
+        )?;
+
+        let (triples, keep) = up_to_date(args, root)?;
+        (Some(triples), keep)
+    };
+
+    let quiet = args.verbosity().is_quiet();
+    let mut removed = 0;
+    let mut total = 0;
+    for home in xargo::homes(root.as_ref())? {
+        for rustlib in home.rustlibs()? {
+            let triple = rustlib.file_name().and_then(|n| n.to_str());
+            let resolved = match (&triples, triple) {
+                (Some(triples), Some(triple)) => triples.iter().any(|t| t == triple),
+                (Some(_), None) => false,
+                (None, _) => true,
+            };
+            if !resolved || keep.contains(&rustlib) {
+                continue;
+            }
+
+            // Wait for whoever may still be using this sysroot, and keep it
+            // from being used until it's gone
+            let _lock = match triple {
+                Some(triple) => Some(home.lock_rw(triple)?),
+                None => None,
+            };
+
+            let bytes = size(&rustlib);
+            if !quiet {
+                writeln!(
+                    io::stderr(),
+                    "    Removing {} ({})",
+                    rustlib.display(),
+                    human(bytes)
+                )
+                .ok();
+            }
+            fs::remove_dir_all(&rustlib)
+                .chain_err(|| format!("couldn't remove {}", rustlib.display()))?;
+
+            removed += 1;
+            total += bytes;
+
+            // The build directory of `XARGO_INCREMENTAL` goes along with it
+            if let Some(build) = triple.map(|t| home.build_dir(t)).filter(|b| b.exists()) {
+                total += size(&build);
+                fs::remove_dir_all(&build)
+                    .chain_err(|| format!("couldn't remove {}", build.display()))?;
+            }
+        }
+    }
+
+    if !quiet {
+        writeln!(
+            io::stderr(),
+            "     Removed {} sysroot(s), {} total",
+            removed,
+            human(total)
+        )
+        .ok();
+    }
+
+    Ok(())
+}
+
+/// Prints the sysroots in Xargo's home, with what they were built with, their
+/// size and when they were last used, in the `--format` of `args`
+///
+/// Nothing is built nor removed: this is to decide what to remove by hand.
+pub fn list(args: &Args, cargo_mode: XargoMode) -> Result<()> {
+    let json = match args.format() {
+        None | Some("human") => false,
+        Some("json") => true,
+        Some(format) => bail!(
+            "`xargo clean --list`: unknown `--format` `{}`, expected `human` or `json`",
+            format
+        ),
+    };
+
+    let root = cargo::root(cargo_mode, args)?;
+    let mut sysroots = vec![];
+    for home in xargo::homes(root.as_ref())? {
+        for rustlib in home.rustlibs()? {
+            sysroots.push(Listed::read(rustlib));
+        }
+    }
+
+    let stdout = io::stdout();
+    let mut stdout = stdout.lock();
+    if json {
+        let list = sysroots.iter().map(Listed::to_json).collect();
+        writeln!(stdout, "{}", Value::Array(list)).ok();
+        return Ok(());
+    }
+
+    // No header for an empty table
+    if sysroots.is_empty() {
+        return Ok(());
+    }
+
+    let now = SystemTime::now();
+    let mut rows = vec![["TRIPLE", "HASH", "RUSTC", "SIZE", "LAST USED", "PATH"].map(String::from)];
+    for s in &sysroots {
+        rows.push([
+            s.triple.clone(),
+            s.hash.clone().unwrap_or_else(|| "-".to_owned()),
+            s.rustc_version.clone().unwrap_or_else(|| "-".to_owned()),
+            human(s.size),
+            s.last_used
+                .map(|t| ago(now.duration_since(t).unwrap_or_default()))
+                .unwrap_or_else(|| "-".to_owned()),
+            s.path.display().to_string(),
+        ]);
+    }
+
+    let mut widths = [0; 6];
+    for row in &rows {
+        for (w, cell) in widths.iter_mut().zip(row) {
+            *w = (*w).max(cell.len());
+        }
+    }
+    for row in rows {
+        let line = row
+            .iter()
+            .zip(&widths)
+            .map(|(cell, &w)| format!("{:w$}", cell, w = w))
+            .collect::<Vec<_>>()
+            .join("  ");
+        writeln!(stdout, "{}", line.trim_end()).ok();
+    }
+
+    Ok(())
+}
+
+/// A sysroot Xargo built, as `xargo clean --list` shows it
+struct Listed {
+    triple: String,
+    /// The sysroot hash, or the commit hash of `rustc` for the host libraries
+    /// copied next to a cross sysroot
+    hash: Option<String>,
+    /// The version of `rustc` that built it, from the inputs of its hash
+    rustc_version: Option<String>,
+    size: u64,
+    /// When it was last built or found up to date
+    last_used: Option<SystemTime>,
+    path: PathBuf,
+}
+
+impl Listed {
+    fn read(path: PathBuf) -> Listed {
+        let hfile = path.join(".hash");
+        let hash = fs::read_to_string(&hfile).ok().and_then(|h| {
+            let h = h.trim();
+            match h.parse::<u64>() {
+                Ok(hash) => Some(format!("{:016x}", hash)),
+                Err(_) if !h.is_empty() => Some(h.chars().take(16).collect()),
+                Err(_) => None,
+            }
+        });
+        // Sysroots built before `LAST_USED` was recorded go by when they were
+        // built
+        let last_used = fs::read_to_string(path.join(sysroot::LAST_USED))
+            .ok()
+            .and_then(|secs| secs.trim().parse().ok())
+            .map(|secs| UNIX_EPOCH + Duration::from_secs(secs))
+            .or_else(|| {
+                fs::metadata(&hfile)
+                    .or_else(|_| fs::metadata(path.join(".sentinel")))
+                    .and_then(|m| m.modified())
+                    .ok()
+            });
+
+        Listed {
+            triple: path
+                .file_name()
+                .map(|n| n.to_string_lossy().into_owned())
+                .unwrap_or_default(),
+            hash,
+            rustc_version: rustc_version(&path.join("inputs.json")),
+            size: size(&path),
+            last_used,
+            path,
+        }
+    }
+
+    fn to_json(&self) -> Value {
+        let mut object = Map::new();
+        object.insert("triple".to_owned(), self.triple.clone().into());
+        object.insert(
+            "hash".to_owned(),
+            self.hash.clone().map_or(Value::Null, Value::from),
+        );
+        object.insert(
+            "rustc_version".to_owned(),
+            self.rustc_version.clone().map_or(Value::Null, Value::from),
+        );
+        object.insert("size".to_owned(), self.size.into());
+        object.insert(
+            "last_used".to_owned(),
+            self.last_used
+                .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+                .map_or(Value::Null, |d| d.as_secs().into()),
+        );
+        object.insert("path".to_owned(), self.path.display().to_string().into());
+        Value::Object(object)
+    }
+}
+
+/// The `rustc version` input recorded in `inputs`, the `inputs.json` of a
+/// sysroot
+fn rustc_version(inputs: &Path) -> Option<String> {
+    let json: Value = serde_json::from_str(&fs::read_to_string(inputs).ok()?).ok()?;
+
+    json.as_array()?
+        .iter()
+        .find(|input| input.get("name").and_then(Value::as_str) == Some("rustc version"))?
+        .get("shown")?
+        .as_str()
+        .map(String::from)
+}
+
+/// Formats `elapsed` like `3 days ago`
+fn ago(elapsed: Duration) -> String {
+    const UNITS: &[(&str, u64)] = &[("day", 86400), ("hour", 3600), ("minute", 60)];
+
+    let secs = elapsed.as_secs();
+    for &(unit, len) in UNITS {
+        let n = secs / len;
+        if n > 0 {
+            return format!("{} {}{} ago", n, unit, if n == 1 { "" } else { "s" });
+        }
+    }
+
+    "just now".to_owned()
+}
+
+/// Returns the triples of the project of `root` that Xargo can resolve, and
+/// the sysroot directories the project would use as they are, with any of
+/// those configurations
+///
+/// That's the `--target`s of `args`, the default target, the targets
+/// `Xargo.toml` configures and the ones with a specification in the project
+/// root, each with any profile of `Cargo.toml`, for `xargo` as well as
+/// `xargo-check`. Only the `--target`s and the default target must resolve.
+fn up_to_date(args: &Args, root: &Root) -> Result<(Vec<String>, Vec<PathBuf>)> {
+    let verbosity = args.verbosity();
+
+    let meta = rustc::version()?;
+    let sysroot = rustc::sysroot(verbosity)?;
+    let src = src(args, root, &meta, &sysroot, verbosity)?.ok_or(
+        "`xargo clean --sysroot` can't tell the stale sysroots apart without \
+         the Rust source. Pass `--all` to remove all the sysroots.",
+    )?;
+    let config = cargo::config()?;
+
+    let mut targets = vec![(None, true)];
+    targets.extend(args.targets().iter().map(|t| (Some(t.clone()), true)));
+    targets.extend(
+        Target::configured(root)?
+            .into_iter()
+            .map(|t| (Some(t), false)),
+    );
+    targets.extend(specs(root.path()).into_iter().map(|t| (Some(t), false)));
+
+    let profiles = if root.path().join("Cargo.toml").is_file() {
+        cargo::toml(root)?
+            .profiles()
+            .into_iter()
+            .map(String::from)
+            .collect()
+    } else {
+        vec![root.profile().to_owned()]
+    };
+
+    let mut triples = vec![];
+    let mut dirs = vec![];
+    for (target, required) in targets {
+        let cmode = match cmode(target.as_deref(), config.as_ref(), root, &meta, verbosity) {
+            Ok(cmode) => cmode,
+            Err(_) if !required => continue,
+            Err(e) => return Err(e),
+        };
+        let rustflags = cargo::rustflags(config.as_ref(), cmode.triple())?;
+        triples.push(cmode.triple().to_owned());
+
+        for profile in &profiles {
+            let root = root.with_profile(profile);
+
+            for cargo_mode in [XargoMode::Build, XargoMode::Check] {
+                let home = xargo::home(&cmode, &root, cargo_mode)?;
+                dirs.extend(sysroot::up_to_date(
+                    &cmode, &home, &root, &rustflags, &meta, &src, cargo_mode,
+                )?);
+            }
+        }
+    }
+
+    Ok((triples, dirs))
+}
+
+/// The names of the `.json` files in `dir`, which may be target
+/// specifications
+fn specs(dir: &Path) -> Vec<String> {
+    let entries = match dir.read_dir() {
+        Ok(entries) => entries,
+        Err(_) => return vec![],
+    };
+
+    entries
+        .filter_map(|e| {
+            let path = e.ok()?.path();
+
+            if path.extension()? == "json" {
+                path.file_stem()?.to_str().map(String::from)
+            } else {
+                None
+            }
+        })
+        .collect()
+}
+
+/// Total size, in bytes, of the files under `dir`
+fn size(dir: &Path) -> u64 {
+    WalkDir::new(dir)
+        .into_iter()
+        .filter_map(|e| e.ok())
+        .filter_map(|e| e.metadata().ok())
+        .filter(|m| m.is_file())
+        .map(|m| m.len())
+        .sum()
+}
+
+/// Formats `bytes` like `12.3MiB`
+fn human(bytes: u64) -> String {
+    const UNITS: &[&str] = &["KiB", "MiB", "GiB", "TiB"];
+
+    if bytes < 1024 {
+        return format!("{}B", bytes);
+    }
+
+    let mut size = bytes as f64 / 1024.;
+    let mut unit = 0;
+    while size >= 1024. && unit + 1 < UNITS.len() {
+        size /= 1024.;
+        unit += 1;
+    }
+
+    format!("{:.1}{}", size, UNITS[unit])
+}
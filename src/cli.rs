@@ -1,13 +1,44 @@
 use std::env;
 
 use cargo::Subcommand;
+use extensions::Verbosity;
 
+#[derive(Clone)]
 pub struct Args {
     all: Vec<String>,
     subcommand: Option<Subcommand>,
-    target: Option<String>,
+    targets: Vec<String>,
     message_format: Option<String>,
     manifest_path: Option<String>, // path to the Cargo toml file given in --manifest-path
+    install_components: bool,
+    print_sysroot: bool,
+    print_hash: bool,
+    clean_sysroot: bool,
+    clean_all: bool,
+    clean_list: bool,
+    jobs: Option<usize>,
+    configs: Vec<String>,
+    dry_run: bool,
+    strict_src: bool,
+    sysroot_env_file: Option<String>,
+    sysroot_dir: Option<String>,
+    sysroot_cache_dir: Option<String>,
+    force: bool,
+    explain_rebuild: bool,
+    no_sysroot: bool,
+    force_custom: bool,
+    format: Option<String>,
+    verbose_cargo: bool,
+    always_run_hook: bool,
+    deny_sysroot_warnings: bool,
+    force_rebuild: bool,
+    quiet_on_success: bool,
+    reproducible: bool,
+    all_configured_targets: bool,
+    target_spec: Option<String>,
+    profile: String,
+    /// Xargo's own flags, as they were given, for `sysroot_flags`
+    sysroot_flags: Vec<String>,
 }
 
 impl Args {
@@ -20,17 +51,190 @@ impl Args {
     }
 
     pub fn target(&self) -> Option<&str> {
-        self.target.as_ref().map(|s| &**s)
+        self.targets.last().map(|s| &**s)
     }
 
+    /// All the targets passed via `--target`
+    pub fn targets(&self) -> &[String] {
+        &self.targets
+    }
+
+    /// Returns these arguments with `target` as the only `--target`
+    pub fn with_target(&self, target: &str) -> Args {
+        self.with_targets(&[target.to_owned()])
+    }
+
+    /// Returns these arguments with `targets` as the `--target`s
+    pub fn with_targets(&self, targets: &[String]) -> Args {
+        let mut all = vec![];
+        {
+            let mut args = self.all.iter();
+            while let Some(arg) = args.next() {
+                if arg == "--" {
+                    all.push(arg.clone());
+                    all.extend(args.cloned());
+                    break;
+                } else if arg == "--target" {
+                    args.next();
+                } else if !arg.starts_with("--target=") {
+                    all.push(arg.clone());
+                }
+            }
+        }
+
+        let end = all.iter().position(|a| a == "--").unwrap_or(all.len());
+        for (i, target) in targets.iter().enumerate() {
+            all.insert(end + i, format!("--target={}", target));
+        }
+
+        Args {
+            all,
+            targets: targets.to_vec(),
+            ..self.clone()
+        }
+    }
+
+    /// Returns these arguments for running Cargo once `build_sysroots` has
+    /// built the sysroots, so they aren't rebuilt by `--force-rebuild`, and
+    /// the hook `--always-run-hook` ran isn't run again
+    pub fn with_sysroots_built(&self) -> Args {
+        Args {
+            force_rebuild: false,
+            always_run_hook: false,
+            ..self.clone()
+        }
+    }
+
+    /// The `--message-format` to build the sysroot with, falling back to
+    /// `$CARGO_MESSAGE_FORMAT`
     pub fn message_format(&self) -> Option<&str> {
         self.message_format.as_ref().map(|s| &**s)
     }
 
-    pub fn verbose(&self) -> bool {
+    /// `-q` / `--quiet`, or how many times `-v` / `--verbose` was given
+    pub fn verbosity(&self) -> Verbosity {
+        let args = self.all.iter().take_while(|a| *a != "--");
+        let mut verbose = 0;
+        for arg in args {
+            if arg == "-q" || arg == "--quiet" {
+                return Verbosity::Quiet;
+            } else if arg == "--verbose" {
+                verbose += 1;
+            } else if arg.len() > 1 && arg.starts_with('-') && arg[1..].chars().all(|c| c == 'v') {
+                verbose += arg.len() - 1;
+            }
+        }
+
+        match verbose {
+            0 => Verbosity::Normal,
+            1 => Verbosity::Verbose,
+            _ => Verbosity::VeryVerbose,
+        }
+    }
+
+    /// The verbosity of the Cargo that builds the sysroot: Xargo's, but at
+    /// least `-v` with `--verbose-cargo`
+    pub fn cargo_verbosity(&self) -> Verbosity {
+        match self.verbosity() {
+            Verbosity::Quiet | Verbosity::Normal if self.verbose_cargo => Verbosity::Verbose,
+            verbosity => verbosity,
+        }
+    }
+
+    /// Whether to run the `build.post-sysroot-hook` of `Xargo.toml` even when
+    /// the sysroot is up to date
+    pub fn always_run_hook(&self) -> bool {
+        self.always_run_hook
+    }
+
+    /// Whether to rebuild the sysroot even when it's up to date
+    pub fn force_rebuild(&self) -> bool {
+        self.force_rebuild
+    }
+
+    /// Whether the output of the builds is only shown when they fail
+    pub fn quiet_on_success(&self) -> bool {
+        self.quiet_on_success
+    }
+
+    /// Whether to build the sysroots of all the targets `Xargo.toml`
+    /// configures
+    pub fn all_configured_targets(&self) -> bool {
+        self.all_configured_targets
+    }
+
+    /// Whether the sysroot must be built reproducibly
+    pub fn reproducible(&self) -> bool {
+        self.reproducible
+    }
+
+    /// Whether the sysroot is built with `-D warnings`
+    pub fn deny_sysroot_warnings(&self) -> bool {
+        self.deny_sysroot_warnings
+    }
+
+    /// Whether Cargo must not access the network, i.e. `--offline` or
+    /// `--frozen`
+    pub fn offline(&self) -> bool {
+        self.all.iter().any(|a| a == "--offline" || a == "--frozen")
+    }
+
+    /// Whether the dependencies of the sysroot must be the ones `Xargo.lock`
+    /// pins, i.e. `--locked` or `--frozen`
+    pub fn locked(&self) -> bool {
+        self.all
+            .iter()
+            .take_while(|a| *a != "--")
+            .any(|a| a == "--locked" || a == "--frozen")
+    }
+
+    /// The features of the crate passed via `--features` / `-F`, which are
+    /// separated by commas or spaces
+    pub fn features(&self) -> Vec<String> {
+        let mut features = vec![];
+        let mut args = self.all.iter().take_while(|a| *a != "--");
+        while let Some(arg) = args.next() {
+            let list = if arg == "--features" || arg == "-F" {
+                args.next().map(|s| &**s)
+            } else if let Some(list) = arg.strip_prefix("--features=") {
+                Some(list)
+            } else {
+                arg.strip_prefix("-F").filter(|l| !l.is_empty())
+            };
+
+            features.extend(
+                list.into_iter()
+                    .flat_map(|l| l.split([',', ' ']))
+                    .filter(|f| !f.is_empty())
+                    .map(String::from),
+            );
+        }
+
+        features
+    }
+
+    /// Whether `--all-features` was given
+    pub fn all_features(&self) -> bool {
+        self.all
+            .iter()
+            .take_while(|a| *a != "--")
+            .any(|a| a == "--all-features")
+    }
+
+    /// Whether `--no-default-features` was given
+    pub fn no_default_features(&self) -> bool {
+        self.all
+            .iter()
+            .take_while(|a| *a != "--")
+            .any(|a| a == "--no-default-features")
+    }
+
+    /// Whether `--timings` was given, which Cargo also gets for the crate
+    pub fn timings(&self) -> bool {
         self.all
             .iter()
-            .any(|a| a == "--verbose" || a == "-v" || a == "-vv")
+            .take_while(|a| *a != "--")
+            .any(|a| a == "--timings" || a.starts_with("--timings="))
     }
 
     pub fn version(&self) -> bool {
@@ -40,41 +244,397 @@ impl Args {
     pub fn manifest_path(&self) -> Option<&str> {
         self.manifest_path.as_ref().map(|s| &**s)
     }
+
+    /// Whether missing toolchain components may be installed via rustup
+    pub fn install_components(&self) -> bool {
+        self.install_components
+    }
+
+    /// Whether to only print the hash of the sysroot
+    pub fn print_hash(&self) -> bool {
+        self.print_hash
+    }
+
+    /// Whether `xargo clean` should remove the stale sysroots, instead of
+    /// running `cargo clean`
+    pub fn clean_sysroot(&self) -> bool {
+        self.clean_sysroot || self.clean_all
+    }
+
+    /// Whether `xargo clean` should remove all the sysroots
+    pub fn clean_all(&self) -> bool {
+        self.clean_all
+    }
+
+    /// Whether `xargo clean` should only list the sysroots in Xargo's home
+    pub fn clean_list(&self) -> bool {
+        self.clean_list
+    }
+
+    /// The number of jobs passed via `-j` / `--jobs`
+    pub fn jobs(&self) -> Option<usize> {
+        self.jobs
+    }
+
+    /// The values of all the `--config` flags, in order
+    pub fn configs(&self) -> &[String] {
+        &self.configs
+    }
+
+    /// Whether to only print how the sysroot would be built
+    pub fn dry_run(&self) -> bool {
+        self.dry_run
+    }
+
+    /// Whether a Rust source of another version than `rustc` is an error
+    pub fn strict_src(&self) -> bool {
+        self.strict_src
+    }
+
+    /// The file to write the sysroot's `RUSTFLAGS` to, for wrapper scripts
+    pub fn sysroot_env_file(&self) -> Option<&str> {
+        self.sysroot_env_file.as_deref()
+    }
+
+    /// The directory to build the sysroot in, instead of Xargo's home
+    pub fn sysroot_dir(&self) -> Option<&str> {
+        self.sysroot_dir.as_deref()
+    }
+
+    /// The directory to cache the sysroots in, like Xargo's home, from
+    /// `--sysroot-cache-dir`
+    pub fn sysroot_cache_dir(&self) -> Option<&str> {
+        self.sysroot_cache_dir.as_deref()
+    }
+
+    /// Whether `--sysroot-dir` may be a directory with other files in it
+    pub fn force(&self) -> bool {
+        self.force
+    }
+
+    /// Whether to only build the sysroot and print its path
+    pub fn print_sysroot(&self) -> bool {
+        self.print_sysroot
+    }
+
+    /// Whether to tell which inputs of the sysroot hash changed when the
+    /// sysroot is rebuilt
+    pub fn explain_rebuild(&self) -> bool {
+        self.explain_rebuild
+    }
+
+    /// Whether to run Cargo with a prebuilt sysroot, without building one
+    pub fn no_sysroot(&self) -> bool {
+        self.no_sysroot
+    }
+
+    /// Whether targets must be loaded from a target specification file, even
+    /// when rustc has a builtin target of the same name
+    pub fn force_custom(&self) -> bool {
+        self.force_custom
+    }
+
+    /// The Cargo profile of the build: the one of `--profile`, `release` with
+    /// `--release` or for `bench` and `install`, and `dev` otherwise
+    pub fn profile(&self) -> &str {
+        &self.profile
+    }
+
+    /// The specification file passed to `xargo validate-target`
+    pub fn target_spec(&self) -> Option<&str> {
+        self.target_spec.as_deref()
+    }
+
+    /// The `--format` of `xargo info` and `xargo clean --list`
+    pub fn format(&self) -> Option<&str> {
+        self.format.as_deref()
+    }
+
+    /// Xargo's own flags, as they were given, that the `xargo` processes
+    /// which only build a sysroot must get too, e.g. `--reproducible`
+    ///
+    /// The flags that make Xargo do something else than build, like
+    /// `--print-hash`, are left out.
+    pub fn sysroot_flags(&self) -> &[String] {
+        &self.sysroot_flags
+    }
+}
+
+/// Xargo's own flags that aren't part of `sysroot_flags`
+const NOT_FOR_SYSROOT: &[&str] = &[
+    "--print-sysroot",
+    "--print-hash",
+    "--dry-run",
+    "--no-sysroot",
+    "--all-configured-targets",
+    "--format",
+    "--sysroot",
+    "--all",
+    "--list",
+];
+
+/// An iterator that keeps what it has yielded, so the arguments Xargo takes,
+/// along with their values, can be told apart from Cargo's
+struct Taken<I> {
+    args: I,
+    taken: Vec<String>,
+}
+
+impl<I> Iterator for Taken<I>
+where
+    I: Iterator<Item = String>,
+{
+    type Item = String;
+
+    fn next(&mut self) -> Option<String> {
+        let arg = self.args.next()?;
+        self.taken.push(arg.clone());
+        Some(arg)
+    }
+}
+
+/// The subcommand in `args`, if any
+fn subcommand(args: &[String]) -> Option<&str> {
+    args.iter().find(|a| !a.starts_with('-')).map(|a| &**a)
+}
+
+/// Whether Cargo has its own `--dry-run` for `subcommand`
+fn has_dry_run(subcommand: Option<&str>) -> bool {
+    matches!(subcommand, Some("clean") | Some("publish"))
+}
+
+/// Whether the subcommand in `args` is `clean`
+fn is_clean(args: &[String]) -> bool {
+    subcommand(args) == Some("clean")
+}
+
+/// Whether the subcommand in `args` takes a `--format`: `info`, or `clean`
+/// for `--list`
+fn has_format(args: &[String]) -> bool {
+    matches!(subcommand(args), Some("info") | Some("clean"))
 }
 
 pub fn args() -> Args {
-    let all = env::args().skip(1).collect::<Vec<_>>();
+    parse(env::args().skip(1))
+}
+
+/// Parses the arguments of `xargo`, not including the program name
+pub fn parse<I>(input: I) -> Args
+where
+    I: IntoIterator<Item = String>,
+{
+    let mut all = vec![];
+    let mut install_components = false;
+    let mut print_sysroot = false;
+    let mut print_hash = false;
+    let mut clean_sysroot = false;
+    let mut clean_all = false;
+    let mut clean_list = false;
+    let mut dry_run = false;
+    let mut strict_src = false;
+    let mut explain_rebuild = false;
+    let mut no_sysroot = false;
+    let mut force_custom = false;
+    let mut format = None;
+    let mut verbose_cargo = false;
+    let mut always_run_hook = false;
+    let mut deny_sysroot_warnings = false;
+    let mut force_rebuild = false;
+    let mut quiet_on_success = false;
+    let mut reproducible = false;
+    let mut all_configured_targets = false;
+    let mut sysroot_env_file = None;
+    let mut sysroot_dir = None;
+    let mut sysroot_cache_dir = None;
+    let mut sysroot_flags = vec![];
+    {
+        // Xargo's own flags are not forwarded to Cargo
+        let mut args = Taken {
+            args: input.into_iter(),
+            taken: vec![],
+        };
+        while let Some(arg) = args.next() {
+            let cargo_args = all.len();
+
+            if arg == "--" {
+                // Everything after `--` belongs to the program / test harness
+                all.push(arg);
+                all.extend(args);
+                break;
+            } else if arg == "--install-components" {
+                install_components = true;
+            } else if arg == "--print-sysroot" {
+                print_sysroot = true;
+            } else if arg == "--print-hash" {
+                print_hash = true;
+            } else if arg == "--strict-src" {
+                strict_src = true;
+            } else if arg == "--explain-rebuild" {
+                explain_rebuild = true;
+            } else if arg == "--no-sysroot" {
+                no_sysroot = true;
+            } else if arg == "--force-custom" {
+                force_custom = true;
+            } else if arg == "--verbose-cargo" {
+                verbose_cargo = true;
+            } else if arg == "--always-run-hook" {
+                always_run_hook = true;
+            } else if arg == "--deny-sysroot-warnings" {
+                deny_sysroot_warnings = true;
+            } else if arg == "--force-rebuild" {
+                force_rebuild = true;
+            } else if arg == "--quiet-on-success" {
+                quiet_on_success = true;
+            } else if arg == "--reproducible" {
+                reproducible = true;
+            } else if arg == "--all-configured-targets" {
+                all_configured_targets = true;
+            } else if arg == "--sysroot-env-file" {
+                sysroot_env_file = args.next();
+            } else if let Some(f) = arg.strip_prefix("--sysroot-env-file=") {
+                sysroot_env_file = Some(f.to_owned());
+            } else if arg == "--sysroot-dir" {
+                sysroot_dir = args.next();
+            } else if let Some(d) = arg.strip_prefix("--sysroot-dir=") {
+                sysroot_dir = Some(d.to_owned());
+            } else if arg == "--sysroot-cache-dir" {
+                sysroot_cache_dir = args.next();
+            } else if let Some(d) = arg.strip_prefix("--sysroot-cache-dir=") {
+                sysroot_cache_dir = Some(d.to_owned());
+            } else if has_format(&all) && arg == "--format" {
+                format = args.next();
+            } else if let Some(f) = arg.strip_prefix("--format=").filter(|_| has_format(&all)) {
+                format = Some(f.to_owned());
+            } else if is_clean(&all) && arg == "--sysroot" {
+                clean_sysroot = true;
+            } else if is_clean(&all) && arg == "--all" {
+                clean_all = true;
+            } else if is_clean(&all) && arg == "--list" {
+                clean_list = true;
+            } else if arg == "--dry-run" && !has_dry_run(subcommand(&all)) {
+                dry_run = true;
+            } else {
+                all.push(arg);
+            }
+
+            let taken = args.taken.drain(..).collect::<Vec<_>>();
+            let flag = taken[0].split('=').next().unwrap_or("");
+            if all.len() == cargo_args && !NOT_FOR_SYSROOT.contains(&flag) {
+                sysroot_flags.extend(taken);
+            }
+        }
+    }
+
+    // `--force` is only Xargo's along with `--sysroot-dir`: Cargo has its own,
+    // e.g. for `cargo install`
+    let mut force = false;
+    if sysroot_dir.is_some() {
+        let end = all.iter().position(|a| a == "--").unwrap_or(all.len());
+        if let Some(i) = all[..end].iter().position(|a| a == "--force") {
+            all.remove(i);
+            force = true;
+            sysroot_flags.push("--force".to_owned());
+        }
+    }
 
     let mut subcommand = None;
-    let mut target = None;
+    let mut targets = vec![];
     let mut message_format = None;
     let mut manifest_path = None;
+    let mut jobs = None;
+    let mut configs = vec![];
+    let mut target_spec = None;
+    let mut profile = None;
     {
         let mut args = all.iter();
         while let Some(arg) = args.next() {
             if !arg.starts_with("-") {
+                if subcommand == Some(Subcommand::ValidateTarget) {
+                    target_spec = target_spec.or_else(|| Some(arg.clone()));
+                }
                 subcommand = subcommand.or_else(|| Some(Subcommand::from(&**arg)));
             }
 
             if arg == "--target" {
-                target = args.next().map(|s| s.to_owned());
+                targets.extend(args.next().cloned());
             } else if arg.starts_with("--target=") {
-                target = arg.splitn(2, '=').nth(1).map(|s| s.to_owned());
+                targets.extend(arg.splitn(2, '=').nth(1).map(|s| s.to_owned()));
             } else if arg == "--message-format" {
                 message_format = args.next().map(|s| s.to_owned());
             } else if arg.starts_with("--message-format=") {
                 message_format = arg.splitn(2, '=').nth(1).map(|s| s.to_owned());
             } else if arg.starts_with("--manifest-path") {
                 manifest_path = args.next().map(|s| s.to_owned());
+            } else if arg == "--jobs" || arg == "-j" {
+                jobs = args.next().and_then(|j| j.parse().ok());
+            } else if let Some(j) = arg.strip_prefix("--jobs=") {
+                jobs = j.parse().ok();
+            } else if let Some(j) = arg.strip_prefix("-j") {
+                jobs = j.parse().ok();
+            } else if arg == "--config" {
+                configs.extend(args.next().cloned());
+            } else if let Some(c) = arg.strip_prefix("--config=") {
+                configs.push(c.to_owned());
+            } else if arg == "--release" || arg == "-r" {
+                profile = Some("release".to_owned());
+            } else if arg == "--profile" {
+                profile = args.next().cloned();
+            } else if let Some(p) = arg.strip_prefix("--profile=") {
+                profile = Some(p.to_owned());
+            } else if arg == "--" {
+                break;
             }
         }
     }
 
+    let profile = profile.unwrap_or_else(|| {
+        match self::subcommand(&all) {
+            Some("bench") | Some("install") => "release",
+            _ => "dev",
+        }
+        .to_owned()
+    });
+
+    // Like `--message-format`, but only applies to the sysroot build
+    let message_format = message_format.or_else(|| {
+        env::var("CARGO_MESSAGE_FORMAT")
+            .ok()
+            .filter(|f| !f.is_empty())
+    });
+
     Args {
         all,
         subcommand,
-        target,
+        targets,
         message_format,
         manifest_path,
+        install_components,
+        print_sysroot,
+        print_hash,
+        clean_sysroot,
+        clean_all,
+        clean_list,
+        jobs,
+        configs,
+        dry_run,
+        strict_src,
+        sysroot_env_file,
+        sysroot_dir,
+        sysroot_cache_dir,
+        force,
+        explain_rebuild,
+        no_sysroot,
+        force_custom,
+        format,
+        verbose_cargo,
+        always_run_hook,
+        deny_sysroot_warnings,
+        force_rebuild,
+        quiet_on_success,
+        reproducible,
+        all_configured_targets,
+        target_spec,
+        profile,
+        sysroot_flags,
     }
 }
@@ -1,4 +1,29 @@
 #![allow(unknown_lints)]
 #![allow(unused_doc_comments)]
 #![allow(unexpected_cfgs)]
-error_chain!();
+
+use std::path::PathBuf;
+
+error_chain! {
+    errors {
+        /// `triple` is neither a builtin target nor a custom target with a
+        /// specification in one of the `searched` directories
+        TargetNotFound(triple: String, searched: Vec<PathBuf>) {
+            description("target not found")
+            display(
+                "`{0}` is not a builtin target, and there's no `{0}.json` in {1}, \
+                 nor a `package.metadata.xargo.target.{0}` in Cargo.toml",
+                triple,
+                if searched.is_empty() {
+                    "any directory".to_owned()
+                } else {
+                    searched
+                        .iter()
+                        .map(|d| d.display().to_string())
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                }
+            )
+        }
+    }
+}
@@ -1,19 +1,63 @@
+use std::env;
 use std::io;
 use std::io::Write;
-use std::process::{Command, ExitStatus};
+use std::process::{Command, ExitStatus, Output, Stdio};
 
 use errors::*;
 
+/// How much Xargo reports about what it's doing
+#[derive(Clone, Copy, Debug, Eq, Ord, PartialEq, PartialOrd)]
+pub enum Verbosity {
+    /// `-q`: errors and warnings only
+    Quiet,
+    Normal,
+    /// `-v`: also the commands Xargo runs
+    Verbose,
+    /// `-vv`: the commands as shell lines, along with the environment
+    /// variables Xargo sets for them
+    VeryVerbose,
+}
+
+impl Verbosity {
+    /// `-v` or more
+    pub fn is_verbose(self) -> bool {
+        self >= Verbosity::Verbose
+    }
+
+    pub fn is_quiet(self) -> bool {
+        self == Verbosity::Quiet
+    }
+
+    /// Whether Xargo reports its progress, e.g. which target it's on: not
+    /// with `-q`, nor with `--quiet-on-success` unless `-v` is passed too
+    pub fn shows_progress(self) -> bool {
+        self.is_verbose() || (!self.is_quiet() && !quiet_on_success())
+    }
+
+    /// The flag that gives Cargo the same verbosity, if any
+    pub fn cargo_flag(self) -> Option<&'static str> {
+        match self {
+            Verbosity::Quiet => Some("-q"),
+            Verbosity::Normal => None,
+            Verbosity::Verbose => Some("-v"),
+            Verbosity::VeryVerbose => Some("-vv"),
+        }
+    }
+}
+
 pub trait CommandExt {
-    fn run(&mut self, verbose: bool) -> Result<()>;
-    fn run_and_get_status(&mut self, verbose: bool) -> Result<ExitStatus>;
-    fn run_and_get_stdout(&mut self, verbose: bool) -> Result<String>;
+    fn run(&mut self, verbosity: Verbosity) -> Result<()>;
+    fn run_and_get_status(&mut self, verbosity: Verbosity) -> Result<ExitStatus>;
+    fn run_and_get_output(&mut self, verbosity: Verbosity) -> Result<Output>;
+    fn run_and_get_stdout(&mut self, verbosity: Verbosity) -> Result<String>;
+    fn log(&self, verbosity: Verbosity);
+    fn shown(&self, verbosity: Verbosity) -> String;
 }
 
 impl CommandExt for Command {
     /// Runs the command to completion
-    fn run(&mut self, verbose: bool) -> Result<()> {
-        let status = self.run_and_get_status(verbose)?;
+    fn run(&mut self, verbosity: Verbosity) -> Result<()> {
+        let status = self.run_and_get_status(verbosity)?;
 
         if status.success() {
             Ok(())
@@ -27,21 +71,49 @@ impl CommandExt for Command {
     }
 
     /// Runs the command to completion
-    fn run_and_get_status(&mut self, verbose: bool) -> Result<ExitStatus> {
-        if verbose {
-            writeln!(io::stderr(), "+ {:?}", self).ok();
+    ///
+    /// With `--quiet-on-success`, and without `-v`, its stderr is held back
+    /// and only shown if it fails. Its stdout, e.g. the output of the program
+    /// of `xargo run`, is never held back.
+    fn run_and_get_status(&mut self, verbosity: Verbosity) -> Result<ExitStatus> {
+        if !quiet_on_success() || verbosity.is_verbose() {
+            self.log(verbosity);
+
+            return self
+                .status()
+                .chain_err(|| format!("couldn't execute `{:?}`", self));
         }
 
-        self.status()
-            .chain_err(|| format!("couldn't execute `{:?}`", self))
+        self.run_and_get_output(verbosity).map(|out| out.status)
     }
 
-    /// Runs the command to completion and returns its stdout
-    fn run_and_get_stdout(&mut self, verbose: bool) -> Result<String> {
-        if verbose {
-            writeln!(io::stderr(), "+ {:?}", self).ok();
+    /// Runs the command to completion and returns its stderr along with its
+    /// status
+    ///
+    /// The stderr is shown like `run_and_get_status` shows it, but only once
+    /// the command exits.
+    fn run_and_get_output(&mut self, verbosity: Verbosity) -> Result<Output> {
+        self.log(verbosity);
+
+        let out = self
+            .stderr(Stdio::piped())
+            .spawn()
+            .and_then(|child| child.wait_with_output())
+            .chain_err(|| format!("couldn't execute `{:?}`", self))?;
+
+        if !out.status.success() || !quiet_on_success() || verbosity.is_verbose() {
+            io::stderr().write_all(&out.stderr).ok();
         }
 
+        Ok(out)
+    }
+
+    /// Runs the command to completion and returns its stdout
+    ///
+    /// On failure, the stderr of the command is the cause of the error.
+    fn run_and_get_stdout(&mut self, verbosity: Verbosity) -> Result<String> {
+        self.log(verbosity);
+
         let out = self
             .output()
             .chain_err(|| format!("couldn't execute `{:?}`", self))?;
@@ -50,11 +122,78 @@ impl CommandExt for Command {
             Ok(String::from_utf8(out.stdout)
                 .chain_err(|| format!("`{:?}` output was not UTF-8", self))?)
         } else {
-            Err(format!(
+            let msg = format!(
                 "`{:?}` failed with exit code: {:?}",
                 self,
                 out.status.code()
-            ))?
+            );
+            let stderr = String::from_utf8_lossy(&out.stderr);
+            let stderr = stderr.trim();
+
+            if stderr.is_empty() {
+                Err(msg)?
+            } else {
+                Err(Error::from(stderr)).chain_err(|| msg)
+            }
+        }
+    }
+
+    /// Prints the command to stderr, with `-v` and up
+    fn log(&self, verbosity: Verbosity) {
+        if verbosity.is_verbose() {
+            writeln!(io::stderr(), "+ {}", self.shown(verbosity)).ok();
+        }
+    }
+
+    /// The command as `log` prints it: as a shell line with `-vv`
+    fn shown(&self, verbosity: Verbosity) -> String {
+        if verbosity == Verbosity::VeryVerbose {
+            shell_line(self)
+        } else {
+            format!("{:?}", self)
         }
     }
 }
+
+/// `cmd` as a line that can be pasted into a POSIX shell, starting with the
+/// environment variables set, or removed, for it
+fn shell_line(cmd: &Command) -> String {
+    let mut unset = vec![];
+    let mut set = vec![];
+    for (k, v) in cmd.get_envs() {
+        let k = k.to_string_lossy();
+
+        match v {
+            Some(v) => set.push(format!("{}={}", k, quote(&v.to_string_lossy()))),
+            None => unset.push(format!("-u {}", quote(&k))),
+        }
+    }
+
+    let mut words = vec![];
+    if !unset.is_empty() {
+        words.push("env".to_owned());
+        words.extend(unset);
+    }
+    words.extend(set);
+    words.push(quote(&cmd.get_program().to_string_lossy()));
+    words.extend(cmd.get_args().map(|a| quote(&a.to_string_lossy())));
+
+    words.join(" ")
+}
+
+/// Quotes `word` for a POSIX shell, unless there's no need to
+pub fn quote(word: &str) -> String {
+    let plain = |c: char| c.is_ascii_alphanumeric() || "/._-+=:,@%".contains(c);
+
+    if !word.is_empty() && word.chars().all(plain) {
+        word.to_owned()
+    } else {
+        format!("'{}'", word.replace('\'', "'\\''"))
+    }
+}
+
+/// Whether the output of the commands Xargo runs is only shown when they
+/// fail, i.e. `--quiet-on-success` or `XARGO_QUIET_ON_SUCCESS`
+pub fn quiet_on_success() -> bool {
+    env::var_os("XARGO_QUIET_ON_SUCCESS").is_some_and(|v| !v.is_empty())
+}
@@ -107,13 +107,13 @@ impl Filesystem {
 
         match state {
             State::Exclusive => {
-                acquire(msg, &path, &|| f.try_lock_exclusive(), &|| {
-                    f.lock_exclusive()
+                acquire(msg, &path, &|| FileExt::try_lock_exclusive(&f), &|| {
+                    FileExt::lock_exclusive(&f)
                 })?;
             }
             State::Shared => {
-                acquire(msg, &path, &|| Ok(f.try_lock_shared()?), &|| {
-                    f.lock_shared()
+                acquire(msg, &path, &|| FileExt::try_lock_shared(&f), &|| {
+                    FileExt::lock_shared(&f)
                 })?;
             }
         }
@@ -127,11 +127,15 @@ impl Filesystem {
     pub fn display(&self) -> Display<'_> {
         self.path.display()
     }
+
+    pub fn as_path_unlocked(&self) -> &Path {
+        &self.path
+    }
 }
 
 impl Drop for FileLock {
     fn drop(&mut self) {
-        self.file.unlock().ok();
+        FileExt::unlock(&self.file).ok();
     }
 }
 
@@ -0,0 +1,95 @@
+//! `xargo info`
+
+use std::io::{self, Write};
+
+use serde_json::{Map, Value};
+
+use cli::Args;
+use errors::*;
+use sysroot::{self, XargoMode};
+use {canonical, cargo, resolve, rustc, CompilationMode};
+
+/// Prints what Xargo resolves the target of `args` to, and where its sysroot
+/// is, in the `--format` of `args`
+///
+/// Nothing is built: the hash is the one the sysroot would have.
+pub fn print(args: &Args, cargo_mode: XargoMode) -> Result<()> {
+    if args.targets().len() > 1 {
+        bail!("`xargo info` takes at most one `--target`")
+    }
+
+    let json = match args.format() {
+        None | Some("human") => false,
+        Some("json") => true,
+        Some(format) => bail!(
+            "`xargo info`: unknown `--format` `{}`, expected `human` or `json`",
+            format
+        ),
+    };
+
+    let meta = rustc::version()?;
+    let config = cargo::config()?;
+    let build = resolve(args, cargo_mode, &meta, config.as_ref())?
+        .ok_or("`xargo info` must be run in a Cargo project")?;
+
+    let (kind, spec, std) = match build.cmode {
+        CompilationMode::Cross(ref target) => (
+            if target.is_custom() {
+                "custom"
+            } else {
+                "builtin"
+            },
+            target.json_path().map(|p| p.display().to_string()),
+            target.supports_std(args.verbosity())?,
+        ),
+        CompilationMode::Native(_) => ("builtin", None, true),
+    };
+    let hash = sysroot::hash_inputs(
+        &build.cmode,
+        &build.root,
+        &build.rustflags,
+        &meta,
+        &build.src,
+        cargo_mode,
+    )?
+    .finish();
+
+    let fields = vec![
+        ("triple", Some(build.cmode.triple().into())),
+        ("kind", Some(kind.into())),
+        ("spec_path", spec.map(Value::from)),
+        ("supports_std", Some(std.into())),
+        (
+            "sysroot",
+            Some(
+                canonical(&build.home.sysroot())
+                    .display()
+                    .to_string()
+                    .into(),
+            ),
+        ),
+        ("hash", Some(format!("{:016x}", hash).into())),
+        ("rustc_version", Some(meta.semver.to_string().into())),
+    ];
+
+    let stdout = io::stdout();
+    let mut stdout = stdout.lock();
+    if json {
+        let object = fields
+            .into_iter()
+            .map(|(k, v)| (k.to_owned(), v.unwrap_or(Value::Null)))
+            .collect::<Map<_, _>>();
+
+        writeln!(stdout, "{}", Value::Object(object)).ok();
+    } else {
+        for (k, v) in fields {
+            match v {
+                Some(Value::String(v)) => writeln!(stdout, "{}: {}", k, v).ok(),
+                Some(v) => writeln!(stdout, "{}: {}", k, v).ok(),
+                None => None,
+            };
+        }
+    }
+
+    Ok(())
+}
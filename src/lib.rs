@@ -4,6 +4,8 @@
 extern crate error_chain;
 extern crate dirs;
 extern crate fs2;
+#[macro_use]
+extern crate lazy_static;
 #[cfg(any(
     all(target_os = "linux", not(target_env = "musl")),
     target_os = "macos"
@@ -15,29 +17,223 @@ extern crate tempdir;
 extern crate toml;
 extern crate walkdir;
 
-use std::hash::{Hash, Hasher};
 use std::io::Write;
-use std::path::Path;
-use std::process::ExitStatus;
-use std::{env, io, process};
+use std::path::{Path, PathBuf};
+use std::process::{Command, ExitStatus};
+use std::sync::{Arc, Mutex};
+use std::{cmp, env, io, process, thread};
 
-use rustc_version::Channel;
+use rustc_version::{Channel, VersionMeta};
 
 use errors::*;
-use rustc::Target;
 
 mod cargo;
+mod cfg;
+mod clean;
 mod cli;
 mod errors;
 mod extensions;
 mod flock;
+mod info;
+mod lockfile;
 mod rustc;
+mod spec;
 mod sysroot;
 mod util;
 mod xargo;
 
+pub use errors::{Error, ErrorKind, Result};
+pub use extensions::Verbosity;
+pub use rustc::{Sysroot, Target};
 pub use sysroot::XargoMode;
 
+/// Where, and how, `build_sysroot` builds a sysroot
+#[derive(Clone, Debug)]
+pub struct Config {
+    manifest_path: Option<PathBuf>,
+    mode: XargoMode,
+    install_components: bool,
+    sysroot_dir: Option<PathBuf>,
+    force: bool,
+}
+
+impl Config {
+    /// The sysroot of the Cargo project in the current directory, built the
+    /// way `xargo build` does
+    pub fn new() -> Config {
+        Config {
+            manifest_path: None,
+            mode: XargoMode::Build,
+            install_components: false,
+            sysroot_dir: None,
+            force: false,
+        }
+    }
+
+    /// Uses the Cargo project of the `Cargo.toml` at `path`, like
+    /// `--manifest-path`
+    pub fn manifest_path<P>(mut self, path: P) -> Config
+    where
+        P: Into<PathBuf>,
+    {
+        self.manifest_path = Some(path.into());
+        self
+    }
+
+    /// Builds the sysroot like `xargo` does, or only checks it like
+    /// `xargo-check` does
+    pub fn mode(mut self, mode: XargoMode) -> Config {
+        self.mode = mode;
+        self
+    }
+
+    /// Lets Xargo install the `rust-src` component if it's missing, like
+    /// `--install-components`
+    pub fn install_components(mut self, install: bool) -> Config {
+        self.install_components = install;
+        self
+    }
+
+    /// Builds the sysroot in the directory at `path` instead of Xargo's home,
+    /// like `--sysroot-dir`
+    pub fn sysroot_dir<P>(mut self, path: P) -> Config
+    where
+        P: Into<PathBuf>,
+    {
+        self.sysroot_dir = Some(path.into());
+        self
+    }
+
+    /// Lets the `sysroot_dir` have other files in it, like `--force`
+    pub fn force(mut self, force: bool) -> Config {
+        self.force = force;
+        self
+    }
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config::new()
+    }
+}
+
+/// Builds the sysroot of `triple`, unless it's up to date, and returns it
+///
+/// This is what `xargo build --target $triple` does before it runs Cargo.
+/// The sysroot is the one `--sysroot` should point to. A `triple` that's neither
+/// a builtin nor a custom target is an `ErrorKind::TargetNotFound`.
+pub fn build_sysroot(triple: &str, config: &Config, verbose: bool) -> Result<Sysroot> {
+    let args = config_args(triple, config, verbose)?;
+    let verbosity = args.verbosity();
+
+    rustc::with_toolchain(rustc::toolchain(&args)?, || {
+        let meta = rustc::version()?;
+        let cargo_config = cargo::config()?;
+        let build = config_build(&args, triple, config, &meta, cargo_config.as_ref())?;
+
+        sysroot::update(
+            &build.cmode,
+            &build.home,
+            &build.root,
+            &build.rustflags,
+            &meta,
+            &build.src,
+            &build.sysroot,
+            verbosity,
+            &args,
+            config.mode,
+        )?;
+
+        Ok(build.home.sysroot())
+    })
+}
+
+/// Whether the sysroot of `triple` is up to date, i.e. whether
+/// `build_sysroot` would return it without building anything
+///
+/// Nothing is compiled: this computes the hash of the sysroot and compares it
+/// to the one of the sysroot on disk, if there's one. When cross compiling,
+/// the copy of the host libraries must be up to date as well.
+pub fn is_fresh(triple: &str, config: &Config, verbose: bool) -> Result<bool> {
+    let args = config_args(triple, config, verbose)?;
+
+    rustc::with_toolchain(rustc::toolchain(&args)?, || {
+        let meta = rustc::version()?;
+        let cargo_config = cargo::config()?;
+        let build = config_build(&args, triple, config, &meta, cargo_config.as_ref())?;
+
+        let fresh = sysroot::up_to_date(
+            &build.cmode,
+            &build.home,
+            &build.root,
+            &build.rustflags,
+            &meta,
+            &build.src,
+            config.mode,
+        )?;
+
+        Ok(fresh.contains(&build.home.rustlib(build.cmode.triple()))
+            && (build.cmode.is_native() || fresh.contains(&build.home.rustlib(&meta.host))))
+    })
+}
+
+/// The commit hash of the `rustc` that builds sysroots, for provenance
+///
+/// That's `XARGO_SYSROOT_RUSTC` if it's set, or else `RUSTC` or the `rustc` in
+/// `PATH`. Returns `None` for a `rustc` that doesn't know its commit, e.g. one
+/// built from a source tarball.
+pub fn commit_hash(verbosity: Verbosity) -> Result<Option<String>> {
+    rustc::commit_hash(verbosity)
+}
+
+/// The arguments of `xargo build --target $triple` with the settings of
+/// `config`
+fn config_args(triple: &str, config: &Config, verbose: bool) -> Result<cli::Args> {
+    let mut all = vec!["build".to_owned(), "--target".to_owned(), triple.to_owned()];
+    if let Some(ref path) = config.manifest_path {
+        let path = path
+            .to_str()
+            .ok_or_else(|| format!("{} is not a valid UTF-8 path", path.display()))?;
+        all.push("--manifest-path".to_owned());
+        all.push(path.to_owned());
+    }
+    if config.install_components {
+        all.push("--install-components".to_owned());
+    }
+    if let Some(ref dir) = config.sysroot_dir {
+        let dir = dir
+            .to_str()
+            .ok_or_else(|| format!("{} is not a valid UTF-8 path", dir.display()))?;
+        all.push("--sysroot-dir".to_owned());
+        all.push(dir.to_owned());
+    }
+    if config.force {
+        all.push("--force".to_owned());
+    }
+    if verbose {
+        all.push("--verbose".to_owned());
+    }
+
+    Ok(cli::parse(all))
+}
+
+/// Resolves the target of `args`, made by `config_args`
+fn config_build(
+    args: &cli::Args,
+    triple: &str,
+    config: &Config,
+    meta: &VersionMeta,
+    cargo_config: Option<&cargo::Config>,
+) -> Result<Build> {
+    resolve(args, config.mode, meta, cargo_config)?.ok_or_else(|| {
+        format!(
+            "can't build a sysroot for {}: there's no Cargo project",
+            triple
+        )
+        .into()
+    })
+}
+
 // We use a different sysroot for Native compilation to avoid file locking
 //
 // Cross compilation requires `lib/rustlib/$HOST` to match `rustc`'s sysroot,
@@ -50,15 +246,22 @@ pub enum CompilationMode {
 }
 
 impl CompilationMode {
-    fn hash<H>(&self, hasher: &mut H) -> Result<()>
-    where
-        H: Hasher,
-    {
+    fn hash(&self, inputs: &mut sysroot::HashInputs) -> Result<()> {
         match *self {
-            CompilationMode::Cross(ref target) => target.hash(hasher)?,
-            CompilationMode::Native(ref triple) => triple.hash(hasher),
+            CompilationMode::Cross(ref target) => target.hash(inputs)?,
+            CompilationMode::Native(ref triple) => inputs.add("target", triple, triple),
         }
 
+        // How rustc interprets the target can change between nightlies, even
+        // for the same specification file
+        let cfgs = self.cfg(Verbosity::Normal)?;
+        let shown = cfgs
+            .iter()
+            .map(|c| c.to_string())
+            .collect::<Vec<_>>()
+            .join("\n");
+        inputs.add("target cfg", &cfgs, shown);
+
         Ok(())
     }
 
@@ -69,58 +272,641 @@ impl CompilationMode {
         }
     }
 
+    /// What to pass to Cargo's `--target` for this target
+    fn target_arg(&self) -> String {
+        match *self {
+            CompilationMode::Cross(ref target) => target.target_arg(),
+            CompilationMode::Native(ref triple) => triple.clone(),
+        }
+    }
+
     fn is_native(&self) -> bool {
         match *self {
             CompilationMode::Native(_) => true,
             _ => false,
         }
     }
+
+    /// `rustc --print cfg` for this target
+    fn cfg(&self, verbosity: Verbosity) -> Result<Vec<cfg::Cfg>> {
+        match *self {
+            CompilationMode::Cross(ref target) => target.cfg(verbosity),
+            CompilationMode::Native(ref triple) => rustc::cfg(triple.as_ref(), verbosity),
+        }
+    }
+
+    /// Whether this is a WebAssembly target, going by its triple or else by
+    /// its `target_family`
+    fn is_wasm(&self) -> Result<bool> {
+        if self.triple().starts_with("wasm") {
+            return Ok(true);
+        }
+
+        let wasm = cfg::Cfg::KeyPair("target_family".to_owned(), "wasm".to_owned());
+        Ok(self.cfg(Verbosity::Normal)?.contains(&wasm))
+    }
+
+    /// Whether `std` can be built for this target, see `Target::supports_std`
+    fn supports_std(&self) -> Result<bool> {
+        match *self {
+            CompilationMode::Cross(ref target) => target.supports_std(Verbosity::Normal),
+            CompilationMode::Native(_) => Ok(true),
+        }
+    }
 }
 
-pub fn main_inner(xargo_mode: XargoMode) {
+/// Prints `e`, and its causes, to stderr
+fn report(e: &Error) {
     fn show_backtrace() -> bool {
         env::var("RUST_BACKTRACE").as_ref().map(|s| &s[..]) == Ok("1")
     }
 
+    let stderr = io::stderr();
+    let mut stderr = stderr.lock();
+
+    writeln!(stderr, "error: {}", e).ok();
+
+    for e in e.iter().skip(1) {
+        writeln!(stderr, "caused by: {}", e).ok();
+    }
+
+    if show_backtrace() {
+        if let Some(backtrace) = e.backtrace() {
+            writeln!(stderr, "{:?}", backtrace).ok();
+        }
+    } else {
+        writeln!(stderr, "note: run with `RUST_BACKTRACE=1` for a backtrace").ok();
+    }
+}
+
+pub fn main_inner(xargo_mode: XargoMode) {
     match run(xargo_mode) {
         Err(e) => {
-            let stderr = io::stderr();
-            let mut stderr = stderr.lock();
+            report(&e);
+
+            process::exit(1)
+        }
+        Ok(Some(status)) => {
+            if !status.success() {
+                exit_like(status)
+            }
+        }
+        Ok(None) => {}
+    }
+}
+
+/// Exits the way the Cargo process that ended with `status` did, so scripts
+/// can't tell Xargo apart from Cargo, e.g. with 101 for a compile error
+///
+/// On Unix, a Cargo killed by a signal gets the same signal raised on this
+/// process; if that doesn't kill it, the exit code is the one shells use,
+/// 128 plus the signal number.
+fn exit_like(status: ExitStatus) -> ! {
+    if let Some(code) = status.code() {
+        process::exit(code)
+    }
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::ExitStatusExt;
+
+        if let Some(signal) = status.signal() {
+            unsafe {
+                libc::signal(signal, libc::SIG_DFL);
+                libc::raise(signal);
+            }
+
+            process::exit(128 + signal)
+        }
+    }
+
+    process::exit(1)
+}
+
+fn run(cargo_mode: XargoMode) -> Result<Option<ExitStatus>> {
+    let mut args = cli::args();
+
+    rustc::pin_toolchain(&args)?;
+
+    // Like the toolchain, this is passed on to the processes we spawn
+    if args.force_custom() {
+        env::set_var("XARGO_FORCE_CUSTOM", "1");
+    }
+    if args.deny_sysroot_warnings() {
+        env::set_var("XARGO_DENY_SYSROOT_WARNINGS", "1");
+    }
+    if args.quiet_on_success() {
+        env::set_var("XARGO_QUIET_ON_SUCCESS", "1");
+    }
+    if args.reproducible() {
+        env::set_var("XARGO_REPRODUCIBLE", "1");
+    }
+
+    if args.subcommand() == Some(cargo::Subcommand::ValidateTarget) {
+        let spec = args
+            .target_spec()
+            .ok_or("`xargo validate-target` takes the path of a target specification")?;
+
+        return rustc::validate_target(Path::new(spec), args.verbosity()).map(|_| None);
+    }
+
+    // `--target -` reads the target specification from stdin, so it can only
+    // be the one target
+    if args.targets().iter().any(|t| t == "-") {
+        if args.targets().len() > 1 {
+            bail!("`--target -` can't be combined with other `--target`s")
+        }
+
+        args = args.with_target(&Target::from_stdin()?);
+    }
+
+    if args.subcommand() == Some(cargo::Subcommand::Clean) && args.clean_list() {
+        return clean::list(&args, cargo_mode).map(|_| None);
+    }
+
+    if args.subcommand() == Some(cargo::Subcommand::Clean) && args.clean_sysroot() {
+        return clean::sysroots(&args, cargo_mode).map(|_| None);
+    }
+
+    if args.no_sysroot() {
+        return run_prebuilt(&args).map(Some);
+    }
+
+    if args.subcommand() == Some(cargo::Subcommand::Info) {
+        return info::print(&args, cargo_mode).map(|_| None);
+    }
+
+    if args.all_configured_targets() {
+        return build_configured(&args, cargo_mode).map(|_| None);
+    }
+
+    if args.targets().len() < 2 {
+        return run_with(&args, cargo_mode);
+    }
+
+    // The sysroots live in different directories, so they can be built in
+    // parallel before Cargo is run for each target
+    let needs_sysroot = match args.subcommand() {
+        Some(sc) => sc.needs_sysroot(),
+        None => true,
+    };
+    let mut failed = match args.jobs() {
+        Some(jobs) if jobs > 1 && needs_sysroot && !args.dry_run() => {
+            let failed = build_sysroots(&args, jobs)?;
+            args = args.with_sysroots_built();
+            failed
+        }
+        _ => vec![],
+    };
 
-            writeln!(stderr, "error: {}", e).ok();
+    // Each target gets its own sysroot, so we run once per target. A failure
+    // doesn't stop us from trying the remaining targets.
+    let mut cargo_status = None;
+    for target in args.targets() {
+        if failed.contains(target) {
+            continue;
+        }
+
+        if args.verbosity().shows_progress() {
+            writeln!(io::stderr(), "xargo: target {}", target).ok();
+        }
+
+        match run_with(&args.with_target(target), cargo_mode) {
+            Ok(Some(status)) if !status.success() => {
+                failed.push(target.clone());
+                cargo_status = cargo_status.or(Some(status));
+            }
+            Ok(_) => {}
+            Err(e) => {
+                report(&e);
+                failed.push(target.clone());
+            }
+        }
+    }
+
+    if failed.is_empty() {
+        return Ok(None);
+    }
+
+    // When Cargo failed for one of the targets, Xargo exits like it did
+    match cargo_status {
+        Some(status) => {
+            writeln!(
+                io::stderr(),
+                "error: failed for target(s): {}",
+                failed.join(", ")
+            )
+            .ok();
+
+            Ok(Some(status))
+        }
+        None => bail!("failed for target(s): {}", failed.join(", ")),
+    }
+}
+
+/// Runs Cargo with the sysroot of `--sysroot-dir`, or else of
+/// `XARGO_SYSROOT`, as it is, for `--no-sysroot`
+///
+/// Nothing is resolved nor built, so the sysroot can't go stale behind the
+/// user's back, e.g. when it's cached by CI.
+fn run_prebuilt(args: &cli::Args) -> Result<ExitStatus> {
+    let dir = match args.sysroot_dir() {
+        Some(dir) => PathBuf::from(dir),
+        None => env::var_os("XARGO_SYSROOT")
+            .filter(|d| !d.is_empty())
+            .map(PathBuf::from)
+            .ok_or(
+                "`--no-sysroot` runs Cargo with a prebuilt sysroot, which must be \
+                 given with `--sysroot-dir <dir>` or `XARGO_SYSROOT=<dir>`",
+            )?,
+    };
+    let home = xargo::prebuilt(&dir)?;
+
+    let config = cargo::config()?;
+    let target = match config {
+        Some(ref config) => config.target()?,
+        None => None,
+    };
+    let triple = match args.target().or(target) {
+        // Cargo goes by the file name of a target specification
+        Some(t) if t.ends_with(".json") => Path::new(t)
+            .file_stem()
+            .and_then(|s| s.to_str())
+            .unwrap_or(t)
+            .to_owned(),
+        Some(t) => t.to_owned(),
+        None => rustc::version()?.host,
+    };
+
+    xargo::run_prebuilt(args, &triple, &home, config.as_ref(), args.verbosity())
+}
+
+/// Builds the sysroots of all the targets `Xargo.toml` configures, for
+/// `--all-configured-targets`, and then reports how each one went
+///
+/// Only the sysroots are built, as with `-j`, so caches can be warmed up
+/// before the crate is built for each target.
+fn build_configured(args: &cli::Args, cargo_mode: XargoMode) -> Result<()> {
+    let verbosity = args.verbosity();
+    let root = cargo::root(cargo_mode, args)?
+        .ok_or("`--all-configured-targets` must be used on a Cargo project")?;
+
+    let triples = Target::configured(&root)?;
+    if triples.is_empty() {
+        bail!(
+            "`--all-configured-targets`: Xargo.toml has no `[target.<triple>]` \
+             table, and there's no specification in `build.target-dir-specs`"
+        )
+    }
 
-            for e in e.iter().skip(1) {
-                writeln!(stderr, "caused by: {}", e).ok();
+    let mut found = vec![];
+    let mut failed = vec![];
+    for triple in &triples {
+        match Target::new(triple, &root, verbosity) {
+            Ok(Some(_)) => found.push(triple.clone()),
+            Ok(None) => {
+                writeln!(
+                    io::stderr(),
+                    "error: `{}` is neither a builtin target nor has a target \
+                     specification",
+                    triple
+                )
+                .ok();
+                failed.push(triple.clone());
             }
+            Err(e) => {
+                report(&e);
+                failed.push(triple.clone());
+            }
+        }
+    }
+
+    if !found.is_empty() {
+        let jobs = args.jobs().unwrap_or(1);
+        failed.extend(build_sysroots(&args.with_targets(&found), jobs)?);
+    }
+
+    if !verbosity.is_quiet() {
+        let stderr = io::stderr();
+        let mut stderr = stderr.lock();
 
-            if show_backtrace() {
-                if let Some(backtrace) = e.backtrace() {
-                    writeln!(stderr, "{:?}", backtrace).ok();
+        writeln!(stderr, "xargo: sysroots of the configured targets:").ok();
+        for triple in &triples {
+            let status = if failed.contains(triple) {
+                "failed"
+            } else {
+                "ok"
+            };
+            writeln!(stderr, "    {:<6} {}", status, triple).ok();
+        }
+    }
+
+    if !failed.is_empty() {
+        bail!(
+            "the sysroot of {} of {} target(s) couldn't be built: {}",
+            failed.len(),
+            triples.len(),
+            failed.join(", ")
+        )
+    }
+
+    Ok(())
+}
+
+/// Set in the environment of the processes `build_sysroots` spawns
+const SYSROOT_ONLY: &str = "__XARGO_SYSROOT_ONLY";
+
+/// Builds the sysroots of all the targets in `args`, with up to `jobs` of
+/// them being built at the same time
+///
+/// Each sysroot is built by a child `xargo` process. Its output is printed
+/// in one piece once it's done, so the output of different targets doesn't
+/// get mixed up. Returns the targets whose sysroot couldn't be built.
+fn build_sysroots(args: &cli::Args, jobs: usize) -> Result<Vec<String>> {
+    let exe = env::current_exe().chain_err(|| "couldn't get path to current executable")?;
+    let queue = Arc::new(Mutex::new(args.targets().to_vec().into_iter()));
+
+    let workers = (0..cmp::min(jobs, args.targets().len()))
+        .map(|_| {
+            let args = args.clone();
+            let exe = exe.clone();
+            let queue = queue.clone();
+
+            thread::spawn(move || {
+                let mut failed = vec![];
+
+                loop {
+                    let target = match queue.lock().unwrap().next() {
+                        Some(target) => target,
+                        None => break,
+                    };
+
+                    // Xargo's flags go before the `--`, if any
+                    let all = args.with_target(&target).all().to_vec();
+                    let end = all.iter().position(|a| a == "--").unwrap_or(all.len());
+                    let mut cmd = Command::new(&exe);
+                    cmd.args(&all[..end])
+                        .args(args.sysroot_flags())
+                        .args(&all[end..])
+                        .env(SYSROOT_ONLY, "1");
+                    let out = cmd.output();
+
+                    let stdout = io::stdout();
+                    let stderr = io::stderr();
+                    let (mut stdout, mut stderr) = (stdout.lock(), stderr.lock());
+
+                    if args.verbosity().shows_progress() {
+                        writeln!(stderr, "xargo: sysroot for target {}", target).ok();
+                    }
+                    match out {
+                        Ok(out) => {
+                            stdout.write_all(&out.stdout).ok();
+                            stderr.write_all(&out.stderr).ok();
+
+                            if !out.status.success() {
+                                failed.push(target);
+                            }
+                        }
+                        Err(e) => {
+                            writeln!(stderr, "error: couldn't execute `{:?}`: {}", cmd, e).ok();
+                            failed.push(target);
+                        }
+                    }
                 }
+
+                failed
+            })
+        })
+        .collect::<Vec<_>>();
+
+    let mut failed = vec![];
+    for worker in workers {
+        failed.extend(
+            worker
+                .join()
+                .map_err(|_| "a thread building sysroots panicked")?,
+        );
+    }
+
+    Ok(failed)
+}
+
+/// Returns the Rust source to build the sysroot from
+///
+/// Returns `None` on the stable and beta channels: we can't build the sysroot
+/// with those due to unstable features.
+fn src(
+    args: &cli::Args,
+    root: &cargo::Root,
+    meta: &VersionMeta,
+    sysroot: &rustc::Sysroot,
+    verbosity: Verbosity,
+) -> Result<Option<rustc::Src>> {
+    let from_url = || rustc::Src::from_url(&xargo::src_cache(Some(root))?, verbosity);
+
+    Ok(Some(match meta.channel {
+        Channel::Dev => {
+            let src = match rustc::Src::from_env()? {
+                Some(src) => src,
+                None => from_url()?.ok_or(
+                    "The XARGO_RUST_SRC env variable must be set and point to the \
+                     Rust source directory when working with the 'dev' channel",
+                )?,
+            };
+            check_src_version(&src, meta, args.strict_src())?;
+            src
+        }
+        Channel::Nightly => {
+            // A stale `XARGO_RUST_SRC`, e.g. from a shell profile, shouldn't
+            // get in the way of a `rust-src` component that's installed
+            let from_env = rustc::Src::from_env().unwrap_or_else(|e| {
+                writeln!(
+                    io::stderr(),
+                    "warning: {} Using the `rust-src` component instead.",
+                    e
+                )
+                .ok();
+                None
+            });
+
+            if let Some(src) = from_env {
+                check_src_version(&src, meta, args.strict_src())?;
+                src
+            } else if let Some(src) = from_url()? {
+                check_src_version(&src, meta, args.strict_src())?;
+                src
             } else {
-                writeln!(stderr, "note: run with `RUST_BACKTRACE=1` for a backtrace").ok();
+                let install =
+                    args.install_components() || env::var_os("XARGO_AUTO_INSTALL").is_some();
+                sysroot.src(install, verbosity)?
             }
+        }
+        Channel::Stable | Channel::Beta => return Ok(None),
+    }))
+}
 
-            process::exit(1)
+/// Warns, or errors if `strict`, when `XARGO_RUST_SRC` holds the source of
+/// another Rust version than `rustc`'s
+///
+/// Pre-release tags are ignored: a nightly `rustc` is `1.56.0-nightly` while
+/// its source is `1.56.0`.
+fn check_src_version(src: &rustc::Src, meta: &VersionMeta, strict: bool) -> Result<()> {
+    let version = match src.version() {
+        Some(version) => version,
+        None => return Ok(()),
+    };
+    let rustc = &meta.semver;
+
+    if (version.major, version.minor, version.patch) == (rustc.major, rustc.minor, rustc.patch) {
+        return Ok(());
+    }
+
+    let msg = format!(
+        "the Rust source in {} (XARGO_RUST_SRC) is version {}, but rustc is \
+         version {}",
+        src.path().display(),
+        version,
+        rustc
+    );
+
+    if strict {
+        bail!(msg)
+    }
+
+    writeln!(
+        io::stderr(),
+        "warning: {}. Building the sysroot may fail; pass `--strict-src` to \
+         make this an error.",
+        msg
+    )
+    .ok();
+
+    Ok(())
+}
+
+/// Returns the compilation mode for `target`, falling back to `build.target`
+/// and then to the host
+///
+/// A target that's neither a builtin nor a custom target is an
+/// `ErrorKind::TargetNotFound`.
+fn cmode(
+    target: Option<&str>,
+    config: Option<&cargo::Config>,
+    root: &cargo::Root,
+    meta: &VersionMeta,
+    verbosity: Verbosity,
+) -> Result<CompilationMode> {
+    Ok(if let Some(triple) = target {
+        if Path::new(triple).is_file() {
+            bail!(
+                "Xargo doesn't support files as an argument to --target. \
+                 Use `--target foo` instead of `--target foo.json`."
+            )
+        } else if triple == meta.host && !rustc::force_custom() {
+            CompilationMode::Native(meta.host.clone())
+        } else {
+            CompilationMode::Cross(Target::find(triple, root, verbosity)?)
         }
-        Ok(Some(status)) => {
-            if !status.success() {
-                process::exit(status.code().unwrap_or(1))
+    } else {
+        if let Some(config) = config {
+            if let Some(triple) = config.target()? {
+                if triple.ends_with(".json") {
+                    let json = config.root().join(triple);
+                    CompilationMode::Cross(Target::from_json_path(&json)?)
+                } else {
+                    CompilationMode::Cross(Target::find(triple, root, verbosity)?)
+                }
+            } else {
+                CompilationMode::Native(meta.host.clone())
             }
+        } else {
+            CompilationMode::Native(meta.host.clone())
         }
-        Ok(None) => {}
+    })
+}
+
+/// The path of `sysroot`, made absolute if possible
+fn canonical(sysroot: &Sysroot) -> PathBuf {
+    let path = sysroot.path();
+
+    path.canonicalize().unwrap_or_else(|_| path.to_owned())
+}
+
+/// Writes the `RUSTFLAGS` that use `sysroot` to `file`, in a form a shell can
+/// `source`
+fn write_sysroot_env(file: &Path, sysroot: &Sysroot) -> Result<()> {
+    let path = canonical(sysroot).display().to_string();
+    let contents = format!(
+        "RUSTFLAGS={}\nXARGO_SYSROOT={}\n",
+        extensions::quote(&format!("--sysroot={}", path)),
+        extensions::quote(&path)
+    );
+
+    util::write_atomic(file, &contents)
+}
+
+/// The target of `args`, and everything needed to build its sysroot
+struct Build {
+    cmode: CompilationMode,
+    home: xargo::Home,
+    root: cargo::Root,
+    rustflags: cargo::Rustflags,
+    src: rustc::Src,
+    sysroot: rustc::Sysroot,
+}
+
+/// Resolves the target of `args`, and where and from what its sysroot is built
+///
+/// Returns `None` outside of a Cargo project.
+fn resolve(
+    args: &cli::Args,
+    cargo_mode: XargoMode,
+    meta: &VersionMeta,
+    config: Option<&cargo::Config>,
+) -> Result<Option<Build>> {
+    let verbosity = args.verbosity();
+
+    let root = match cargo::root(cargo_mode, args)? {
+        Some(root) => root,
+        None => return Ok(None),
+    };
+    let sysroot = rustc::sysroot(verbosity)?;
+    let src = match src(args, &root, meta, &sysroot, verbosity)? {
+        Some(src) => src,
+        None => bail!(
+            "the sysroot can't be built for the {:?} channel. Switch to \
+             nightly.",
+            meta.channel
+        ),
+    };
+    let cmode = cmode(args.target(), config, &root, meta, verbosity)?;
+    if let CompilationMode::Cross(ref target) = cmode {
+        target.check_pointer_width(verbosity)?;
     }
+    let home = match args.sysroot_dir() {
+        Some(dir) => xargo::home_in(Path::new(dir), args.force())?,
+        None => xargo::home(&cmode, &root, cargo_mode)?,
+    };
+    let rustflags = cargo::rustflags(config, cmode.triple())?;
+
+    Ok(Some(Build {
+        cmode,
+        home,
+        root,
+        rustflags,
+        src,
+        sysroot,
+    }))
 }
 
-fn run(cargo_mode: XargoMode) -> Result<Option<ExitStatus>> {
-    let args = cli::args();
-    let verbose = args.verbose();
+fn run_with(args: &cli::Args, cargo_mode: XargoMode) -> Result<Option<ExitStatus>> {
+    let verbosity = args.verbosity();
 
-    let meta = rustc::version().map_err(|_| "could not determine rustc version")?;
+    let meta = rustc::version()?;
 
     if let Some(sc) = args.subcommand() {
         if !sc.needs_sysroot() {
-            return cargo::run(&args, verbose).map(Some);
+            return cargo::run(args, verbosity).map(Some);
         }
     } else if args.version() {
         writeln!(
@@ -130,90 +916,85 @@ fn run(cargo_mode: XargoMode) -> Result<Option<ExitStatus>> {
         )
         .ok();
 
-        return cargo::run(&args, verbose).map(Some);
+        return cargo::run(args, verbosity).map(Some);
     }
 
     let config = cargo::config()?;
-    if let Some(root) = cargo::root(cargo_mode, args.manifest_path())? {
-        // We can't build sysroot with stable or beta due to unstable features
-        let sysroot = rustc::sysroot(verbose)?;
-        let src = match meta.channel {
-            Channel::Dev => rustc::Src::from_env().ok_or(
-                "The XARGO_RUST_SRC env variable must be set and point to the \
-                 Rust source directory when working with the 'dev' channel",
-            )?,
-            Channel::Nightly => {
-                if let Some(src) = rustc::Src::from_env() {
-                    src
-                } else {
-                    sysroot.src()?
-                }
-            }
-            Channel::Stable | Channel::Beta => {
-                eprintln!(
-                    "ERROR: the sysroot can't be built for the {:?} channel. \
-                     Switch to nightly.",
-                    meta.channel
-                );
-                process::exit(1);
-            }
-        };
-        let cmode = if let Some(triple) = args.target() {
-            if Path::new(triple).is_file() {
-                bail!(
-                    "Xargo doesn't support files as an argument to --target. \
-                     Use `--target foo` instead of `--target foo.json`."
-                )
-            } else if triple == meta.host {
-                Some(CompilationMode::Native(meta.host.clone()))
-            } else {
-                Target::new(triple, &root, verbose)?.map(CompilationMode::Cross)
-            }
-        } else {
-            if let Some(ref config) = config {
-                if let Some(triple) = config.target()? {
-                    Target::new(triple, &root, verbose)?.map(CompilationMode::Cross)
-                } else {
-                    Some(CompilationMode::Native(meta.host.clone()))
+    if let Some(build) = resolve(args, cargo_mode, &meta, config.as_ref())? {
+        let Build {
+            cmode,
+            home,
+            root,
+            rustflags,
+            src,
+            sysroot,
+        } = build;
+
+        if args.print_hash() {
+            let inputs = sysroot::hash_inputs(&cmode, &root, &rustflags, &meta, &src, cargo_mode)?;
+
+            if verbosity.is_verbose() {
+                for (name, shown, hash) in inputs.iter() {
+                    writeln!(io::stderr(), "{:016x} {}: {}", hash, name, shown).ok();
                 }
-            } else {
-                Some(CompilationMode::Native(meta.host.clone()))
             }
-        };
+            writeln!(io::stdout(), "{:016x}", inputs.finish()).ok();
+
+            return Ok(None);
+        }
 
-        if let Some(cmode) = cmode {
-            let home = xargo::home(&cmode)?;
-            let rustflags = cargo::rustflags(config.as_ref(), cmode.triple())?;
+        sysroot::update(
+            &cmode, &home, &root, &rustflags, &meta, &src, &sysroot, verbosity, args, cargo_mode,
+        )?;
+
+        if args.dry_run() {
+            return Ok(None);
+        }
+
+        if let Some(file) = args.sysroot_env_file() {
+            write_sysroot_env(Path::new(file), &home.sysroot())?;
+        }
 
-            sysroot::update(
+        if env::var_os(SYSROOT_ONLY).is_some() {
+            return Ok(None);
+        }
+
+        if args.print_sysroot() {
+            writeln!(io::stdout(), "{}", canonical(&home.sysroot()).display()).ok();
+
+            return Ok(None);
+        }
+
+        if args.subcommand().is_some() || cargo_mode == XargoMode::Build {
+            return xargo::run(
+                args,
                 &cmode,
+                rustflags,
                 &home,
-                &root,
-                &rustflags,
                 &meta,
-                &src,
-                &sysroot,
-                verbose,
-                args.message_format(),
-                cargo_mode,
-            )?;
-
-            if args.subcommand().is_some() || cargo_mode == XargoMode::Build {
-                return xargo::run(
-                    &args,
-                    &cmode,
-                    rustflags,
-                    &home,
-                    &meta,
-                    config.as_ref(),
-                    verbose,
-                )
-                .map(Some);
-            } else {
-                return Ok(None);
-            }
+                config.as_ref(),
+                verbosity,
+            )
+            .map(Some);
+        } else {
+            return Ok(None);
         }
     }
 
-    cargo::run(&args, verbose).map(Some)
+    if env::var_os(SYSROOT_ONLY).is_some() {
+        return Ok(None);
+    }
+
+    if args.print_sysroot()
+        || args.print_hash()
+        || args.dry_run()
+        || args.sysroot_env_file().is_some()
+    {
+        bail!(
+            "`--print-sysroot`, `--print-hash`, `--dry-run` and \
+             `--sysroot-env-file` must be used on a Cargo project"
+        )
+    }
+
+    cargo::run(args, verbosity).map(Some)
 }
@@ -0,0 +1,175 @@
+//! `Xargo.lock`, the pinned dependencies of the sysroot
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use toml::value::{Table, Value};
+
+use errors::*;
+use util;
+
+const HEADER: &str = "# This file is generated by Xargo. It pins the dependencies of the \
+                      sysroot that\n# come from a registry or git, like `Cargo.lock` does \
+                      for a crate.\n";
+
+/// A package of a `Cargo.lock`, by name, version and source
+type Id = (String, String, String);
+
+/// The packages of `Xargo.lock`, next to `Cargo.toml`
+///
+/// Only packages with a `source` are pinned: the others are the crates of the
+/// Rust source, which the `Cargo.lock` of the Rust source pins.
+pub struct Lockfile {
+    path: PathBuf,
+    version: Option<Value>,
+    packages: BTreeMap<Id, Value>,
+    /// The packages the sysroot build resolved to, when they differ from the
+    /// ones in the file
+    changed: BTreeMap<Id, Value>,
+}
+
+impl Lockfile {
+    /// Reads the `Xargo.lock` at `path`, which may not exist yet
+    pub fn open(path: PathBuf) -> Result<Lockfile> {
+        let (version, packages) = if path.is_file() {
+            let toml = util::parse(&path)?;
+            (toml.get("version").cloned(), packages(&toml))
+        } else {
+            (None, BTreeMap::new())
+        };
+
+        Ok(Lockfile {
+            path,
+            version,
+            packages,
+            changed: BTreeMap::new(),
+        })
+    }
+
+    /// Writes the `Cargo.lock` a stage of the sysroot starts from to `dst`:
+    /// the one of the Rust source at `src`, along with the packages of this
+    /// file that the Rust source doesn't have
+    ///
+    /// The crates the Rust source has keep the versions it pins, so a new
+    /// nightly can't be held back by this file, only caught by `record`.
+    pub fn seed(&self, src: &Path, dst: &Path) -> Result<()> {
+        let mut lock =
+            util::parse(src).chain_err(|| "Cargo.lock file is missing from source dir")?;
+
+        if let Some(Value::Array(ref mut list)) =
+            lock.as_table_mut().and_then(|t| t.get_mut("package"))
+        {
+            let pinned = list
+                .iter()
+                .filter_map(|p| id(p).map(|(name, _, source)| (name, source)))
+                .collect::<Vec<_>>();
+
+            list.extend(
+                self.packages
+                    .iter()
+                    .filter(|((name, _, source), _)| {
+                        !pinned.iter().any(|(n, s)| n == name && s == source)
+                    })
+                    .map(|(_, p)| p.clone()),
+            );
+        }
+
+        util::write(dst, &lock.to_string())
+    }
+
+    /// Records the packages the `Cargo.lock` at `lock`, of a stage of the
+    /// sysroot, resolved to
+    ///
+    /// With `locked`, a package that's not in this file, as it is, is an
+    /// error.
+    pub fn record(&mut self, lock: &Path, locked: bool) -> Result<()> {
+        let toml = util::parse(lock)?;
+        if self.version.is_none() {
+            self.version = toml.get("version").cloned();
+        }
+
+        let resolved = packages(&toml);
+        let new = resolved
+            .iter()
+            .filter(|(id, p)| self.packages.get(*id) != Some(*p))
+            .collect::<Vec<_>>();
+
+        if locked && !new.is_empty() {
+            if !self.path.is_file() {
+                bail!(
+                    "`--locked` was passed, but there's no {} to build the sysroot \
+                     with. Build it once without `--locked` to create it.",
+                    self.path.display()
+                )
+            }
+
+            bail!(
+                "the dependencies of the sysroot don't match {}, and `--locked` \
+                 was passed to prevent it from being updated. Not pinned: {}",
+                self.path.display(),
+                new.iter()
+                    .map(|((name, version, _), _)| format!("{} {}", name, version))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            )
+        }
+
+        for (id, p) in new {
+            self.changed.insert(id.clone(), p.clone());
+        }
+
+        Ok(())
+    }
+
+    /// Writes the file, if the sysroot build resolved to other packages than
+    /// the ones in it
+    ///
+    /// A package that now resolves to another version replaces the old one.
+    pub fn save(self) -> Result<()> {
+        let Lockfile {
+            path,
+            version,
+            mut packages,
+            changed,
+        } = self;
+
+        if changed.is_empty() {
+            return Ok(());
+        }
+
+        packages.retain(|(name, _, source), _| {
+            !changed.keys().any(|(n, _, s)| n == name && s == source)
+        });
+        packages.extend(changed);
+
+        let mut toml = Table::new();
+        if let Some(version) = version {
+            toml.insert("version".to_owned(), version);
+        }
+        toml.insert(
+            "package".to_owned(),
+            Value::Array(packages.into_values().collect()),
+        );
+
+        util::write(&path, &format!("{}{}", HEADER, Value::Table(toml)))
+    }
+}
+
+/// The packages with a `source` of the `Cargo.lock` `toml`
+fn packages(toml: &Value) -> BTreeMap<Id, Value> {
+    toml.get("package")
+        .and_then(Value::as_array)
+        .map(|list| {
+            list.iter()
+                .filter_map(|p| id(p).map(|id| (id, p.clone())))
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// The name, version and source of `package`, if it has a source
+fn id(package: &Value) -> Option<Id> {
+    let field = |key| package.get(key).and_then(Value::as_str).map(String::from);
+
+    Some((field("name")?, field("version")?, field("source")?))
+}
@@ -1,60 +1,586 @@
-use std::env;
+use std::cell::RefCell;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::ffi::{OsStr, OsString};
 use std::hash::{Hash, Hasher};
+use std::io::{self, Read, Write};
 use std::path::{Path, PathBuf};
 use std::process::Command;
+use std::sync::Mutex;
+use std::{env, fs};
 
-pub use rustc_version::version_meta as version;
-
-use serde_json;
-use serde_json::Value;
+use rustc_version::{self, Version, VersionMeta};
+use toml::Value;
+use walkdir::WalkDir;
 
 use cargo::Root;
+use cfg::Cfg;
+use cli::Args;
 use errors::*;
-use extensions::CommandExt;
-use {rustc, util};
+use extensions::{CommandExt, Verbosity};
+use spec::Spec;
+use sysroot::HashInputs;
+use {cargo, rustc, util, xargo};
+
+lazy_static! {
+    /// `rustc --print target-list` output, keyed by the `rustc` that printed it
+    static ref TARGETS: Mutex<HashMap<OsString, Vec<String>>> = Mutex::new(HashMap::new());
+}
+
+/// Fewer builtin targets than this means `rustc --print target-list` failed
+/// silently
+const MIN_TARGETS: usize = 10;
+
+thread_local! {
+    /// The toolchain a library entry point pinned, for the duration of the
+    /// call, see `with_toolchain`
+    static PINNED: RefCell<Option<Toolchain>> = const { RefCell::new(None) };
+}
+
+/// The binaries of the rustup toolchain `pin_toolchain` pins
+#[derive(Clone)]
+pub struct Toolchain {
+    rustc: String,
+    cargo: String,
+    rustdoc: String,
+}
+
+/// `f(toolchain)` if a library entry point pinned a toolchain on this thread
+pub fn pinned<F, T>(f: F) -> Option<T>
+where
+    F: FnOnce(&Toolchain) -> T,
+{
+    PINNED.with(|p| p.borrow().as_ref().map(f))
+}
+
+/// Runs `f` with `toolchain`, if any, pinned on this thread, like
+/// `pin_toolchain` does for the whole process, but without touching the
+/// environment
+pub fn with_toolchain<F, T>(toolchain: Option<Toolchain>, f: F) -> T
+where
+    F: FnOnce() -> T,
+{
+    /// Puts back the toolchain that was pinned before, even on a panic
+    struct Restore(Option<Toolchain>);
+
+    impl Drop for Restore {
+        fn drop(&mut self) {
+            let previous = self.0.take();
+            PINNED.with(|p| *p.borrow_mut() = previous);
+        }
+    }
+
+    let _restore = Restore(PINNED.with(|p| p.replace(toolchain)));
+    f()
+}
+
+impl Toolchain {
+    /// The `cargo` of this toolchain, with the `RUSTC`, `CARGO` and `RUSTDOC`
+    /// of this toolchain in its environment
+    pub fn cargo(&self) -> Command {
+        let mut cmd = Command::new(&self.cargo);
+        cmd.env("RUSTC", &self.rustc)
+            .env("CARGO", &self.cargo)
+            .env("RUSTDOC", &self.rustdoc);
+        cmd
+    }
+}
+
+/// The `rustc` to use: the pinned one, or else `$RUSTC` or whatever `rustc`
+/// is in `$PATH`
+fn rustc() -> OsString {
+    pinned(|t| OsString::from(&t.rustc))
+        .or_else(|| env::var_os("RUSTC"))
+        .unwrap_or_else(|| OsString::from("rustc"))
+}
+
+/// Like `rustc()`, but fails if that `rustc` doesn't exist
+///
+/// Spawning a missing program only reports "No such file or directory" which
+/// is not very helpful.
+fn find() -> Result<OsString> {
+    let rustc = rustc();
+
+    if util::which(&rustc).is_some() {
+        return Ok(rustc);
+    }
+
+    match env::var_os("RUSTC") {
+        Some(var) => Err(format!(
+            "rustc not found; `RUSTC` is set to `{}`, which doesn't exist. \
+             Point it to a `rustc` binary or unset it.",
+            var.to_string_lossy()
+        ))?,
+        None => Err(
+            "rustc not found; install Rust via rustup (https://rustup.rs) \
+                     or set RUSTC",
+        )?,
+    }
+}
+
+/// `rustc -vV`
+pub fn version() -> Result<VersionMeta> {
+    VersionMeta::for_command(Command::new(find()?))
+        .chain_err(|| "could not determine rustc version")
+}
+
+/// The `rustc` that builds the sysroot, `XARGO_SYSROOT_RUSTC`, if it's not
+/// the one used for everything else
+pub fn sysroot_rustc() -> Option<OsString> {
+    env::var_os("XARGO_SYSROOT_RUSTC").filter(|r| !r.is_empty())
+}
+
+/// `rustc -vV` of `sysroot_rustc()`, if set
+pub fn sysroot_version() -> Result<Option<VersionMeta>> {
+    let rustc = match sysroot_rustc() {
+        Some(rustc) => rustc,
+        None => return Ok(None),
+    };
+
+    VersionMeta::for_command(Command::new(&rustc))
+        .map(Some)
+        .chain_err(|| {
+            format!(
+                "could not determine the version of `{}` (XARGO_SYSROOT_RUSTC)",
+                rustc.to_string_lossy()
+            )
+        })
+}
 
-fn command() -> Command {
-    env::var_os("RUSTC")
-        .map(Command::new)
-        .unwrap_or_else(|| Command::new("rustc"))
+/// The commit hash of the `rustc` that builds the sysroot: `sysroot_rustc()`,
+/// if set, or else the one used for everything else
+pub fn commit_hash(verbosity: Verbosity) -> Result<Option<String>> {
+    let rustc = match sysroot_rustc() {
+        Some(rustc) => rustc,
+        None => find()?,
+    };
+
+    let out = Command::new(&rustc)
+        .arg("-vV")
+        .run_and_get_stdout(verbosity)?;
+    let meta = rustc_version::version_meta_for(&out).chain_err(|| {
+        format!(
+            "could not determine the version of `{}`",
+            rustc.to_string_lossy()
+        )
+    })?;
+
+    Ok(meta.commit_hash)
+}
+
+/// Points `RUSTC`, `CARGO` and `RUSTDOC` at the binaries of the rustup
+/// toolchain named by `XARGO_TOOLCHAIN`, if set, or else by the
+/// `rust-toolchain` file of the project
+///
+/// This overrides any `RUSTC` / `CARGO` / `RUSTDOC` in the environment, and
+/// applies to both the sysroot build and the build of the application. The
+/// `rust-toolchain` file is only used with rustup, and like for rustup,
+/// `+toolchain`, `RUSTUP_TOOLCHAIN` and `RUSTC` take precedence over it.
+pub fn pin_toolchain(args: &Args) -> Result<()> {
+    if let Some(toolchain) = toolchain(args)? {
+        env::set_var("RUSTC", toolchain.rustc);
+        env::set_var("CARGO", toolchain.cargo);
+        env::set_var("RUSTDOC", toolchain.rustdoc);
+    }
+
+    Ok(())
+}
+
+/// The toolchain `pin_toolchain` pins, if any
+pub fn toolchain(args: &Args) -> Result<Option<Toolchain>> {
+    let verbosity = args.verbosity();
+
+    match env::var("XARGO_TOOLCHAIN") {
+        Ok(ref t) if !t.is_empty() => return pin(t, "XARGO_TOOLCHAIN", verbosity).map(Some),
+        _ => {}
+    }
+
+    let overridden = args.all().first().map(|a| a.starts_with('+')) == Some(true)
+        || env::var_os("RUSTUP_TOOLCHAIN").is_some()
+        || env::var_os("RUSTC").is_some();
+    if overridden || util::which(OsStr::new("rustup")).is_none() {
+        return Ok(None);
+    }
+
+    let dir = match args.manifest_path() {
+        Some(p) => Path::new(p)
+            .parent()
+            .unwrap_or_else(|| Path::new("."))
+            .to_owned(),
+        None => env::current_dir().chain_err(|| "couldn't get the current directory")?,
+    };
+
+    match toolchain_file(&dir)? {
+        Some((file, channel)) => pin(&channel, &file.display().to_string(), verbosity).map(Some),
+        None => Ok(None),
+    }
+}
+
+/// The binaries of `toolchain`, which comes from `origin`
+fn pin(toolchain: &str, origin: &str, verbosity: Verbosity) -> Result<Toolchain> {
+    let which = |tool: &str| -> Result<String> {
+        Command::new("rustup")
+            .args(["which", "--toolchain", toolchain, tool])
+            .run_and_get_stdout(verbosity)
+            .map(|path| path.trim().to_owned())
+            .chain_err(|| {
+                format!(
+                    "couldn't find `{}` in the `{}` toolchain ({})",
+                    tool, toolchain, origin
+                )
+            })
+    };
+
+    Ok(Toolchain {
+        rustc: which("rustc")?,
+        cargo: which("cargo")?,
+        rustdoc: which("rustdoc")?,
+    })
+}
+
+/// The closest `rust-toolchain` (or `rust-toolchain.toml`) file to `dir`,
+/// along with the channel it names
+///
+/// Like rustup, `rust-toolchain` wins when both files are in the same
+/// directory, and it may hold just the name of the channel. A file without
+/// a `toolchain.channel`, e.g. one with a `path`, is left to rustup.
+fn toolchain_file(dir: &Path) -> Result<Option<(PathBuf, String)>> {
+    for dir in dir.ancestors() {
+        for name in &["rust-toolchain", "rust-toolchain.toml"] {
+            let file = dir.join(name);
+
+            if !file.is_file() {
+                continue;
+            }
+
+            let contents = util::read(&file)?;
+            let channel = match contents.parse::<Value>() {
+                Ok(toml) => toml
+                    .get("toolchain")
+                    .and_then(|t| t.get("channel"))
+                    .and_then(Value::as_str)
+                    .map(String::from),
+                Err(_) if *name == "rust-toolchain" && contents.trim().lines().count() == 1 => {
+                    Some(contents.trim().to_owned())
+                }
+                Err(_) => Err(format!("{} is not valid TOML", file.display()))?,
+            };
+
+            return Ok(channel.map(|c| (file, c)));
+        }
+    }
+
+    Ok(None)
+}
+
+/// `rustc`, invoked through `RUSTC_WRAPPER` / `RUSTC_WORKSPACE_WRAPPER` like
+/// Cargo does when those are set
+fn command() -> Result<Command> {
+    let rustc = find()?;
+    let wrappers = ["RUSTC_WRAPPER", "RUSTC_WORKSPACE_WRAPPER"]
+        .iter()
+        .filter_map(env::var_os)
+        .filter(|w| !w.is_empty())
+        .collect::<Vec<_>>();
+
+    Ok(match wrappers.split_first() {
+        Some((wrapper, rest)) => {
+            let mut cmd = Command::new(wrapper);
+            cmd.args(rest).arg(rustc);
+            cmd
+        }
+        None => Command::new(rustc),
+    })
+}
+
+/// Whether targets must be custom targets, i.e. `--force-custom` or
+/// `XARGO_FORCE_CUSTOM`
+///
+/// This is for authors of a replacement specification for a builtin target.
+pub fn force_custom() -> bool {
+    env::var_os("XARGO_FORCE_CUSTOM").is_some_and(|v| !v.is_empty())
 }
 
 /// `rustc --print target-list`
-pub fn targets(verbose: bool) -> Result<Vec<String>> {
-    command()
+///
+/// The list is only computed once per `rustc` for the lifetime of the process.
+/// If `XARGO_TARGET_LIST` is set, the list is read from the file it points to,
+/// one triple per line, instead.
+///
+/// An empty list, or one too short to come from a working `rustc`, is an
+/// error: every target would be taken for a custom one otherwise.
+pub fn targets(verbosity: Verbosity) -> Result<Vec<String>> {
+    if let Some(list) = env::var_os("XARGO_TARGET_LIST") {
+        if !list.is_empty() {
+            let list = PathBuf::from(list);
+
+            let targets = util::read(&list)
+                .map(|t| {
+                    t.lines()
+                        .map(|l| l.trim())
+                        .filter(|l| !l.is_empty())
+                        .map(|l| l.to_owned())
+                        .collect::<Vec<_>>()
+                })
+                .chain_err(|| {
+                    format!(
+                        "couldn't read the target list (XARGO_TARGET_LIST={})",
+                        list.display()
+                    )
+                })?;
+
+            // A short list is fine here, it's only the targets that are used
+            if targets.is_empty() {
+                bail!(
+                    "the target list is empty (XARGO_TARGET_LIST={})",
+                    list.display()
+                )
+            }
+
+            return Ok(targets);
+        }
+    }
+
+    let rustc = rustc();
+    let mut cache = TARGETS.lock().unwrap();
+
+    if let Some(targets) = cache.get(&rustc) {
+        return Ok(targets.clone());
+    }
+
+    let targets = command()?
         .args(&["--print", "target-list"])
-        .run_and_get_stdout(verbose)
-        .map(|t| t.lines().map(|l| l.to_owned()).collect())
+        .run_and_get_stdout(verbosity)
+        .map(|t| {
+            t.lines()
+                .map(|l| l.trim())
+                .filter(|l| !l.is_empty())
+                .map(|l| l.to_owned())
+                .collect::<Vec<_>>()
+        })?;
+
+    // Any `rustc` knows of hundreds of targets, whichever LLVM backends it
+    // was built with
+    if targets.len() < MIN_TARGETS {
+        bail!(
+            "`{} --print target-list` printed {} target(s), so the toolchain \
+             looks broken. Reinstall it, or set XARGO_TARGET_LIST to a file \
+             listing the builtin targets.",
+            rustc.to_string_lossy(),
+            targets.len()
+        )
+    }
+    cache.insert(rustc, targets.clone());
+
+    Ok(targets)
+}
+
+/// `rustc --print cfg --target $target`
+///
+/// `target` is either a triple or the path to a target specification file
+pub fn cfg(target: &OsStr, verbosity: Verbosity) -> Result<Vec<Cfg>> {
+    let mut cmd = command()?;
+
+    // Recent nightlies only load target specification files with this flag
+    if Path::new(target).extension() == Some(OsStr::new("json")) {
+        cmd.args(["-Z", "unstable-options"]);
+    }
+
+    cmd.args(["--print", "cfg", "--target"])
+        .arg(target)
+        .run_and_get_stdout(verbosity)
+        .map(|o| o.lines().filter_map(Cfg::from_line).collect())
+}
+
+/// `xargo validate-target`: loads the target specification at `path` like a
+/// build would, without building anything
+///
+/// Xargo's own warnings, e.g. for unknown keys, come first, then the ones of
+/// rustc. rustc rejecting the specification is an error.
+pub fn validate_target(path: &Path, verbosity: Verbosity) -> Result<()> {
+    let target = Target::from_json_path(path)?;
+    let json = target.json_path().unwrap_or(path);
+
+    let mut cmd = command()?;
+    cmd.args(["-Z", "unstable-options", "--print", "cfg", "--target"])
+        .arg(json);
+    cmd.log(verbosity);
+    let out = cmd
+        .output()
+        .chain_err(|| format!("couldn't execute `{:?}`", cmd))?;
+
+    // rustc warns about the keys it ignores even when it accepts the file
+    io::stderr().write_all(&out.stderr).ok();
+    if !out.status.success() {
+        bail!("{} is not a valid target specification", path.display())
+    }
+
+    target.check_pointer_width(verbosity)?;
+
+    if verbosity.shows_progress() {
+        writeln!(io::stderr(), "{}: ok", path.display()).ok();
+    }
+
+    Ok(())
 }
 
-/// `rustc --print sysroot`
-pub fn sysroot(verbose: bool) -> Result<Sysroot> {
-    command()
+/// `rustc --print sysroot`, with symlinks resolved
+///
+/// On macOS the path rustc prints may go through symlinks, and the paths
+/// derived from it wouldn't match the resolved paths Cargo reports. The path
+/// is kept as it is when it can't be resolved.
+pub fn sysroot(verbosity: Verbosity) -> Result<Sysroot> {
+    command()?
         .args(&["--print", "sysroot"])
-        .run_and_get_stdout(verbose)
-        .map(|l| Sysroot {
-            path: PathBuf::from(l.trim()),
+        .run_and_get_stdout(verbosity)
+        .map(|l| {
+            let path = PathBuf::from(l.trim());
+
+            Sysroot {
+                path: path.canonicalize().unwrap_or(path),
+            }
         })
 }
+
 /// Path to Rust source
 pub struct Src {
     path: PathBuf,
 }
 
 impl Src {
-    pub fn from_env() -> Option<Self> {
-        env::var_os("XARGO_RUST_SRC").map(|s| {
-            let path = PathBuf::from(s);
-            // To support relative paths, we have to make sure we canonicalize
-            // before changing the working directory.
-            let path = path.canonicalize().unwrap_or(path);
-            Src { path }
-        })
+    /// Returns the Rust source `XARGO_RUST_SRC` points to, if set
+    pub fn from_env() -> Result<Option<Self>> {
+        let path = match env::var_os("XARGO_RUST_SRC") {
+            Some(s) => PathBuf::from(s),
+            None => return Ok(None),
+        };
+        // To support relative paths, we have to make sure we canonicalize
+        // before changing the working directory.
+        let path = path.canonicalize().unwrap_or(path);
+
+        if !Src::is_valid(&path) {
+            Err(format!(
+                "XARGO_RUST_SRC is set to `{}`, but neither `std/Cargo.toml` \
+                 nor `libstd/Cargo.toml` exists there. It must point to the \
+                 `library` subfolder of a Rust checkout (`src` for older \
+                 checkouts).",
+                path.display()
+            ))?
+        }
+
+        Ok(Some(Src { path }))
+    }
+
+    /// Returns the Rust source in the tarball `XARGO_RUST_SRC_URL` points to,
+    /// if set
+    ///
+    /// The tarball is downloaded with `curl`, and extracted with `tar`, into
+    /// a directory of `cache` named after the hash of the URL, so it's only
+    /// downloaded once. The source is the shallowest directory of the tarball
+    /// with a `std` crate, e.g. `library` in the `rust-src` component.
+    pub fn from_url(cache: &Path, verbosity: Verbosity) -> Result<Option<Self>> {
+        let url = match env::var("XARGO_RUST_SRC_URL") {
+            Ok(url) if !url.is_empty() => url,
+            _ => return Ok(None),
+        };
+
+        let mut hasher = DefaultHasher::new();
+        url.hash(&mut hasher);
+        let dir = cache.join(format!("{:016x}", hasher.finish()));
+
+        if !dir.is_dir() {
+            let tmp = cache.join(format!("{:016x}.tmp", hasher.finish()));
+            if tmp.exists() {
+                fs::remove_dir_all(&tmp)
+                    .chain_err(|| format!("couldn't remove {}", tmp.display()))?;
+            }
+            fs::create_dir_all(&tmp)
+                .chain_err(|| format!("couldn't create directory {}", tmp.display()))?;
+
+            let tarball = tmp.join(".tarball");
+            Command::new("curl")
+                .args(["--fail", "--silent", "--show-error", "--location"])
+                .args(["--retry", "3", "--output"])
+                .arg(&tarball)
+                .arg(&url)
+                .run(verbosity)
+                .chain_err(|| format!("couldn't download XARGO_RUST_SRC_URL ({})", url))?;
+            Command::new("tar")
+                .arg("-xf")
+                .arg(&tarball)
+                .arg("-C")
+                .arg(&tmp)
+                .run(verbosity)
+                .chain_err(|| format!("couldn't extract XARGO_RUST_SRC_URL ({})", url))?;
+            fs::remove_file(&tarball).ok();
+
+            if Src::find_in(&tmp).is_none() {
+                fs::remove_dir_all(&tmp).ok();
+                bail!(
+                    "XARGO_RUST_SRC_URL is set to `{}`, but there's no `std/Cargo.toml` \
+                     nor `libstd/Cargo.toml` in that tarball",
+                    url
+                )
+            }
+            util::write(&tmp.join(".url"), &url)?;
+
+            // Another Xargo may have been faster
+            if fs::rename(&tmp, &dir).is_err() && dir.is_dir() {
+                fs::remove_dir_all(&tmp).ok();
+            }
+        }
+
+        Src::find_in(&dir)
+            .map(|path| Some(Src { path }))
+            .ok_or_else(|| {
+                format!(
+                    "{}, the Rust source of XARGO_RUST_SRC_URL, has no `std` crate. \
+                     Remove it to download the source again.",
+                    dir.display()
+                )
+                .into()
+            })
+    }
+
+    /// The shallowest directory under `dir` that looks like Rust source
+    fn find_in(dir: &Path) -> Option<PathBuf> {
+        WalkDir::new(dir)
+            .max_depth(8)
+            .into_iter()
+            .filter_map(|e| e.ok())
+            .filter(|e| e.file_type().is_dir() && Src::is_valid(e.path()))
+            .min_by_key(|e| e.depth())
+            .map(|e| e.path().to_owned())
+    }
+
+    /// Whether `path` looks like Rust source, i.e. has a `std` crate
+    fn is_valid(path: &Path) -> bool {
+        path.join("std").join("Cargo.toml").is_file()
+            || path.join("libstd").join("Cargo.toml").is_file()
     }
 
     pub fn path(&self) -> &Path {
         &self.path
     }
+
+    /// The Rust version of this source, if known
+    ///
+    /// Rust checkouts record it in `src/version`, next to `library` (or in
+    /// `version`, inside `src`, for older checkouts).
+    pub fn version(&self) -> Option<Version> {
+        let candidates = [
+            self.path.parent().map(|p| p.join("src").join("version")),
+            Some(self.path.join("version")),
+        ];
+
+        candidates
+            .iter()
+            .flatten()
+            .filter(|p| p.is_file())
+            .filter_map(|p| util::read(p).ok())
+            .filter_map(|v| Version::parse(v.trim()).ok())
+            .next()
+    }
 }
 
 /// Path to `rustc`'s sysroot
@@ -63,84 +589,497 @@ pub struct Sysroot {
 }
 
 impl Sysroot {
+    pub fn new(path: PathBuf) -> Sysroot {
+        Sysroot { path }
+    }
+
     pub fn path(&self) -> &Path {
         &self.path
     }
 
+    /// The directory holding the rlibs of `triple`, `lib/rustlib/$triple/lib`
+    pub fn rustlib_dir(&self, triple: &str) -> PathBuf {
+        self.path()
+            .join("lib")
+            .join("rustlib")
+            .join(triple)
+            .join("lib")
+    }
+
     /// Returns the path to Rust source, `$SRC`, where `$SRC/libstd/Cargo.toml`
     /// or `$SRC/std/Cargo.toml` exists.
-    pub fn src(&self) -> Result<Src> {
-        let src = self.path().join("lib").join("rustlib").join("src");
-
-        if src
-            .join("rust")
-            .join("src")
-            .join("libstd")
-            .join("Cargo.toml")
-            .is_file()
-        {
-            return Ok(Src {
-                path: src.join("rust").join("src"),
-            });
+    ///
+    /// If the `rust-src` component is missing and this sysroot belongs to a
+    /// rustup toolchain, `install` lets us add the component via rustup.
+    pub fn src(&self, install: bool, verbosity: Verbosity) -> Result<Src> {
+        if let Some(src) = self.find_src() {
+            return Ok(src);
         }
 
-        if src
-            .join("rust")
-            .join("library")
-            .join("std")
-            .join("Cargo.toml")
-            .is_file()
-        {
-            return Ok(Src {
-                path: src.join("rust").join("library"),
-            });
+        if let Some(toolchain) = self.rustup_toolchain() {
+            if !install {
+                Err("`rust-src` component not found. Run `rustup component add \
+                     rust-src`, or pass `--install-components` (or set \
+                     `XARGO_AUTO_INSTALL`) to let Xargo install it.")?
+            }
+
+            Command::new("rustup")
+                .args(["component", "add", "rust-src", "--toolchain", toolchain])
+                .run(verbosity)
+                .chain_err(|| "couldn't install the `rust-src` component")?;
+
+            if let Some(src) = self.find_src() {
+                return Ok(src);
+            }
         }
 
         Err("`rust-src` component not found. Run `rustup component add \
              rust-src`.")?
     }
+
+    fn find_src(&self) -> Option<Src> {
+        let src = self.path().join("lib").join("rustlib").join("src");
+
+        [
+            src.join("rust").join("src"),
+            src.join("rust").join("library"),
+        ]
+        .iter()
+        .find(|p| Src::is_valid(p))
+        .map(|p| Src { path: p.clone() })
+    }
+
+    /// Returns the name of the rustup toolchain this sysroot belongs to, if
+    /// any
+    ///
+    /// rustup keeps its toolchains in `$RUSTUP_HOME/toolchains/$NAME`.
+    fn rustup_toolchain(&self) -> Option<&str> {
+        let parent = self.path().parent()?;
+
+        if parent.file_name()? == "toolchains" {
+            self.path().file_name()?.to_str()
+        } else {
+            None
+        }
+    }
 }
 
 #[derive(Debug)]
 pub enum Target {
-    Builtin { triple: String },
-    Custom { json: PathBuf, triple: String },
+    Builtin {
+        triple: String,
+    },
+    Custom {
+        /// The specification rustc loads
+        json: PathBuf,
+        triple: String,
+        /// The directory of the specification `json` was made from
+        dir: PathBuf,
+    },
 }
 
 impl Target {
-    pub fn new(triple: &str, root: &Root, verbose: bool) -> Result<Option<Target>> {
+    /// Like `new`, but a target that's neither builtin nor custom is an
+    /// `ErrorKind::TargetNotFound` naming the directories that were searched
+    pub fn find(triple: &str, root: &Root, verbosity: Verbosity) -> Result<Target> {
+        let target = Target::new(triple, root, verbosity)?;
+        let not_found = || -> Result<Target> {
+            Err(ErrorKind::TargetNotFound(triple.to_owned(), Target::searched_dirs(root)?).into())
+        };
+
+        match target {
+            Some(target) => Ok(target),
+            None if force_custom() => not_found().chain_err(|| {
+                format!(
+                    "`{}` has no target specification, which `--force-custom` \
+                     (or `XARGO_FORCE_CUSTOM`) requires",
+                    triple
+                )
+            }),
+            None => not_found(),
+        }
+    }
+
+    /// Returns `None` if `triple` is neither a builtin target nor a custom
+    /// target
+    ///
+    /// With `force_custom`, builtin targets are not considered at all.
+    pub fn new(triple: &str, root: &Root, verbosity: Verbosity) -> Result<Option<Target>> {
         let triple = triple.to_owned();
+        let inline = Target::inline_json(&triple, root)?;
+        let json = Target::find_json(&triple, root)?;
+
+        if !force_custom() && rustc::targets(verbosity)?.iter().any(|t| t == &triple) {
+            if inline.is_some() {
+                writeln!(
+                    io::stderr(),
+                    "warning: `package.metadata.xargo.target.{0}` in Cargo.toml is \
+                     ignored because `{0}` is a builtin target",
+                    triple
+                )
+                .ok();
+            }
+
+            if let Some(json) = json {
+                writeln!(
+                    io::stderr(),
+                    "warning: {} is ignored because `{}` is a builtin target. \
+                     Rename the file to use it as a custom target.",
+                    json.display(),
+                    triple
+                )
+                .ok();
+            }
 
-        if rustc::targets(verbose)?.iter().any(|t| t == &triple) {
             Ok(Some(Target::Builtin { triple: triple }))
+        } else if let Some(inline) = inline {
+            if let Some(json) = json {
+                writeln!(
+                    io::stderr(),
+                    "warning: {} is ignored because Cargo.toml specifies `{}` in \
+                     `package.metadata.xargo.target`",
+                    json.display(),
+                    triple
+                )
+                .ok();
+            }
+
+            Target::custom(inline, triple).map(Some)
+        } else if let Some(json) = json {
+            Target::custom(json, triple).map(Some)
         } else {
-            let mut json = root.path().join(&triple);
-            json.set_extension("json");
-
-            if json.exists() {
-                return Ok(Some(Target::Custom {
-                    json: json,
-                    triple: triple,
-                }));
-            } else {
-                if let Some(p) = env::var_os("RUST_TARGET_PATH") {
-                    let mut json = PathBuf::from(p);
-                    json.push(&triple);
-                    json.set_extension("json");
-
-                    if json.exists() {
-                        return Ok(Some(Target::Custom {
-                            json: json,
-                            triple: triple,
-                        }));
-                    }
+            Ok(None)
+        }
+    }
+
+    /// The targets `Xargo.toml` configures: the ones of its `[target.<triple>]`
+    /// tables, and the ones with a specification in `build.target-dir-specs`
+    pub fn configured(root: &Root) -> Result<Vec<String>> {
+        let mut triples = match xargo::toml(root)?.1 {
+            Some(toml) => toml.targets().into_iter().map(String::from).collect(),
+            None => vec![],
+        };
+
+        for dir in Target::spec_dirs(root)? {
+            let entries = match dir.read_dir() {
+                Ok(entries) => entries,
+                Err(_) => continue,
+            };
+
+            triples.extend(entries.filter_map(|e| {
+                let path = e.ok()?.path();
+
+                if path.extension()? == "json" {
+                    path.file_stem()?.to_str().map(String::from)
+                } else {
+                    None
+                }
+            }));
+        }
+
+        triples.sort();
+        triples.dedup();
+
+        Ok(triples)
+    }
+
+    /// Writes the target specification of `triple` in the `Cargo.toml` of
+    /// `root`, if there's one, to a file rustc can load
+    fn inline_json(triple: &str, root: &Root) -> Result<Option<PathBuf>> {
+        if !root.path().join("Cargo.toml").is_file() {
+            return Ok(None);
+        }
+
+        let spec = match cargo::toml(root)?.target_spec(triple) {
+            Some(toml) => Spec::from_toml(toml).chain_err(|| {
+                format!(
+                    "Cargo.toml: invalid `package.metadata.xargo.target.{}`",
+                    triple
+                )
+            })?,
+            None => return Ok(None),
+        };
+
+        Target::write_json(triple, &spec).map(Some)
+    }
+
+    /// Reads the target specification of `--target -` from stdin and returns
+    /// the triple it can be found under
+    ///
+    /// The triple is the `llvm-target` of the specification followed by the
+    /// hash of its contents, so the same specification always gets the same
+    /// sysroot, and never shadows a builtin target. The file is written like
+    /// an inline specification, and its directory is put in front of
+    /// `RUST_TARGET_PATH`.
+    pub fn from_stdin() -> Result<String> {
+        let mut json = String::new();
+        io::stdin()
+            .read_to_string(&mut json)
+            .chain_err(|| "couldn't read the target specification from stdin")?;
+        let mut spec = Spec::parse(&json)?;
+        spec.strip_schema();
+
+        let llvm_target = spec.get_str("llvm-target").ok_or(
+            "`--target -`: the target specification has no `llvm-target`, which \
+             the name of the target is made from",
+        )?;
+        let mut hasher = DefaultHasher::new();
+        spec.to_canonical_string().hash(&mut hasher);
+        let triple = format!("{}-{:08x}", llvm_target, hasher.finish() as u32);
+
+        let json = Target::write_json(&triple, &spec)?;
+        let mut dirs = json
+            .parent()
+            .map(|d| vec![d.to_owned()])
+            .unwrap_or_default();
+        dirs.extend(Target::target_path_dirs());
+        env::set_var(
+            "RUST_TARGET_PATH",
+            env::join_paths(dirs).chain_err(|| "couldn't set RUST_TARGET_PATH")?,
+        );
+
+        Ok(triple)
+    }
+
+    /// Writes `spec` to a file rustc can load as the specification of
+    /// `triple`
+    ///
+    /// The file goes in a directory named after the hash of the
+    /// specification, so the same specification always ends up in the same
+    /// file.
+    fn write_json(triple: &str, spec: &Spec) -> Result<PathBuf> {
+        let contents = spec.to_canonical_string();
+
+        let mut hasher = DefaultHasher::new();
+        contents.hash(&mut hasher);
+        let dir = env::temp_dir()
+            .join("xargo")
+            .join(format!("{:016x}", hasher.finish()));
+        let json = dir.join(format!("{}.json", triple));
+
+        if util::read(&json).ok().as_ref() != Some(&contents) {
+            fs::create_dir_all(&dir)
+                .chain_err(|| format!("couldn't create directory {}", dir.display()))?;
+            util::write(&json, &contents)?;
+        }
+
+        Ok(json)
+    }
+
+    /// Searches the target specification file of `triple` in `root`, then in
+    /// the root of its workspace, then in the `build.target-dir-specs`
+    /// directories of `Xargo.toml` and then in the directories listed in
+    /// `RUST_TARGET_PATH`
+    fn find_json(triple: &str, root: &Root) -> Result<Option<PathBuf>> {
+        let mut project = vec![root.path().to_owned()];
+        project.extend(root.workspace()?);
+
+        Target::search(
+            triple,
+            project,
+            Target::spec_dirs(root)?,
+            Target::target_path_dirs(),
+        )
+    }
+
+    /// Searches the specification file of `triple` in the `project`
+    /// directories, then in the `specs` directories and then in the
+    /// `target_path` directories
+    ///
+    /// The first directory that has the file wins. The `specs` directories
+    /// must agree with each other though, so finding files with different
+    /// contents in several of them is an error.
+    fn search(
+        triple: &str,
+        project: Vec<PathBuf>,
+        specs: Vec<PathBuf>,
+        target_path: Vec<PathBuf>,
+    ) -> Result<Option<PathBuf>> {
+        if let Some(json) = Target::find_spec(triple, project.into_iter()) {
+            return Ok(Some(json));
+        }
+
+        if let Some(json) = Target::find_json_in_specs(triple, specs)? {
+            return Ok(Some(json));
+        }
+
+        Ok(Target::find_spec(triple, target_path.into_iter()))
+    }
+
+    /// The specification file of `triple` in the first of `dirs` that has one
+    fn find_spec(triple: &str, mut dirs: impl Iterator<Item = PathBuf>) -> Option<PathBuf> {
+        let file = format!("{}.json", triple);
+
+        dirs.find_map(|dir| Target::json_in(&dir, &file))
+    }
+
+    /// The directories listed in `RUST_TARGET_PATH`
+    fn target_path_dirs() -> Vec<PathBuf> {
+        match env::var_os("RUST_TARGET_PATH") {
+            // Like rustc, treat `RUST_TARGET_PATH` as a list of paths. Empty
+            // entries would otherwise resolve against the current directory.
+            Some(p) => env::split_paths(&p)
+                .filter(|dir| !dir.as_os_str().is_empty())
+                .collect(),
+            None => vec![],
+        }
+    }
+
+    /// The directories `find_json` searches, in order
+    fn searched_dirs(root: &Root) -> Result<Vec<PathBuf>> {
+        let mut dirs = vec![root.path().to_owned()];
+        dirs.extend(root.workspace()?);
+        dirs.extend(Target::spec_dirs(root)?);
+        dirs.extend(Target::target_path_dirs());
+
+        Ok(dirs)
+    }
+
+    /// The specification `file` in `dir`, if it's there
+    ///
+    /// File systems on Windows and macOS usually ignore case, and so does
+    /// rustc's own search there, so a file like `X86_64-Unknown-None.json`
+    /// matches `x86_64-unknown-none.json` too. The path returned is then the
+    /// one of the file as it's named on disk.
+    fn json_in(dir: &Path, file: &str) -> Option<PathBuf> {
+        if cfg!(any(windows, target_os = "macos")) {
+            let found = dir.read_dir().ok().and_then(|entries| {
+                entries
+                    .filter_map(|e| e.ok())
+                    .find(|e| {
+                        e.file_name()
+                            .to_str()
+                            .map(|name| name.eq_ignore_ascii_case(file))
+                            .unwrap_or(false)
+                    })
+                    .map(|e| e.path())
+            });
+
+            if let Some(json) = found {
+                if json.is_file() {
+                    return Some(json);
                 }
             }
+        }
 
-            Ok(None)
+        let json = dir.join(file);
+
+        if json.exists() {
+            Some(json)
+        } else {
+            None
         }
     }
 
+    /// Searches the specification file of `triple` in the
+    /// `build.target-dir-specs` directories of `Xargo.toml`
+    ///
+    /// Finding different files in several of the directories is an error.
+    fn find_json_in_specs(triple: &str, dirs: Vec<PathBuf>) -> Result<Option<PathBuf>> {
+        let mut dirs = dirs.into_iter();
+        let first = match Target::find_spec(triple, &mut dirs) {
+            Some(json) => json,
+            None => return Ok(None),
+        };
+
+        let contents = util::read(&first)?;
+        while let Some(json) = Target::find_spec(triple, &mut dirs) {
+            if util::read(&json)? != contents {
+                Err(format!(
+                    "found conflicting target specifications {} and {} \
+                     in `build.target-dir-specs`",
+                    first.display(),
+                    json.display()
+                ))?
+            }
+        }
+
+        Ok(Some(first))
+    }
+
+    /// The `build.target-dir-specs` directories of `Xargo.toml`, which are
+    /// relative to `Xargo.toml`
+    fn spec_dirs(root: &Root) -> Result<Vec<PathBuf>> {
+        // The paths of a global `Xargo.toml` are already absolute
+        let (base, toml) = match xargo::toml(root)? {
+            (base, Some(toml)) => (base.unwrap_or_else(|| root.path()), toml),
+            (_, None) => return Ok(vec![]),
+        };
+        let dirs = match toml.target_dir_specs() {
+            Some(Value::String(dir)) => Some(vec![dir.clone()]),
+            Some(Value::Array(dirs)) => dirs
+                .iter()
+                .map(|d| d.as_str().map(String::from))
+                .collect::<Option<Vec<_>>>(),
+            Some(_) => None,
+            None => return Ok(vec![]),
+        }
+        .ok_or("Xargo.toml: `build.target-dir-specs` must be a string or an array of strings")?;
+
+        Ok(dirs.into_iter().map(|dir| base.join(dir)).collect())
+    }
+
+    /// Loads the custom target specified by the file at `path`, without any
+    /// search
+    ///
+    /// The triple is the file name without its extension, like for rustc.
+    pub fn from_json_path(path: &Path) -> Result<Target> {
+        if !path.is_file() {
+            Err(format!(
+                "target specification {} doesn't exist",
+                path.display()
+            ))?
+        }
+
+        let triple = path
+            .file_stem()
+            .and_then(|s| s.to_str())
+            .ok_or_else(|| {
+                format!(
+                    "{} is not a valid target specification name",
+                    path.display()
+                )
+            })?
+            .to_owned();
+
+        Target::custom(path.to_owned(), triple)
+    }
+
+    /// Loads the custom target `triple` from the specification at `json`
+    fn custom(json: PathBuf, triple: String) -> Result<Target> {
+        // `root` and `RUST_TARGET_PATH` may be relative paths
+        let json = json.canonicalize().unwrap_or(json);
+        let mut spec = Spec::read(&json)?;
+
+        for key in spec.unknown_keys() {
+            writeln!(
+                io::stderr(),
+                "warning: {}: unknown target specification key `{}`",
+                json.display(),
+                key
+            )
+            .ok();
+        }
+
+        for mismatch in arch_mismatches(&triple, &spec) {
+            writeln!(io::stderr(), "warning: {}: {}", json.display(), mismatch).ok();
+        }
+
+        // The linker runs wherever Cargo runs it, so the paths to linker
+        // scripts and the like must not be relative to the specification.
+        // When they are, rustc gets a copy of it with absolute paths instead,
+        // as it does when the file has comments or trailing commas.
+        let dir = json.parent().map(Path::to_owned).unwrap_or_default();
+        let schema = spec.strip_schema();
+        let json = if spec.absolutize_link_args(&dir) || schema || spec.is_relaxed() {
+            Target::write_json(&triple, &spec)?
+        } else {
+            json
+        };
+
+        Ok(Target::Custom { json, triple, dir })
+    }
+
     pub fn triple(&self) -> &str {
         match *self {
             Target::Builtin { ref triple } => triple,
@@ -148,19 +1087,371 @@ impl Target {
         }
     }
 
-    pub fn hash<H>(&self, hasher: &mut H) -> Result<()>
-    where
-        H: Hasher,
-    {
+    /// What to pass to Cargo's `--target` for this target
+    ///
+    /// That's the triple, except with `force_custom`: rustc prefers a builtin
+    /// target over a specification file of the same name, so it gets the path
+    /// of the file instead. Both the sysroot and Cargo go by its file name,
+    /// which is the triple.
+    pub fn target_arg(&self) -> String {
+        match *self {
+            Target::Custom { ref json, .. } if force_custom() => json.display().to_string(),
+            _ => self.triple().to_owned(),
+        }
+    }
+
+    /// Whether this target was loaded from a target specification file
+    /// rather than found in `rustc --print target-list`
+    pub fn is_custom(&self) -> bool {
+        self.json_path().is_some()
+    }
+
+    /// The directory of the target specification file, if this is a custom
+    /// target
+    ///
+    /// This is where the file was found, even when rustc loads a copy of it
+    /// from `json_path`.
+    pub fn spec_dir(&self) -> Option<&Path> {
+        match *self {
+            Target::Builtin { .. } => None,
+            Target::Custom { ref dir, .. } => Some(dir),
+        }
+    }
+
+    /// Path to the target specification file, if this is a custom target
+    pub fn json_path(&self) -> Option<&Path> {
+        match *self {
+            Target::Builtin { .. } => None,
+            Target::Custom { ref json, .. } => Some(json),
+        }
+    }
+
+    /// The cfgs rustc sets for this target, as `rustc --print cfg` prints
+    /// them, e.g. `target_pointer_width="32"`
+    pub fn cfg_specs(&self, verbosity: Verbosity) -> Result<Vec<String>> {
+        Ok(self
+            .cfg(verbosity)?
+            .iter()
+            .map(|cfg| cfg.to_string())
+            .collect())
+    }
+
+    /// `rustc --print cfg` for this target
+    pub fn cfg(&self, verbosity: Verbosity) -> Result<Vec<Cfg>> {
+        match self.json_path() {
+            Some(json) => cfg(json.as_os_str(), verbosity),
+            None => cfg(self.triple().as_ref(), verbosity),
+        }
+    }
+
+    /// Warns when the pointer width of the target specification, or of its
+    /// `data-layout`, isn't the `target_pointer_width` rustc derives from it
+    ///
+    /// Such a target gets a sysroot, but fails much later, at link time.
+    pub fn check_pointer_width(&self, verbosity: Verbosity) -> Result<()> {
+        let json = match self.json_path() {
+            Some(json) => json,
+            None => return Ok(()),
+        };
+        let spec = Spec::read(json)?;
+        let derived = self.cfg(verbosity)?.into_iter().find_map(|cfg| match cfg {
+            Cfg::KeyPair(ref k, v) if k == "target_pointer_width" => Some(v),
+            _ => None,
+        });
+        let derived = match derived {
+            Some(derived) => derived,
+            None => return Ok(()),
+        };
+
+        for (key, width) in [
+            ("target-pointer-width", spec.pointer_width()),
+            ("data-layout", spec.data_layout_pointer_width()),
+        ] {
+            match width {
+                Some(width) if width.to_string() != derived => {
+                    writeln!(
+                        io::stderr(),
+                        "warning: {}: `{}` makes pointers {} bits wide, but rustc \
+                         derives `target_pointer_width=\"{}\"` for `{}`",
+                        json.display(),
+                        key,
+                        width,
+                        derived,
+                        self.triple()
+                    )
+                    .ok();
+                }
+                _ => {}
+            }
+        }
+
+        Ok(())
+    }
+
+    /// `RUST_TARGET_PATH` with the directory of the target specification file
+    /// in front, so rustc finds the file wherever Cargo invokes it
+    ///
+    /// Returns `None` for builtin targets.
+    pub fn target_path(&self) -> Result<Option<OsString>> {
+        let dir = match self.json_path().and_then(Path::parent) {
+            Some(dir) => dir.to_owned(),
+            None => return Ok(None),
+        };
+
+        let mut dirs = vec![dir];
+        dirs.extend(Target::target_path_dirs());
+
+        env::join_paths(dirs)
+            .map(Some)
+            .chain_err(|| "couldn't add the target specification to RUST_TARGET_PATH")
+    }
+
+    /// Whether this target has an operating system, and thus may support
+    /// `std`
+    ///
+    /// That's a target of the `unix` or `windows` family, or with a
+    /// `target_os` other than `"none"` or `"unknown"`, as `rustc --print cfg`
+    /// tells. Custom targets without an `os` field default to `"none"`, just
+    /// like rustc does, and `wasm32-unknown-unknown` has none either.
+    pub fn has_os(&self, verbosity: Verbosity) -> Result<bool> {
+        Ok(self.cfg(verbosity)?.iter().any(|cfg| match *cfg {
+            Cfg::Name(ref family) => family == "unix" || family == "windows",
+            Cfg::KeyPair(ref k, ref os) => k == "target_os" && os != "none" && os != "unknown",
+        }))
+    }
+
+    /// Whether `std` can be built for this target
+    ///
+    /// A custom target may say so in the `std` of its `metadata`. Otherwise,
+    /// that's a target with an operating system, see `has_os`.
+    pub fn supports_std(&self, verbosity: Verbosity) -> Result<bool> {
+        if let Some(std) = self
+            .json_path()
+            .map(Spec::read)
+            .transpose()?
+            .and_then(|s| s.metadata_std())
+        {
+            return Ok(std);
+        }
+
+        self.has_os(verbosity)
+    }
+
+    pub fn hash(&self, inputs: &mut HashInputs) -> Result<()> {
         if let Target::Custom { ref json, .. } = *self {
             // Here we roundtrip to/from JSON to get the same hash when some
             // fields of the JSON file has been shuffled around
-            serde_json::from_str::<Value>(&util::read(json)?)
-                .chain_err(|| format!("{} is not valid JSON", json.display()))?
-                .to_string()
-                .hash(hasher);
+            let spec = Spec::read(json)?.to_canonical_string();
+            inputs.add("target specification", &spec, &spec);
         }
 
         Ok(())
     }
 }
+
+/// The architectures of the target `triple` that don't agree with each
+/// other: the one of its name, of the `llvm-target` of its specification
+/// `spec` and of its `arch`
+///
+/// rustc goes by the file name, but it's the specification that determines
+/// the generated code. Only the architectures of the name and the
+/// `llvm-target` are compared since custom targets are usually named after
+/// what they run on.
+fn arch_mismatches(triple: &str, spec: &Spec) -> Vec<String> {
+    let llvm_target = match spec.get_str("llvm-target") {
+        Some(llvm_target) => llvm_target,
+        None => return vec![],
+    };
+    let llvm_arch = rust_arch(llvm_target.split('-').next().unwrap_or(""));
+
+    let mut mismatches = vec![];
+    if rust_arch(triple.split('-').next().unwrap_or("")) != llvm_arch {
+        mismatches.push(format!(
+            "`llvm-target` is `{}`, which doesn't match the architecture of the \
+             target name `{}`",
+            llvm_target, triple
+        ));
+    }
+    if let Some(arch) = spec.get_str("arch") {
+        if arch != llvm_arch {
+            mismatches.push(format!(
+                "`llvm-target` is `{}`, whose architecture doesn't match the \
+                 `arch` `{}` of the specification",
+                llvm_target, arch
+            ));
+        }
+    }
+
+    mismatches
+}
+
+/// The `arch` of a target specification whose `llvm-target` starts with
+/// `llvm_arch`, e.g. `arm` for `thumbv6m`
+fn rust_arch(llvm_arch: &str) -> &str {
+    let prefixed = |prefixes: &[&str]| prefixes.iter().any(|p| llvm_arch.starts_with(p));
+
+    match llvm_arch {
+        "i386" | "i486" | "i586" | "i686" => "x86",
+        "bpfeb" | "bpfel" => "bpf",
+        "powerpc64le" => "powerpc64",
+        "sparcv9" => "sparc64",
+        _ if prefixed(&["aarch64", "arm64"]) => "aarch64",
+        _ if prefixed(&["arm", "thumb"]) => "arm",
+        _ if prefixed(&["x86_64"]) => "x86_64",
+        _ if prefixed(&["riscv32"]) => "riscv32",
+        _ if prefixed(&["riscv64"]) => "riscv64",
+        _ if prefixed(&["mipsisa32r6"]) => "mips32r6",
+        _ if prefixed(&["mipsisa64r6"]) => "mips64r6",
+        _ if prefixed(&["mips64"]) => "mips64",
+        _ if prefixed(&["mips"]) => "mips",
+        _ => llvm_arch,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::{Path, PathBuf};
+
+    use tempdir::TempDir;
+
+    use super::{arch_mismatches, rust_arch, Target};
+    use spec::Spec;
+
+    const TRIPLE: &str = "thumbv7m-none-eabi";
+
+    /// Creates the directory `name` in `td`, with a specification of `TRIPLE`
+    /// that contains `spec` if there's one
+    fn dir(td: &TempDir, name: &str, spec: Option<&str>) -> PathBuf {
+        let dir = td.path().join(name);
+        fs::create_dir_all(&dir).unwrap();
+        if let Some(spec) = spec {
+            fs::write(dir.join(format!("{}.json", TRIPLE)), spec).unwrap();
+        }
+        dir
+    }
+
+    fn found(dir: &Path) -> Option<PathBuf> {
+        Some(dir.join(format!("{}.json", TRIPLE)))
+    }
+
+    #[test]
+    fn search_project_first() {
+        let td = TempDir::new("xargo").unwrap();
+        let root = dir(&td, "root", Some("{}"));
+        let specs = dir(&td, "specs", Some("{}"));
+        let target_path = dir(&td, "target-path", Some("{}"));
+
+        assert_eq!(
+            Target::search(TRIPLE, vec![root.clone()], vec![specs], vec![target_path]).unwrap(),
+            found(&root)
+        );
+    }
+
+    #[test]
+    fn search_specs_before_target_path() {
+        let td = TempDir::new("xargo").unwrap();
+        let root = dir(&td, "root", None);
+        let specs = dir(&td, "specs", Some("{}"));
+        let target_path = dir(&td, "target-path", Some("{}"));
+
+        assert_eq!(
+            Target::search(TRIPLE, vec![root], vec![specs.clone()], vec![target_path]).unwrap(),
+            found(&specs)
+        );
+    }
+
+    #[test]
+    fn search_target_path_last() {
+        let td = TempDir::new("xargo").unwrap();
+        let root = dir(&td, "root", None);
+        let specs = dir(&td, "specs", None);
+        let target_path = dir(&td, "target-path", Some("{}"));
+
+        assert_eq!(
+            Target::search(
+                TRIPLE,
+                vec![root.clone()],
+                vec![specs.clone()],
+                vec![target_path.clone()]
+            )
+            .unwrap(),
+            found(&target_path)
+        );
+
+        fs::remove_file(found(&target_path).unwrap()).unwrap();
+        assert_eq!(
+            Target::search(TRIPLE, vec![root], vec![specs], vec![target_path]).unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn search_ties_go_to_the_first_directory() {
+        let td = TempDir::new("xargo").unwrap();
+        let root = dir(&td, "root", Some("{}"));
+        let workspace = dir(&td, "workspace", Some("{ }"));
+        let first = dir(&td, "first", Some("{}"));
+        let second = dir(&td, "second", Some("{ }"));
+
+        assert_eq!(
+            Target::search(TRIPLE, vec![root.clone(), workspace], vec![], vec![]).unwrap(),
+            found(&root)
+        );
+        assert_eq!(
+            Target::search(TRIPLE, vec![], vec![], vec![first.clone(), second]).unwrap(),
+            found(&first)
+        );
+    }
+
+    #[test]
+    fn search_specs_must_agree() {
+        let td = TempDir::new("xargo").unwrap();
+        let first = dir(&td, "first", Some("{}"));
+        let same = dir(&td, "same", Some("{}"));
+        let other = dir(&td, "other", Some("{ }"));
+
+        assert_eq!(
+            Target::search(TRIPLE, vec![], vec![first.clone(), same.clone()], vec![]).unwrap(),
+            found(&first)
+        );
+        assert!(Target::search(TRIPLE, vec![], vec![first, same, other], vec![]).is_err());
+    }
+
+    #[test]
+    fn rust_arch_of_llvm_targets() {
+        assert_eq!(rust_arch("thumbv6m"), "arm");
+        assert_eq!(rust_arch("armv7a"), "arm");
+        assert_eq!(rust_arch("aarch64"), "aarch64");
+        assert_eq!(rust_arch("i686"), "x86");
+        assert_eq!(rust_arch("x86_64"), "x86_64");
+        assert_eq!(rust_arch("riscv32imac"), "riscv32");
+        assert_eq!(rust_arch("mipsel"), "mips");
+        assert_eq!(rust_arch("powerpc64le"), "powerpc64");
+        assert_eq!(rust_arch("msp430"), "msp430");
+    }
+
+    #[test]
+    fn arch_mismatches_of_the_name() {
+        let spec =
+            Spec::parse(r#"{ "arch": "arm", "llvm-target": "thumbv6m-none-eabi" }"#).unwrap();
+
+        assert!(arch_mismatches("thumbv6m-none-eabi", &spec).is_empty());
+        // Named after the board, but for the same architecture
+        assert!(arch_mismatches("thumbv7em-myboard-eabi", &spec).is_empty());
+
+        let mismatches = arch_mismatches("x86_64-myboard", &spec);
+        assert_eq!(mismatches.len(), 1);
+        assert!(mismatches[0].contains("target name `x86_64-myboard`"));
+    }
+
+    #[test]
+    fn arch_mismatches_of_the_arch() {
+        let spec =
+            Spec::parse(r#"{ "arch": "x86_64", "llvm-target": "thumbv6m-none-eabi" }"#).unwrap();
+
+        let mismatches = arch_mismatches("thumbv6m-myboard-eabi", &spec);
+        assert_eq!(mismatches.len(), 1);
+        assert!(mismatches[0].contains("the `arch` `x86_64`"));
+    }
+}
@@ -0,0 +1,397 @@
+//! Custom target specification files
+
+use std::path::Path;
+
+use serde_json;
+use serde_json::Value;
+use toml;
+
+use errors::*;
+use util;
+
+/// Top level keys understood by rustc's target specification parser
+///
+/// rustc grows new fields over time so this list is only used to warn about
+/// likely typos, never to reject a specification.
+const KNOWN_KEYS: &[&str] = &[
+    "abi",
+    "abi-return-struct-as-int",
+    "allow-asm",
+    "allows-weak-linkage",
+    "arch",
+    "archive-format",
+    "asm-args",
+    "atomic-cas",
+    "binary-format",
+    "bitcode-llvm-cmdline",
+    "c-enum-min-bits",
+    "code-model",
+    "cpu",
+    "crt-objects-fallback",
+    "crt-static-allows-dylibs",
+    "crt-static-default",
+    "crt-static-respected",
+    "data-layout",
+    "debuginfo-kind",
+    "default-adjusted-cabi",
+    "default-codegen-backend",
+    "default-codegen-units",
+    "default-dwarf-version",
+    "default-hidden-visibility",
+    "default-uwtable",
+    "default-visibility",
+    "direct-access-external-data",
+    "disable-redzone",
+    "dll-prefix",
+    "dll-suffix",
+    "dwarf-version",
+    "dynamic-linking",
+    "eh-frame-header",
+    "eliminate-frame-pointer",
+    "emit-debug-gdb-scripts",
+    "entry-abi",
+    "entry-name",
+    "env",
+    "exe-suffix",
+    "executables",
+    "features",
+    "forces-embed-bitcode",
+    "frame-pointer",
+    "function-sections",
+    "generate-arange-section",
+    "has-elf-tls",
+    "has-rpath",
+    "has-thread-local",
+    "has-thumb-interworking",
+    "is-builtin",
+    "is-like-aix",
+    "is-like-android",
+    "is-like-emscripten",
+    "is-like-fuchsia",
+    "is-like-msvc",
+    "is-like-osx",
+    "is-like-solaris",
+    "is-like-wasm",
+    "is-like-windows",
+    "late-link-args",
+    "late-link-args-dynamic",
+    "late-link-args-static",
+    "limit-rdylib-exports",
+    "link-env",
+    "link-env-remove",
+    "link-script",
+    "linker",
+    "linker-flavor",
+    "linker-is-gnu",
+    "lld-flavor",
+    "llvm-abiname",
+    "llvm-args",
+    "llvm-floatabi",
+    "llvm-mcount-intrinsic",
+    "llvm-target",
+    "main-needs-argc-argv",
+    "max-atomic-width",
+    "mcount",
+    "merge-functions",
+    "metadata",
+    "min-atomic-width",
+    "min-global-align",
+    "needs-plt",
+    "no-builtins",
+    "no-default-libraries",
+    "obj-is-bitcode",
+    "only-cdylib",
+    "os",
+    "override-export-symbols",
+    "panic-strategy",
+    "plt-by-default",
+    "position-independent-executables",
+    "post-link-args",
+    "post-link-objects",
+    "post-link-objects-fallback",
+    "pre-link-args",
+    "pre-link-objects",
+    "pre-link-objects-fallback",
+    "relax-elf-relocations",
+    "relocation-model",
+    "relro-level",
+    "requires-lto",
+    "requires-uwtable",
+    "rustc-abi",
+    "simd-types-indirect",
+    "singlethread",
+    "small-data-threshold-support",
+    "split-debuginfo",
+    "stack-probes",
+    "static-position-independent-executables",
+    "staticlib-prefix",
+    "staticlib-suffix",
+    "supported-sanitizers",
+    "supported-split-debuginfo",
+    "supports-stack-protector",
+    "supports-xray",
+    "target-c-int-width",
+    "target-endian",
+    "target-family",
+    "target-mcount",
+    "target-pointer-width",
+    "tls-model",
+    "trap-unreachable",
+    "unsupported-abis",
+    "use-ctors-section",
+    "vendor",
+];
+
+/// The top level keys holding linker arguments, as maps from the linker
+/// flavor to the list of arguments
+const LINK_ARGS_KEYS: &[&str] = &[
+    "pre-link-args",
+    "late-link-args",
+    "late-link-args-dynamic",
+    "late-link-args-static",
+    "post-link-args",
+];
+
+/// Prefixes of linker arguments that are followed by a path, e.g. `-Tlink.x`
+/// or `@args.txt`. The empty prefix covers paths given on their own, like the
+/// `link.x` of `-T link.x`.
+const PATH_PREFIXES: &[&str] = &["@", "--script=", "-T", "-L", ""];
+
+/// Editor hints like `$schema` aren't part of the specification
+const SCHEMA_KEY: &str = "$schema";
+
+/// A parsed target specification file
+pub struct Spec {
+    json: Value,
+    /// Whether the file had comments or trailing commas, which rustc rejects
+    relaxed: bool,
+}
+
+impl Spec {
+    /// Reads and parses the target specification at `path`
+    pub fn read(path: &Path) -> Result<Spec> {
+        Spec::parse_relaxed(&util::read(path)?)
+            .chain_err(|| format!("{} is not valid JSON", path.display()))
+    }
+
+    /// Parses the target specification `json`
+    pub fn parse(json: &str) -> Result<Spec> {
+        Spec::parse_relaxed(json).chain_err(|| "the target specification is not valid JSON")
+    }
+
+    /// Parses `json`, allowing `//` and `/* */` comments and trailing commas
+    ///
+    /// They're blanked out rather than removed, so the line and column of a
+    /// syntax error still point into the original file.
+    fn parse_relaxed(json: &str) -> Result<Spec> {
+        let strict = strip_trailing_commas(&strip_comments(json));
+        let relaxed = strict != json;
+
+        serde_json::from_str(&strict)
+            .map(|json| Spec { json, relaxed })
+            .map_err(|e| e.to_string().into())
+    }
+
+    /// Converts a target specification written in TOML
+    pub fn from_toml(toml: &toml::Value) -> Result<Spec> {
+        if !toml.is_table() {
+            Err("a target specification must be a table")?
+        }
+
+        serde_json::to_value(toml)
+            .map(|json| Spec {
+                json,
+                relaxed: false,
+            })
+            .chain_err(|| "couldn't convert the target specification to JSON")
+    }
+
+    /// Returns the string value of the top level `key`, if any
+    pub fn get_str(&self, key: &str) -> Option<&str> {
+        self.json.get(key).and_then(Value::as_str)
+    }
+
+    /// The `target-pointer-width`, a string in older specifications and a
+    /// number in newer ones
+    pub fn pointer_width(&self) -> Option<u64> {
+        match self.json.get("target-pointer-width")? {
+            Value::String(width) => width.parse().ok(),
+            width => width.as_u64(),
+        }
+    }
+
+    /// The size of pointers the `data-layout` gives, e.g. 32 for `p:32:32`
+    pub fn data_layout_pointer_width(&self) -> Option<u64> {
+        self.get_str("data-layout")?.split('-').find_map(|spec| {
+            let spec = spec
+                .strip_prefix("p:")
+                .or_else(|| spec.strip_prefix("p0:"))?;
+            spec.split(':').next()?.parse().ok()
+        })
+    }
+
+    /// The `std` of the `metadata`, which tells whether the target supports
+    /// `std`, if set
+    pub fn metadata_std(&self) -> Option<bool> {
+        self.json.get("metadata")?.get("std")?.as_bool()
+    }
+
+    /// Returns the top level keys rustc doesn't know about
+    pub fn unknown_keys(&self) -> Vec<&str> {
+        self.json
+            .as_object()
+            .map(|o| {
+                o.keys()
+                    .map(|k| &**k)
+                    .filter(|k| !KNOWN_KEYS.contains(k) && *k != SCHEMA_KEY)
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// Removes the top level `$schema` key, if any, and returns whether there
+    /// was one
+    pub fn strip_schema(&mut self) -> bool {
+        self.json
+            .as_object_mut()
+            .map(|o| o.remove(SCHEMA_KEY).is_some())
+            .unwrap_or(false)
+    }
+
+    /// Whether the file had comments or trailing commas, so rustc must be
+    /// handed a strict copy of it
+    pub fn is_relaxed(&self) -> bool {
+        self.relaxed
+    }
+
+    /// Makes the paths in the linker arguments that are relative to `dir`,
+    /// the directory of the specification, absolute, and returns whether any
+    /// argument changed
+    ///
+    /// Only the paths of files that exist in `dir` are changed, so flags that
+    /// merely look like paths are left alone.
+    pub fn absolutize_link_args(&mut self, dir: &Path) -> bool {
+        let mut changed = false;
+
+        for key in LINK_ARGS_KEYS {
+            let flavors = match self.json.get_mut(*key).and_then(Value::as_object_mut) {
+                Some(flavors) => flavors,
+                None => continue,
+            };
+
+            for arg in flavors
+                .values_mut()
+                .filter_map(Value::as_array_mut)
+                .flat_map(|args| args.iter_mut())
+            {
+                let absolute = match arg.as_str().and_then(|a| absolutize(a, dir)) {
+                    Some(absolute) => absolute,
+                    None => continue,
+                };
+
+                *arg = Value::String(absolute);
+                changed = true;
+            }
+        }
+
+        changed
+    }
+
+    /// Stringifies the specification
+    ///
+    /// The keys are sorted, so two files that only differ in the order of
+    /// their fields produce the same string
+    pub fn to_canonical_string(&self) -> String {
+        self.json.to_string()
+    }
+}
+
+/// `json` with its comments replaced by spaces, line breaks aside
+///
+/// An unterminated block comment is left as is, for the parser to report.
+fn strip_comments(json: &str) -> String {
+    let mut out = String::with_capacity(json.len());
+    let mut chars = json.char_indices().peekable();
+    let blank =
+        |out: &mut String, s: &str| out.extend(s.chars().map(|c| if c == '\n' { c } else { ' ' }));
+
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '"' => {
+                out.push(c);
+                while let Some((_, c)) = chars.next() {
+                    out.push(c);
+                    match c {
+                        '\\' => out.extend(chars.next().map(|(_, c)| c)),
+                        '"' => break,
+                        _ => {}
+                    }
+                }
+            }
+            '/' if json[i + 1..].starts_with('/') => {
+                let end = json[i..].find('\n').map(|n| i + n).unwrap_or(json.len());
+                blank(&mut out, &json[i..end]);
+                while chars.peek().is_some_and(|&(j, _)| j < end) {
+                    chars.next();
+                }
+            }
+            '/' if json[i + 1..].starts_with('*') => match json[i + 2..].find("*/") {
+                Some(n) => {
+                    let end = i + 2 + n + 2;
+                    blank(&mut out, &json[i..end]);
+                    while chars.peek().is_some_and(|&(j, _)| j < end) {
+                        chars.next();
+                    }
+                }
+                None => {
+                    out.push_str(&json[i..]);
+                    break;
+                }
+            },
+            _ => out.push(c),
+        }
+    }
+
+    out
+}
+
+/// `json`, without comments, with the commas that directly precede a `}` or
+/// `]` replaced by spaces
+fn strip_trailing_commas(json: &str) -> String {
+    let mut out = String::with_capacity(json.len());
+    let mut chars = json.char_indices();
+
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '"' => {
+                out.push(c);
+                while let Some((_, c)) = chars.next() {
+                    out.push(c);
+                    match c {
+                        '\\' => out.extend(chars.next().map(|(_, c)| c)),
+                        '"' => break,
+                        _ => {}
+                    }
+                }
+            }
+            ',' if json[i + 1..].trim_start().starts_with(['}', ']']) => out.push(' '),
+            _ => out.push(c),
+        }
+    }
+
+    out
+}
+
+/// `arg` with the relative path that follows its prefix made absolute, if
+/// that path is a file or directory in `dir`
+fn absolutize(arg: &str, dir: &Path) -> Option<String> {
+    PATH_PREFIXES.iter().find_map(|prefix| {
+        let path = Path::new(arg.strip_prefix(prefix)?);
+
+        if path.as_os_str().is_empty() || path.is_absolute() || !dir.join(path).exists() {
+            return None;
+        }
+
+        Some(format!("{}{}", prefix, dir.join(path).display()))
+    })
+}
@@ -3,36 +3,61 @@ use std::collections::BTreeMap;
 use std::hash::{Hash, Hasher};
 use std::io::{self, Write};
 use std::path::{Path, PathBuf};
-use std::{env, fs};
+use std::process::Command;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use std::{env, fmt, fs, thread};
 
-use rustc_version::VersionMeta;
+use rustc_version::{Channel, VersionMeta};
+use serde_json::{self, Value as JsonValue};
 use tempdir::TempDir;
 use toml::{map::Map, value::Table, Value};
+use walkdir::WalkDir;
 
-use cargo::{Root, Rustflags};
+use cargo::{Profile, Root, Rustflags};
+use cfg::Expr;
+use cli::Args;
 use errors::*;
-use extensions::CommandExt;
+use extensions::{CommandExt, Verbosity};
+use lockfile::Lockfile;
 use rustc::{Src, Sysroot, Target};
 use util;
 use xargo::Home;
 use CompilationMode;
-use {cargo, xargo};
+use {cargo, rustc, xargo};
 
-fn profile() -> &'static str {
-    "release"
+/// Written last by a build of the sysroot, or a copy of the host libraries,
+/// so a directory without it is rebuilt whatever its `.hash` says: the build
+/// that wrote the hash was interrupted
+const COMPLETE: &str = ".xargo-complete";
+
+/// When the sysroot was last built or found up to date, in seconds since the
+/// Unix epoch, for `xargo clean --list`
+pub const LAST_USED: &str = ".last-used";
+
+/// The `.hash` of `dir`, if the build that wrote it completed
+fn recorded_hash(dir: &Path) -> Result<Option<String>> {
+    let hfile = dir.join(".hash");
+
+    if hfile.exists() && dir.join(COMPLETE).exists() {
+        util::read(&hfile).map(Some)
+    } else {
+        Ok(None)
+    }
 }
 
 fn build(
     cmode: &CompilationMode,
     blueprint: Blueprint,
-    ctoml: &Option<cargo::Toml>,
+    profile: Option<&Profile>,
     home: &Home,
     rustflags: &Rustflags,
+    meta: &VersionMeta,
     src: &Src,
     sysroot: &Sysroot,
-    hash: u64,
-    verbose: bool,
-    message_format: Option<&str>,
+    inputs: &HashInputs,
+    lockfile: Lockfile,
+    verbosity: Verbosity,
+    args: &Args,
     cargo_mode: XargoMode,
 ) -> Result<()> {
     const TOML: &'static str = r#"
@@ -50,12 +75,7 @@ version = "0.0.0"
     util::mkdir(&dst)?;
 
     if cmode.triple().contains("pc-windows-gnu") && cargo_mode == XargoMode::Build {
-        let src = &sysroot
-            .path()
-            .join("lib")
-            .join("rustlib")
-            .join(cmode.triple())
-            .join("lib");
+        let src = &sysroot.rustlib_dir(cmode.triple());
 
         // Some extra files are required for linking executables/dlls -- but they moved, so we have
         // to support both locations.
@@ -88,14 +108,30 @@ version = "0.0.0"
         }
     }
 
-    for (_, stage) in blueprint.stages {
-        let td = TempDir::new("xargo").chain_err(|| "couldn't create a temporary directory")?;
+    let incremental = incremental_dir(cmode, home, meta, cargo_mode)?;
+    let mut lockfile = lockfile;
+    let mut timings = vec![];
+    let jobs = blueprint.jobs;
+    for (n, stage) in blueprint.stages {
+        let tmp;
         let tdp;
-        let td = if env::var_os("XARGO_KEEP_TEMP").is_some() {
-            tdp = td.into_path();
-            &tdp
-        } else {
-            td.path()
+        let td = match incremental {
+            Some(ref dir) => {
+                tdp = dir.join(format!("stage{}", n));
+                fs::create_dir_all(tdp.join("src"))
+                    .chain_err(|| format!("couldn't create directory {}", tdp.display()))?;
+                &tdp
+            }
+            None => {
+                tmp =
+                    TempDir::new("xargo").chain_err(|| "couldn't create a temporary directory")?;
+                if env::var_os("XARGO_KEEP_TEMP").is_some() {
+                    tdp = tmp.into_path();
+                    &tdp
+                } else {
+                    tmp.path()
+                }
+            }
         };
 
         let mut stoml = TOML.to_owned();
@@ -108,136 +144,852 @@ version = "0.0.0"
             stoml.push_str(&Value::Table(map).to_string());
         }
 
-        if let Some(ctoml) = ctoml {
-            if let Some(profile) = ctoml.profile() {
-                stoml.push_str(&profile.to_string())
-            }
+        if let Some(profile) = profile {
+            stoml.push_str(&profile.to_string())
         }
 
-        // rust-src comes with a lockfile for libstd. Use it.
+        // rust-src comes with a lockfile for libstd. Use it, along with what
+        // `Xargo.lock` pins.
         let src_parent = src
             .path()
             .parent()
             .map(Path::to_path_buf)
             .unwrap_or_else(|| src.path().join(".."));
-        let lockfile = src_parent.join("Cargo.lock");
-        let target_lockfile = td.join("Cargo.lock");
-        fs::copy(lockfile, &target_lockfile)
-            .chain_err(|| "Cargo.lock file is missing from source dir")?;
-
-        let mut perms = fs::metadata(&target_lockfile)
-            .chain_err(|| "Cargo.lock file is missing from target dir")?
-            .permissions();
-        perms.set_readonly(false);
-        fs::set_permissions(&target_lockfile, perms)
-            .chain_err(|| "Cargo.lock file is missing from target dir")?;
+        lockfile.seed(&src_parent.join("Cargo.lock"), &td.join("Cargo.lock"))?;
 
         util::write(&td.join("Cargo.toml"), &stoml)?;
-        util::mkdir(&td.join("src"))?;
+        if incremental.is_none() {
+            util::mkdir(&td.join("src"))?;
+        }
         util::write(&td.join("src").join("lib.rs"), "")?;
 
-        let cargo = || {
-            let mut cmd = cargo::command();
-            let mut flags = rustflags.clone();
-            flags.push(&["-Z", "force-unstable-if-unmarked"]);
-            if verbose {
-                writeln!(io::stderr(), "+ RUSTFLAGS={}", flags).ok();
-            }
-            cmd.env("CARGO_ENCODED_RUSTFLAGS", flags.encode(home));
-
-            // Since we currently don't want to respect `.cargo/config` or `CARGO_TARGET_DIR`,
-            // we need to force the target directory to match the `cp_r` below.
-            cmd.env("CARGO_TARGET_DIR", td.join("target"));
-
-            // Workaround #261.
-            //
-            // If a crate is shared between the sysroot and a binary, we might
-            // end up with conflicting symbols. This is because both versions
-            // of the crate would get linked, and their metadata hash would be
-            // exactly the same.
-            //
-            // To avoid this, we need to inject some data that modifies the
-            // metadata hash. Fortunately, cargo already has a mechanism for
-            // this, the __CARGO_DEFAULT_LIB_METADATA environment variable.
-            // Unsurprisingly, rust's bootstrap (which has basically the same
-            // role as xargo of building the libstd) makes use of this
-            // environment variable to avoid exactly this problem. See here:
-            // https://github.com/rust-lang/rust/blob/73369f32621f6a844a80a8513ae3ded901e4a406/src/bootstrap/builder.rs#L876
-            //
-            // This relies on an **unstable cargo feature** that isn't meant to
-            // be used outside the bootstrap. This is explicitly stated in
-            // cargo's source:
-            // https://github.com/rust-lang/cargo/blob/14654f38d0819c47d7a605d6f1797ffbcdc65000/src/cargo/core/compiler/context/compilation_files.rs#L496
-            // Unfortunately, I don't see any other way out. We need to have a
-            // way to modify the crate's hash, and from the outside this is the
-            // only way to do so.
-            cmd.env("__CARGO_DEFAULT_LIB_METADATA", "xargo");
-
-            // As of rust-lang/cargo#4788 Cargo invokes rustc with a changed "current directory" so
-            // we can't assume that such directory will be the same as the directory from which
-            // Xargo was invoked. This is specially true when compiling the sysroot as the std
-            // source is provided as a workspace and Cargo will change the current directory to the
-            // root of the workspace when building one. To ensure rustc finds a target specification
-            // file stored in the current directory we'll set `RUST_TARGET_PATH`  to the current
-            // directory.
-            if env::var_os("RUST_TARGET_PATH").is_none() {
-                if let CompilationMode::Cross(ref target) = *cmode {
-                    if let Target::Custom { ref json, .. } = *target {
-                        cmd.env("RUST_TARGET_PATH", json.parent().unwrap());
-                    }
-                }
+        // Downloads are the part of the build that may fail for no reason of
+        // ours, so they're done, and retried, on their own
+        if !args.offline() {
+            let start = Instant::now();
+            fetch(cmode, td, args)?;
+            timings.push((format!("stage {} (fetch)", n), start.elapsed()));
+        }
+
+        let cargo = || -> Result<Command> {
+            let mut cmd = cargo_command(cmode, profile, home, rustflags, td, args, cargo_mode)?;
+            if let Some(jobs) = jobs {
+                cmd.args(["-j", &jobs.to_string()]);
             }
+            Ok(cmd)
+        };
 
-            match cargo_mode {
-                XargoMode::Build => cmd.arg("build"),
-                XargoMode::Check => cmd.arg("check"),
-            };
+        // The dependencies are resolved by now, unless we're offline
+        if !args.offline() {
+            lockfile.record(&td.join("Cargo.lock"), args.locked())?;
+        }
 
-            cmd.arg("--release");
-            cmd.arg("--manifest-path");
-            cmd.arg(td.join("Cargo.toml"));
-            cmd.args(&["--target", cmode.triple()]);
-            if let Some(format) = message_format {
-                cmd.args(&["--message-format", format]);
+        for krate in stage.crates {
+            let start = Instant::now();
+            let built = cargo()?.arg("-p").arg(&krate).run(verbosity);
+            // Cargo only reports its exit code, so a build that ran out of
+            // disk space is told apart by the space that's left
+            if built.is_err() && (low_on_space(td) || low_on_space(&home.rustlib(cmode.triple()))) {
+                return built.chain_err(|| disk_full_message(home));
             }
+            built?;
+            timings.push((krate, start.elapsed()));
+        }
+
+        lockfile.record(&td.join("Cargo.lock"), args.locked())?;
+
+        // Copy artifacts to Xargo sysroot
+        let deps = td
+            .join("target")
+            .join(cmode.triple())
+            .join(profile.map_or("release", Profile::dir))
+            .join("deps");
+        if incremental.is_some() {
+            cp_latest(&deps, &dst)?;
+        } else {
+            util::cp_r(&deps, &dst)?;
+        }
+    }
+
+    if args.timings() {
+        print_timings(cmode.triple(), &timings);
+    }
+
+    lockfile.save()?;
+
+    // Create hash file, and record what it's made of for `--explain-rebuild`
+    util::write(&rustlib.parent().join("inputs.json"), &inputs.to_json())?;
+    util::write(
+        &rustlib.parent().join(".hash"),
+        &inputs.finish().to_string(),
+    )?;
+    util::write(&rustlib.parent().join(COMPLETE), "")?;
+    stamp_last_used(rustlib.parent())?;
+
+    Ok(())
+}
+
+/// Records in the sysroot directory `dir` that it's used now, see `LAST_USED`
+///
+/// The caller holds the lock of the sysroot.
+fn stamp_last_used(dir: &Path) -> Result<()> {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+
+    util::write(&dir.join(LAST_USED), &now.to_string())
+}
+
+/// Prints how long each step of the sysroot build of `triple` took, for
+/// `--timings`
+///
+/// Each crate is built by its own Cargo invocation, so a crate's time
+/// includes building the dependencies that no earlier crate built.
+fn print_timings(triple: &str, timings: &[(String, Duration)]) {
+    let width = timings
+        .iter()
+        .map(|(step, _)| step.len())
+        .chain(Some("total".len()))
+        .max()
+        .unwrap_or(0);
+    let total = timings.iter().map(|&(_, time)| time).sum::<Duration>();
+
+    let stderr = io::stderr();
+    let mut stderr = stderr.lock();
+    writeln!(stderr, "xargo: sysroot build times for {}:", triple).ok();
+    for (step, time) in timings {
+        writeln!(
+            stderr,
+            "    {:width$}  {:>7.1}s",
+            step,
+            time.as_secs_f64(),
+            width = width
+        )
+        .ok();
+    }
+    writeln!(
+        stderr,
+        "    {:width$}  {:>7.1}s",
+        "total",
+        total.as_secs_f64(),
+        width = width
+    )
+    .ok();
+}
+
+/// The build directory of the sysroot of `cmode` with `XARGO_INCREMENTAL=1`
+///
+/// The directory stays the same from one build of the sysroot to the next, so
+/// Cargo only rebuilds the crates affected by what changed. It's cleared when
+/// `rustc` changes, as the artifacts of the old `rustc` would otherwise stay
+/// around.
+///
+/// A reproducible sysroot is always built from scratch.
+fn incremental_dir(
+    cmode: &CompilationMode,
+    home: &Home,
+    meta: &VersionMeta,
+    cargo_mode: XargoMode,
+) -> Result<Option<PathBuf>> {
+    if env::var("XARGO_INCREMENTAL").ok().as_deref() != Some("1") || reproducible() {
+        return Ok(None);
+    }
+
+    let dir = home.build_dir(cmode.triple()).join(match cargo_mode {
+        XargoMode::Build => "build",
+        XargoMode::Check => "check",
+    });
+
+    let mut rustc = meta.short_version_string.clone();
+    if let Some(meta) = rustc::sysroot_version()? {
+        rustc.push('\n');
+        rustc.push_str(&meta.short_version_string);
+    }
+
+    let stamp = dir.join(".rustc");
+    if dir.exists() && util::read(&stamp).ok().as_ref() != Some(&rustc) {
+        fs::remove_dir_all(&dir).chain_err(|| format!("couldn't remove {}", dir.display()))?;
+    }
+    fs::create_dir_all(&dir)
+        .chain_err(|| format!("couldn't create directory {}", dir.display()))?;
+    util::write(&stamp, &rustc)?;
+
+    Ok(Some(dir))
+}
+
+/// Copies the artifacts in `deps` to `dst`, only the latest of each
+///
+/// A build directory that's reused keeps the artifacts of earlier builds, e.g.
+/// `libcore-0123.rlib` next to `libcore-4567.rlib`, and the sysroot must only
+/// have one of them.
+fn cp_latest(deps: &Path, dst: &Path) -> Result<()> {
+    let mut latest = BTreeMap::new();
+    for entry in deps
+        .read_dir()
+        .chain_err(|| format!("couldn't read {}", deps.display()))?
+    {
+        let entry = entry.chain_err(|| format!("couldn't read {}", deps.display()))?;
+        let metadata = entry
+            .metadata()
+            .chain_err(|| format!("couldn't read the metadata of {}", entry.path().display()))?;
+        if !metadata.is_file() {
+            continue;
+        }
+
+        // `libcore-0123.rlib` is the `rlib` of `libcore`
+        let path = entry.path();
+        let stem = path
+            .file_stem()
+            .map(|s| s.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let name = match stem.rfind('-') {
+            Some(i) => stem[..i].to_owned(),
+            None => stem,
+        };
+        let key = (name, path.extension().map(|e| e.to_owned()));
 
-            if verbose {
-                cmd.arg("-v");
+        let modified = metadata.modified().ok();
+        match latest.get(&key) {
+            Some((_, newest)) if *newest >= modified => {}
+            _ => {
+                latest.insert(key, (path, modified));
             }
+        }
+    }
+
+    for (path, _) in latest.values() {
+        if let Some(name) = path.file_name() {
+            let to = dst.join(name);
+            fs::copy(path, &to).chain_err(|| {
+                format!(
+                    "copying files from `{}` to `{}` failed",
+                    path.display(),
+                    to.display()
+                )
+            })?;
+        }
+    }
+
+    Ok(())
+}
+
+/// The `cargo` command that builds (or checks) the sysroot crates of
+/// `cmode` in the sysroot project at `td`, minus the crates to build
+fn cargo_command(
+    cmode: &CompilationMode,
+    profile: Option<&Profile>,
+    home: &Home,
+    rustflags: &Rustflags,
+    td: &Path,
+    args: &Args,
+    cargo_mode: XargoMode,
+) -> Result<Command> {
+    let verbosity = args.verbosity();
+    let mut cmd = cargo::command();
+    let mut flags = rustflags.clone();
+    flags.push(&["-Z", "force-unstable-if-unmarked"]);
+    if profile.map(Profile::embed_bitcode) == Some(true) {
+        // Otherwise the application can't use LTO with these crates
+        flags.push(&["-C", "embed-bitcode=yes"]);
+    }
+    if verbosity.is_verbose() {
+        writeln!(io::stderr(), "+ RUSTFLAGS={}", flags).ok();
+    }
+    cmd.env("CARGO_ENCODED_RUSTFLAGS", flags.encode(home));
+
+    // Since we currently don't want to respect `.cargo/config` or `CARGO_TARGET_DIR`,
+    // we need to force the target directory to match the `cp_r` below. This is
+    // the only command that overrides it: the application's Cargo inherits the
+    // environment as it is.
+    cmd.env("CARGO_TARGET_DIR", td.join("target"));
+
+    // Workaround #261.
+    //
+    // If a crate is shared between the sysroot and a binary, we might
+    // end up with conflicting symbols. This is because both versions
+    // of the crate would get linked, and their metadata hash would be
+    // exactly the same.
+    //
+    // To avoid this, we need to inject some data that modifies the
+    // metadata hash. Fortunately, cargo already has a mechanism for
+    // this, the __CARGO_DEFAULT_LIB_METADATA environment variable.
+    // Unsurprisingly, rust's bootstrap (which has basically the same
+    // role as xargo of building the libstd) makes use of this
+    // environment variable to avoid exactly this problem. See here:
+    // https://github.com/rust-lang/rust/blob/73369f32621f6a844a80a8513ae3ded901e4a406/src/bootstrap/builder.rs#L876
+    //
+    // This relies on an **unstable cargo feature** that isn't meant to
+    // be used outside the bootstrap. This is explicitly stated in
+    // cargo's source:
+    // https://github.com/rust-lang/cargo/blob/14654f38d0819c47d7a605d6f1797ffbcdc65000/src/cargo/core/compiler/context/compilation_files.rs#L496
+    // Unfortunately, I don't see any other way out. We need to have a
+    // way to modify the crate's hash, and from the outside this is the
+    // only way to do so.
+    cmd.env("__CARGO_DEFAULT_LIB_METADATA", "xargo");
+
+    // As of rust-lang/cargo#4788 Cargo invokes rustc with a changed "current directory" so
+    // we can't assume that such directory will be the same as the directory from which
+    // Xargo was invoked. This is specially true when compiling the sysroot as the std
+    // source is provided as a workspace and Cargo will change the current directory to the
+    // root of the workspace when building one. To ensure rustc finds a target specification
+    // file stored in the current directory we'll add its directory to `RUST_TARGET_PATH`.
+    if let CompilationMode::Cross(ref target) = *cmode {
+        if let Some(path) = target.target_path()? {
+            cmd.env("RUST_TARGET_PATH", path);
+        }
+    }
+
+    if env::var_os("CARGO_NET_RETRY").is_none() {
+        cmd.env("CARGO_NET_RETRY", net_retry()?.to_string());
+    }
+
+    if let Some(rustc) = rustc::sysroot_rustc() {
+        cmd.env("RUSTC", rustc);
+    }
+
+    match cargo_mode {
+        XargoMode::Build => cmd.arg("build"),
+        XargoMode::Check => cmd.arg("check"),
+    };
+
+    match profile.map(Profile::name) {
+        None | Some("release") => cmd.arg("--release"),
+        Some(name) => cmd.args(["--profile", name]),
+    };
+    cmd.arg("--manifest-path");
+    cmd.arg(td.join("Cargo.toml"));
+    cmd.args(&["--target", &cmode.target_arg()]);
+    if let Some(format) = args.message_format() {
+        cmd.args(&["--message-format", format]);
+    }
+    // Not part of the hash: these are mostly about fetching the
+    // dependencies (`net.*`, `http.*`, `source.*`)
+    for config in args.configs() {
+        cmd.args(["--config", config]);
+    }
+
+    // `--locked` (and the locking half of `--frozen`) is not forwarded:
+    // the lock file comes from `rust-src` and always needs our
+    // `sysroot` package added to it
+    if args.offline() {
+        cmd.arg("--offline");
+    }
+
+    if let Some(flag) = args.cargo_verbosity().cargo_flag() {
+        cmd.arg(flag);
+    }
+
+    Ok(cmd)
+}
+
+/// Output of Cargo that tells a network failure apart from any other
+const NETWORK_ERRORS: &[&str] = &[
+    "failed to download",
+    "spurious network error",
+    "failed to fetch",
+    "failed to get `",
+    "couldn't resolve host",
+    "could not resolve host",
+    "timed out",
+    "connection refused",
+    "connection reset",
+    "network is unreachable",
+    "ssl connect error",
+];
+
+/// The longest wait, in seconds, between two attempts to download the
+/// sysroot dependencies
+const MAX_NET_RETRY_DELAY: u64 = 60;
+
+/// How many times to retry a network failure, `XARGO_NET_RETRY` or 2
+fn net_retry() -> Result<u32> {
+    match env::var("XARGO_NET_RETRY") {
+        Ok(ref n) if !n.is_empty() => n
+            .trim()
+            .parse()
+            .map_err(|_| format!("XARGO_NET_RETRY must be a number, but it's `{}`", n).into()),
+        _ => Ok(2),
+    }
+}
+
+/// The `cargo fetch` command that downloads the dependencies of the sysroot
+/// project at `td`
+fn fetch_command(cmode: &CompilationMode, td: &Path, args: &Args) -> Result<Command> {
+    let mut cmd = cargo::command();
+
+    // Cargo asks rustc about the target to tell which dependencies it needs
+    if let CompilationMode::Cross(ref target) = *cmode {
+        if let Some(path) = target.target_path()? {
+            cmd.env("RUST_TARGET_PATH", path);
+        }
+    }
+
+    if env::var_os("CARGO_NET_RETRY").is_none() {
+        cmd.env("CARGO_NET_RETRY", net_retry()?.to_string());
+    }
+
+    if let Some(rustc) = rustc::sysroot_rustc() {
+        cmd.env("RUSTC", rustc);
+    }
+
+    cmd.arg("fetch");
+    cmd.arg("--manifest-path");
+    cmd.arg(td.join("Cargo.toml"));
+    cmd.args(["--target", &cmode.target_arg()]);
+    for config in args.configs() {
+        cmd.args(["--config", config]);
+    }
+    if let Some(flag) = args.cargo_verbosity().cargo_flag() {
+        cmd.arg(flag);
+    }
+
+    Ok(cmd)
+}
+
+/// Downloads the dependencies of the sysroot project at `td`
+///
+/// Cargo already retries failed downloads (`net.retry`), but when it gives up
+/// on a network failure the whole fetch is retried, up to `XARGO_NET_RETRY`
+/// times with a doubling delay. Other failures are reported right away.
+fn fetch(cmode: &CompilationMode, td: &Path, args: &Args) -> Result<()> {
+    let retries = net_retry()?;
+
+    let mut attempt = 0;
+    loop {
+        let mut cmd = fetch_command(cmode, td, args)?;
+        let out = cmd.run_and_get_output(args.verbosity())?;
+
+        if out.status.success() {
+            return Ok(());
+        }
+
+        let stderr = String::from_utf8_lossy(&out.stderr).to_lowercase();
+        if attempt >= retries || !NETWORK_ERRORS.iter().any(|e| stderr.contains(e)) {
+            Err(format!(
+                "`{:?}` failed with exit code: {:?}",
+                cmd,
+                out.status.code()
+            ))?
+        }
+
+        attempt += 1;
+        let delay = 1u64
+            .checked_shl(attempt - 1)
+            .unwrap_or(u64::MAX)
+            .min(MAX_NET_RETRY_DELAY);
+        writeln!(
+            io::stderr(),
+            "warning: couldn't download the sysroot dependencies, retrying in {}s ({}/{})",
+            delay,
+            attempt,
+            retries
+        )
+        .ok();
+        thread::sleep(Duration::from_secs(delay));
+    }
+}
+
+/// Whether the sysroot must be reproducible, i.e. `--reproducible` or
+/// `XARGO_REPRODUCIBLE`
+///
+/// Its crates are then built with a single codegen unit, unless
+/// `build.codegen-units` says otherwise, and never incrementally.
+fn reproducible() -> bool {
+    env::var_os("XARGO_REPRODUCIBLE").is_some_and(|v| !v.is_empty())
+}
+
+/// Returns the profile used to build the sysroot for `target`
+///
+/// This is the `[profile.$name]` of `Cargo.toml`, `release` unless
+/// `build.per-profile` is set, overridden by `Xargo.toml`'s `[build.profile]`,
+/// its `build.codegen-units` (1 for a reproducible sysroot) and then by its
+/// `[target.{}.profile]`.
+fn sysroot_profile(
+    ctoml: Option<&cargo::Toml>,
+    xtoml: Option<&xargo::Toml>,
+    target: &str,
+    name: &str,
+) -> Result<Option<Profile>> {
+    let mut profile = ctoml
+        .and_then(|t| t.profile(name))
+        .unwrap_or_else(|| Profile::new(name, Table::new()));
+
+    if let Some(value) = xtoml.and_then(xargo::Toml::profile) {
+        profile.merge(
+            value
+                .as_table()
+                .ok_or("Xargo.toml: `build.profile` must be a table")?,
+        );
+    }
+
+    let mut table = Table::new();
+    match xtoml.and_then(xargo::Toml::codegen_units) {
+        Some(value) => {
+            let n = value
+                .as_integer()
+                .filter(|&n| n > 0)
+                .ok_or("Xargo.toml: `build.codegen-units` must be a positive integer")?;
+            table.insert("codegen-units".to_owned(), Value::Integer(n));
+        }
+        None if reproducible() => {
+            table.insert("codegen-units".to_owned(), Value::Integer(1));
+        }
+        None => {}
+    }
+    if reproducible() {
+        table.insert("incremental".to_owned(), Value::Boolean(false));
+    }
+    profile.merge(&table);
+
+    if let Some(value) = xtoml.and_then(|t| t.target_profile(target)) {
+        profile.merge(
+            value.as_table().ok_or_else(|| {
+                format!("Xargo.toml: `target.{}.profile` must be a table", target)
+            })?,
+        );
+    }
+
+    match profile.get("embed-bitcode") {
+        Some(value) if !value.is_bool() => Err("Xargo.toml: `embed-bitcode` must be a boolean")?,
+        _ => {}
+    }
 
-            cmd
+    // Without settings, the profile still says how Cargo is invoked, unless
+    // it's `release`
+    Ok(if profile.is_empty() && name == "release" {
+        None
+    } else {
+        Some(profile)
+    })
+}
+
+/// The flags of the sysroot build: `rustflags`, followed by `build.rustflags`
+/// of `Xargo.toml`, `-D warnings`, the unstable flags and the
+/// `target.<triple>.linker`, which only apply to the sysroot
+fn sysroot_rustflags(
+    rustflags: &Rustflags,
+    xtoml: Option<&xargo::Toml>,
+    triple: &str,
+    base_path: &Path,
+    meta: &VersionMeta,
+) -> Result<Rustflags> {
+    let mut flags = rustflags.clone();
+
+    if let Some(value) = xtoml.and_then(xargo::Toml::rustflags) {
+        let extra = value
+            .as_array()
+            .and_then(|a| a.iter().map(Value::as_str).collect::<Option<Vec<_>>>())
+            .ok_or("Xargo.toml: `build.rustflags` must be an array of strings")?;
+        flags.push(&extra);
+    }
+
+    if deny_warnings(xtoml)? {
+        flags.push(&["-D", "warnings"]);
+    }
+
+    let mut unstable = unstable_flags(xtoml)?;
+    let sanitizer = sanitizer(xtoml)?;
+    if let Some(sanitizer) = sanitizer {
+        unstable.push(format!("sanitizer={}", sanitizer));
+    }
+    if !unstable.is_empty() {
+        // rustc rejects `-Z` flags outside of the nightly (and dev) channel
+        let channel = match rustc::sysroot_version()? {
+            Some(meta) => meta.channel,
+            None => meta.channel,
         };
+        if channel == Channel::Stable || channel == Channel::Beta {
+            bail!(
+                "the unstable flags of the sysroot build (`-Z {}`) need a nightly \
+                 rustc, but rustc is on the {:?} channel",
+                unstable.join(" -Z "),
+                channel
+            )
+        }
 
-        for krate in stage.crates {
-            cargo().arg("-p").arg(krate).run(verbose)?;
+        for flag in &unstable {
+            flags.push(&["-Z", flag]);
         }
+    }
 
-        // Copy artifacts to Xargo sysroot
-        util::cp_r(
-            &td.join("target")
-                .join(cmode.triple())
-                .join(profile())
-                .join("deps"),
-            &dst,
-        )?;
+    // The stack traces of these sanitizers go by the frame pointers
+    if let Some("address" | "hwaddress" | "kernel-address" | "memory" | "thread") = sanitizer {
+        flags.push(&["-C", "force-frame-pointers=yes"]);
+    }
+
+    if let Some(value) = xtoml.and_then(|t| t.target_linker(triple)) {
+        let linker = value
+            .as_str()
+            .ok_or_else(|| format!("Xargo.toml: `target.{}.linker` must be a string", triple))?;
+        let linker = if xargo::linker_is_path(linker) {
+            base_path.join(linker).display().to_string()
+        } else {
+            linker.to_owned()
+        };
+
+        flags.push(&["-C", &format!("linker={}", linker)]);
     }
 
-    // Create hash file
-    util::write(&rustlib.parent().join(".hash"), &hash.to_string())?;
+    Ok(flags)
+}
+
+/// Whether the sysroot is built with `-D warnings`: `build.deny-warnings` of
+/// `Xargo.toml`, or `XARGO_DENY_SYSROOT_WARNINGS`, which
+/// `--deny-sysroot-warnings` sets
+fn deny_warnings(xtoml: Option<&xargo::Toml>) -> Result<bool> {
+    if env::var_os("XARGO_DENY_SYSROOT_WARNINGS").is_some_and(|v| !v.is_empty()) {
+        return Ok(true);
+    }
 
-    Ok(())
+    match xtoml.and_then(xargo::Toml::deny_warnings) {
+        Some(value) => value
+            .as_bool()
+            .ok_or_else(|| "Xargo.toml: `build.deny-warnings` must be a boolean".into()),
+        None => Ok(false),
+    }
+}
+
+/// The sanitizers `build.sanitizer` may name, as `-Z sanitizer` takes them
+const SANITIZERS: &[&str] = &[
+    "address",
+    "cfi",
+    "dataflow",
+    "hwaddress",
+    "kcfi",
+    "kernel-address",
+    "leak",
+    "memory",
+    "memtag",
+    "safestack",
+    "shadow-call-stack",
+    "thread",
+];
+
+/// The sanitizer the sysroot crates are instrumented with: `XARGO_SANITIZER`,
+/// or else `build.sanitizer` of `Xargo.toml`
+fn sanitizer(xtoml: Option<&xargo::Toml>) -> Result<Option<&'static str>> {
+    let var = env::var("XARGO_SANITIZER").ok().filter(|s| !s.is_empty());
+    let name = match var {
+        Some(ref var) => var.as_str(),
+        None => match xtoml.and_then(xargo::Toml::sanitizer) {
+            Some(value) => value
+                .as_str()
+                .ok_or("Xargo.toml: `build.sanitizer` must be a string")?,
+            None => return Ok(None),
+        },
+    };
+
+    match SANITIZERS.iter().find(|&&s| s == name) {
+        Some(&sanitizer) => Ok(Some(sanitizer)),
+        None => bail!(
+            "unknown sanitizer `{}`; valid sanitizers are: {}",
+            name,
+            SANITIZERS.join(", ")
+        ),
+    }
+}
+
+/// The number of jobs Cargo builds the sysroot with: `XARGO_SYSROOT_JOBS`,
+/// or else `build.jobs` of `Xargo.toml`
+///
+/// Without either, the sysroot's Cargo picks its number of jobs as usual,
+/// regardless of the `-j` of the application's build. Neither is part of the
+/// hash.
+fn sysroot_jobs(xtoml: Option<&xargo::Toml>) -> Result<Option<u32>> {
+    let jobs = match env::var("XARGO_SYSROOT_JOBS") {
+        Ok(ref jobs) if !jobs.is_empty() => jobs
+            .trim()
+            .parse()
+            .ok()
+            .filter(|&jobs| jobs > 0)
+            .ok_or_else(|| {
+                format!(
+                    "XARGO_SYSROOT_JOBS must be a positive number, but it's `{}`",
+                    jobs
+                )
+            })?,
+        _ => match xtoml.and_then(xargo::Toml::jobs) {
+            Some(value) => value
+                .as_integer()
+                .filter(|&jobs| jobs > 0 && jobs <= i64::from(u32::MAX))
+                .map(|jobs| jobs as u32)
+                .ok_or("Xargo.toml: `build.jobs` must be a positive integer")?,
+            None => return Ok(None),
+        },
+    };
+
+    Ok(Some(jobs))
+}
+
+/// The `-Z` flags of the sysroot build: `build.unstable-flags` of
+/// `Xargo.toml`, followed by the ones in `XARGO_RUSTC_UNSTABLE`
+///
+/// The flags are given without `-Z`, e.g. `macro-backtrace`, though a leading
+/// `-Z` is accepted too.
+fn unstable_flags(xtoml: Option<&xargo::Toml>) -> Result<Vec<String>> {
+    let mut flags = vec![];
+
+    if let Some(value) = xtoml.and_then(xargo::Toml::unstable_flags) {
+        let listed = value
+            .as_array()
+            .and_then(|a| a.iter().map(Value::as_str).collect::<Option<Vec<_>>>())
+            .ok_or("Xargo.toml: `build.unstable-flags` must be an array of strings")?;
+        flags.extend(listed.into_iter().map(String::from));
+    }
+
+    if let Ok(var) = env::var("XARGO_RUSTC_UNSTABLE") {
+        flags.extend(var.split_whitespace().map(String::from));
+    }
+
+    Ok(flags
+        .into_iter()
+        .map(|f| f.strip_prefix("-Z").map(String::from).unwrap_or(f))
+        .filter(|f| !f.is_empty())
+        .collect())
 }
 
 fn old_hash(cmode: &CompilationMode, home: &Home) -> Result<Option<u64>> {
     // FIXME this should be `lock_ro`
     let lock = home.lock_rw(cmode.triple())?;
-    let hfile = lock.parent().join(".hash");
 
-    if hfile.exists() {
-        Ok(util::read(&hfile)?.parse().ok())
+    Ok(recorded_hash(lock.parent())?.and_then(|h| h.parse().ok()))
+}
+
+/// The inputs of a sysroot hash
+///
+/// Every input is recorded as it's fed into the hash, so `--print-hash -v` can
+/// show exactly what the hash is made of.
+pub struct HashInputs {
+    hasher: DefaultHasher,
+    inputs: Vec<(String, String, u64)>,
+}
+
+impl HashInputs {
+    fn new() -> Self {
+        HashInputs {
+            hasher: DefaultHasher::new(),
+            inputs: vec![],
+        }
+    }
+
+    /// Feeds `value` into the hash, as the input `name` shown as `shown`
+    ///
+    /// `shown` is put on a single line, as TOML tables span several lines.
+    pub fn add<T, D>(&mut self, name: &str, value: &T, shown: D)
+    where
+        T: Hash + ?Sized,
+        D: fmt::Display,
+    {
+        value.hash(&mut self.hasher);
+
+        let mut hasher = DefaultHasher::new();
+        value.hash(&mut hasher);
+        let shown = shown.to_string().trim().replace('\n', ", ");
+        self.inputs.push((name.to_owned(), shown, hasher.finish()));
+    }
+
+    /// The hash of all the inputs
+    pub fn finish(&self) -> u64 {
+        self.hasher.finish()
+    }
+
+    /// The inputs, in order: their name, how they are shown and their own
+    /// hash
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str, u64)> {
+        self.inputs.iter().map(|&(ref n, ref s, h)| (&**n, &**s, h))
+    }
+
+    /// The inputs as a JSON array, to be read back by `explain`
+    fn to_json(&self) -> String {
+        let inputs = self
+            .iter()
+            .map(|(name, shown, hash)| {
+                let mut input = serde_json::Map::new();
+                input.insert("name".to_owned(), name.into());
+                input.insert("shown".to_owned(), shown.into());
+                input.insert("hash".to_owned(), format!("{:016x}", hash).into());
+                JsonValue::Object(input)
+            })
+            .collect();
+
+        JsonValue::Array(inputs).to_string()
+    }
+
+    /// Tells which of these inputs differ from the `recorded` ones, as
+    /// written by `to_json`, one line each
+    fn explain(&self, recorded: &str) -> Result<Vec<String>> {
+        let json: JsonValue = serde_json::from_str(recorded)
+            .chain_err(|| "the recorded inputs are not valid JSON")?;
+        let mut old = json
+            .as_array()
+            .map(|inputs| {
+                inputs
+                    .iter()
+                    .filter_map(|input| {
+                        let field = |key| input.get(key).and_then(JsonValue::as_str);
+                        Some((field("name")?, (field("shown")?, field("hash")?)))
+                    })
+                    .collect::<BTreeMap<_, _>>()
+            })
+            .unwrap_or_default();
+
+        let mut changes = vec![];
+        for (name, shown, hash) in self.iter() {
+            match old.remove(name) {
+                Some((_, old_hash)) if old_hash == format!("{:016x}", hash) => {}
+                Some((old_shown, _)) => {
+                    changes.push(format!("{} changed: {} -> {}", name, old_shown, shown))
+                }
+                None => changes.push(format!("{} is new: {}", name, shown)),
+            }
+        }
+        for (name, (shown, _)) in old {
+            changes.push(format!("{} is gone: {}", name, shown));
+        }
+
+        Ok(changes)
+    }
+}
+
+/// Prints why the sysroot of `cmode` is about to be rebuilt, comparing
+/// `inputs` to the ones recorded by its last build
+fn explain_rebuild(
+    cmode: &CompilationMode,
+    home: &Home,
+    inputs: &HashInputs,
+    forced: bool,
+) -> Result<()> {
+    let rustlib = home.rustlib(cmode.triple());
+    let record = rustlib.join("inputs.json");
+    let mut changes = if record.is_file() {
+        inputs.explain(&util::read(&record)?)?
     } else {
-        Ok(None)
+        vec!["no previous build".to_owned()]
+    };
+    if forced {
+        changes.insert(0, "`--force-rebuild` was passed".to_owned());
+    } else if record.is_file() && !rustlib.join(COMPLETE).exists() {
+        changes.insert(0, "the previous build didn't complete".to_owned());
+    }
+
+    let stderr = io::stderr();
+    let mut stderr = stderr.lock();
+    writeln!(
+        stderr,
+        "xargo: rebuilding the sysroot of {}:",
+        cmode.triple()
+    )
+    .ok();
+    if changes.is_empty() {
+        // e.g. the order of the inputs changed
+        writeln!(stderr, "    the hash changed, but none of its inputs did").ok();
+    }
+    for change in changes {
+        writeln!(stderr, "    {}", change).ok();
     }
+
+    Ok(())
 }
 
 /// Computes the hash of the would-be target sysroot
@@ -245,50 +997,76 @@ fn old_hash(cmode: &CompilationMode, home: &Home) -> Result<Option<u64>> {
 /// This information is used to compute the hash
 ///
 /// - Dependencies in `Xargo.toml` for a specific target
-/// - RUSTFLAGS / build.rustflags / target.*.rustflags
+/// - The `[patch]` section of `Xargo.toml`, and the contents of the patched
+///   crates that are given by `path`
+/// - RUSTFLAGS / build.rustflags / target.*.rustflags, and `build.rustflags`
+///   and the unstable flags in `Xargo.toml` / `XARGO_RUSTC_UNSTABLE`
 /// - The target specification file, is any
-/// - `[profile.release]` in `Cargo.toml`
-/// - `rustc` commit hash
+/// - `rustc --print cfg` for the target
+/// - `[profile.release]` in `Cargo.toml`, or the profile of the crate with
+///   `build.per-profile`, with the overrides in `Xargo.toml`
+/// - `rustc` version, commit hash and commit date
+/// - The version of `XARGO_SYSROOT_RUSTC`, if set
+/// - Whether the sysroot is built or only checked
 fn hash(
     cmode: &CompilationMode,
     blueprint: &Blueprint,
     rustflags: &Rustflags,
-    ctoml: &Option<cargo::Toml>,
+    profile: Option<&Profile>,
     meta: &VersionMeta,
-) -> Result<u64> {
-    let mut hasher = DefaultHasher::new();
+    cargo_mode: XargoMode,
+) -> Result<HashInputs> {
+    let mut inputs = HashInputs::new();
 
-    blueprint.hash(&mut hasher);
+    // A check-only sysroot has no codegen so it must never be reused for a
+    // real build (e.g. `xargo check` after `xargo-check`)
+    inputs.add("mode", &cargo_mode, format!("{:?}", cargo_mode));
 
-    rustflags.hash(&mut hasher);
+    blueprint.hash(&mut inputs)?;
 
-    cmode.hash(&mut hasher)?;
+    rustflags.hash(&mut inputs);
 
-    if let Some(ctoml) = ctoml {
-        if let Some(profile) = ctoml.profile() {
-            profile.hash(&mut hasher);
+    cmode.hash(&mut inputs)?;
+
+    if let Some(profile) = profile {
+        // `release` isn't hashed, so sysroots built before
+        // `build.per-profile` stay up to date
+        if profile.name() != "release" {
+            inputs.add("cargo profile", &profile.name(), profile.name());
         }
+        profile.hash(&mut inputs);
     }
 
+    // Nightlies (and dev builds) share a version number, so also hash the
+    // commit information when it's available
+    inputs.add("rustc version", &meta.semver, &meta.semver);
+
     if let Some(ref hash) = meta.commit_hash {
-        hash.hash(&mut hasher);
+        inputs.add("rustc commit hash", hash, hash);
     }
 
-    Ok(hasher.finish())
+    if let Some(ref date) = meta.commit_date {
+        inputs.add("rustc commit date", date, date);
+    }
+
+    if let Some(meta) = rustc::sysroot_version()? {
+        let version = (&meta.semver, &meta.commit_hash, &meta.commit_date);
+        inputs.add("sysroot rustc", &version, &meta.short_version_string);
+    }
+
+    Ok(inputs)
 }
 
-pub fn update(
+/// Returns the blueprint, the profile and the rustc flags of the sysroot of
+/// `cmode`, along with the inputs of its hash
+fn plan(
     cmode: &CompilationMode,
-    home: &Home,
     root: &Root,
     rustflags: &Rustflags,
     meta: &VersionMeta,
     src: &Src,
-    sysroot: &Sysroot,
-    verbose: bool,
-    message_format: Option<&str>,
     cargo_mode: XargoMode,
-) -> Result<()> {
+) -> Result<(Blueprint, Option<Profile>, Rustflags, HashInputs)> {
     let ctoml = match cargo_mode {
         XargoMode::Build => Some(cargo::toml(root)?),
         XargoMode::Check => {
@@ -308,54 +1086,249 @@ pub fn update(
     // root path.
     let base_path: &Path = xtoml_parent.unwrap_or_else(|| root.path());
 
-    let blueprint = Blueprint::from(xtoml.as_ref(), cmode.triple(), &base_path, &src)?;
+    let profile = sysroot_profile(
+        ctoml.as_ref(),
+        xtoml.as_ref(),
+        cmode.triple(),
+        xargo::sysroot_profile(xtoml.as_ref(), root)?,
+    )?;
+    let panic_abort = profile.as_ref().and_then(Profile::panic) == Some("abort");
+
+    let blueprint = Blueprint::from(xtoml.as_ref(), cmode, &base_path, &src, panic_abort)?;
+    let rustflags = sysroot_rustflags(rustflags, xtoml.as_ref(), cmode.triple(), base_path, meta)?;
+
+    let inputs = hash(
+        cmode,
+        &blueprint,
+        &rustflags,
+        profile.as_ref(),
+        meta,
+        cargo_mode,
+    )?;
+
+    Ok((blueprint, profile, rustflags, inputs))
+}
+
+/// Returns the inputs of the hash of the sysroot of `cmode`
+pub fn hash_inputs(
+    cmode: &CompilationMode,
+    root: &Root,
+    rustflags: &Rustflags,
+    meta: &VersionMeta,
+    src: &Src,
+    cargo_mode: XargoMode,
+) -> Result<HashInputs> {
+    plan(cmode, root, rustflags, meta, src, cargo_mode).map(|(_, _, _, inputs)| inputs)
+}
+
+/// Returns the directories of `home` that `update` would use as they are: the
+/// sysroot of `cmode` and, when cross compiling, the copy of the host
+/// libraries
+pub fn up_to_date(
+    cmode: &CompilationMode,
+    home: &Home,
+    root: &Root,
+    rustflags: &Rustflags,
+    meta: &VersionMeta,
+    src: &Src,
+    cargo_mode: XargoMode,
+) -> Result<Vec<PathBuf>> {
+    let hash = hash_inputs(cmode, root, rustflags, meta, src, cargo_mode)?.finish();
+    let mut dirs = vec![];
+
+    // Unlike `old_hash`, this doesn't create the directory if it's missing
+    let rustlib = home.rustlib(cmode.triple());
+    if recorded_hash(&rustlib)?.and_then(|h| h.parse().ok()) == Some(hash) {
+        dirs.push(rustlib);
+    }
+
+    if !cmode.is_native() {
+        let rustlib = home.rustlib(&meta.host);
+        let hash = meta.commit_hash.as_deref().unwrap_or("");
+        if recorded_hash(&rustlib)?.as_deref() == Some(hash) {
+            dirs.push(rustlib);
+        }
+    }
+
+    Ok(dirs)
+}
+
+pub fn update(
+    cmode: &CompilationMode,
+    home: &Home,
+    root: &Root,
+    rustflags: &Rustflags,
+    meta: &VersionMeta,
+    src: &Src,
+    sysroot: &Sysroot,
+    verbosity: Verbosity,
+    args: &Args,
+    cargo_mode: XargoMode,
+) -> Result<()> {
+    let (blueprint, profile, flags, inputs) = plan(cmode, root, rustflags, meta, src, cargo_mode)?;
+    let hash = inputs.finish();
+
+    if args.dry_run() {
+        let fresh = up_to_date(cmode, home, root, rustflags, meta, src, cargo_mode)?;
+        let stdout = io::stdout();
+        let mut stdout = stdout.lock();
+
+        let target = match *cmode {
+            CompilationMode::Native(ref triple) => format!("{} (native)", triple),
+            CompilationMode::Cross(Target::Builtin { ref triple }) => {
+                format!("{} (builtin)", triple)
+            }
+            CompilationMode::Cross(Target::Custom {
+                ref triple,
+                ref json,
+                ..
+            }) => format!("{} (custom, {})", triple, json.display()),
+        };
+        writeln!(stdout, "target: {}", target).ok();
+
+        let rustlib = home.rustlib(cmode.triple());
+        let status = if fresh.contains(&rustlib) && !args.force_rebuild() {
+            "up to date"
+        } else {
+            "would be built"
+        };
+        writeln!(stdout, "sysroot: {} ({})", rustlib.display(), status).ok();
+        writeln!(stdout, "hash: {:016x}", hash).ok();
+
+        // The sysroot project lives in a temporary directory
+        let td = env::temp_dir().join("xargo.XXXXXX");
+        for (n, stage) in blueprint.stages.iter() {
+            writeln!(stdout, "stage {}: {}", n, stage.crates.join(", ")).ok();
+
+            if !args.offline() {
+                let cmd = fetch_command(cmode, &td, args)?;
+                writeln!(stdout, "+ {}", cmd.shown(args.verbosity())).ok();
+            }
+
+            for krate in &stage.crates {
+                let mut cmd =
+                    cargo_command(cmode, profile.as_ref(), home, &flags, &td, args, cargo_mode)?;
+                if let Some(jobs) = blueprint.jobs {
+                    cmd.args(["-j", &jobs.to_string()]);
+                }
+                cmd.arg("-p").arg(krate);
+                writeln!(stdout, "+ {}", cmd.shown(args.verbosity())).ok();
+            }
+        }
+
+        if !cmode.is_native() {
+            let rustlib = home.rustlib(&meta.host);
+            let status = if fresh.contains(&rustlib) && !args.force_rebuild() {
+                "up to date".to_owned()
+            } else {
+                format!(
+                    "would be copied from {}",
+                    sysroot.rustlib_dir(&meta.host).display()
+                )
+            };
+            writeln!(stdout, "host libraries: {} ({})", rustlib.display(), status).ok();
+        }
+
+        return Ok(());
+    }
+
+    let rebuilt = args.force_rebuild() || old_hash(cmode, home)? != Some(hash);
+    if rebuilt {
+        // The build directory of `XARGO_INCREMENTAL` may be what's broken
+        let build_dir = home.build_dir(cmode.triple());
+        if args.force_rebuild() && build_dir.exists() {
+            fs::remove_dir_all(&build_dir)
+                .chain_err(|| format!("couldn't remove {}", build_dir.display()))?;
+        }
 
-    let hash = hash(cmode, &blueprint, rustflags, &ctoml, meta)?;
+        if args.explain_rebuild() {
+            explain_rebuild(cmode, home, &inputs, args.force_rebuild())?;
+        }
 
-    if old_hash(cmode, home)? != Some(hash) {
-        build(
+        let built = build(
             cmode,
             blueprint,
-            &ctoml,
+            profile.as_ref(),
             home,
-            rustflags,
+            &flags,
+            meta,
             src,
             sysroot,
-            hash,
-            verbose,
-            message_format,
+            &inputs,
+            Lockfile::open(root.path().join("Xargo.lock"))?,
+            verbosity,
+            args,
             cargo_mode,
-        )?;
+        );
+        disk_full(built, home)?;
+    } else {
+        let lock = home.lock_ro(cmode.triple())?;
+        stamp_last_used(lock.parent())?;
     }
 
     // copy host artifacts into the sysroot, if necessary
-    if cmode.is_native() {
-        return Ok(());
+    if !cmode.is_native() {
+        disk_full(copy_host(home, meta, sysroot, args.force_rebuild()), home)?;
     }
 
+    if rebuilt || args.always_run_hook() {
+        post_sysroot_hook(cmode, home, root, args)?;
+    }
+
+    Ok(())
+}
+
+/// Runs the `build.post-sysroot-hook` of `Xargo.toml`, if any, on the sysroot
+/// of `cmode`
+///
+/// The hook gets the sysroot and the triple as its arguments, and as
+/// `XARGO_SYSROOT` and `XARGO_TARGET`. If it fails, the sysroot is rebuilt
+/// next time, so it's never used without the hook having run on it.
+fn post_sysroot_hook(cmode: &CompilationMode, home: &Home, root: &Root, args: &Args) -> Result<()> {
+    let (xtoml_parent, xtoml) = xargo::toml(root)?;
+    let hook = match xtoml.as_ref().and_then(xargo::Toml::post_sysroot_hook) {
+        Some(hook) => hook
+            .as_str()
+            .ok_or("Xargo.toml: `build.post-sysroot-hook` must be a string")?,
+        None => return Ok(()),
+    };
+    let hook = if xargo::linker_is_path(hook) {
+        xtoml_parent.unwrap_or_else(|| root.path()).join(hook)
+    } else {
+        PathBuf::from(hook)
+    };
+
+    let sysroot = home.sysroot();
+    let ran = Command::new(&hook)
+        .arg(sysroot.path())
+        .arg(cmode.triple())
+        .env("XARGO_SYSROOT", sysroot.path())
+        .env("XARGO_TARGET", cmode.triple())
+        .run(args.verbosity());
+
+    if ran.is_err() {
+        fs::remove_file(home.rustlib(cmode.triple()).join(".hash")).ok();
+    }
+
+    ran.chain_err(|| format!("the post-sysroot hook {} failed", hook.display()))
+}
+
+/// Copies the host libraries of `sysroot`, and its linker, to `home`, unless
+/// they are already there
+fn copy_host(home: &Home, meta: &VersionMeta, sysroot: &Sysroot, force: bool) -> Result<()> {
     let lock = home.lock_rw(&meta.host)?;
     let hfile = lock.parent().join(".hash");
 
     let hash = meta.commit_hash.as_ref().map(|s| &**s).unwrap_or("");
-    if hfile.exists() {
-        if util::read(&hfile)? == hash {
-            return Ok(());
-        }
+    if !force && recorded_hash(lock.parent())?.as_deref() == Some(hash) {
+        return Ok(());
     }
 
     lock.remove_siblings()
         .chain_err(|| format!("couldn't clear {}", lock.path().display()))?;
     let dst = lock.parent().join("lib");
     util::mkdir(&dst)?;
-    util::cp_r(
-        &sysroot
-            .path()
-            .join("lib")
-            .join("rustlib")
-            .join(&meta.host)
-            .join("lib"),
-        &dst,
-    )?;
+    util::cp_r(&sysroot.rustlib_dir(&meta.host), &dst)?;
 
     let bin_src = sysroot
         .path()
@@ -371,10 +1344,139 @@ pub fn update(
     }
 
     util::write(&hfile, hash)?;
+    util::write(&lock.parent().join(COMPLETE), "")?;
 
     Ok(())
 }
 
+/// Explains `result` when it failed because the disk is full
+///
+/// The IO error alone only names the file that couldn't be written, not
+/// where the space went.
+fn disk_full<T>(result: Result<T>, home: &Home) -> Result<T> {
+    let e = match result {
+        Ok(t) => return Ok(t),
+        Err(e) => e,
+    };
+
+    let mut cause = ::std::error::Error::source(&e);
+    while let Some(c) = cause {
+        if c.downcast_ref::<io::Error>().map(is_disk_full) == Some(true) {
+            return Err(e).chain_err(|| disk_full_message(home));
+        }
+
+        cause = c.source();
+    }
+
+    Err(e)
+}
+
+/// The error of a sysroot build that ran out of disk space
+fn disk_full_message(home: &Home) -> String {
+    format!(
+        "ran out of disk space while building the sysroot in {}. \
+         `xargo clean --sysroot` removes the sysroots that aren't in use.",
+        home.display()
+    )
+}
+
+/// Less free space than this, in bytes, on the file system of a sysroot
+/// build that failed means it ran out of disk space
+const LOW_SPACE: u64 = 64 * 1024 * 1024;
+
+/// Whether the file system of `path` is (nearly) out of space
+fn low_on_space(path: &Path) -> bool {
+    fs2::available_space(path)
+        .map(|space| space < LOW_SPACE)
+        .unwrap_or(false)
+}
+
+/// Whether `e` means that there's no space left on the device
+fn is_disk_full(e: &io::Error) -> bool {
+    if e.kind() == io::ErrorKind::WriteZero {
+        return true;
+    }
+
+    match e.raw_os_error() {
+        // ERROR_HANDLE_DISK_FULL and ERROR_DISK_FULL
+        Some(code) if cfg!(windows) => code == 39 || code == 112,
+        // ENOSPC
+        Some(code) => code == 28,
+        None => false,
+    }
+}
+
+/// Crates that can be added to, or left out of, the sysroot with
+/// `<crate> = true / false` in `Xargo.toml`
+const OPTIONAL_CRATES: &[&str] = &["proc_macro", "test"];
+
+/// The sysroot crates set with `XARGO_SYSROOT_CRATES`, or `build.crates` in
+/// `Xargo.toml`, in the order they were given
+fn crate_list(toml: Option<&xargo::Toml>) -> Result<Option<Vec<String>>> {
+    if let Ok(list) = env::var("XARGO_SYSROOT_CRATES") {
+        let crates = list
+            .split(',')
+            .map(str::trim)
+            .filter(|c| !c.is_empty())
+            .map(String::from)
+            .collect::<Vec<_>>();
+
+        if !crates.is_empty() {
+            return Ok(Some(crates));
+        }
+    }
+
+    match toml.and_then(xargo::Toml::crates) {
+        Some(value) => {
+            let crates = value
+                .as_array()
+                .and_then(|a| {
+                    a.iter()
+                        .map(|c| c.as_str().map(String::from))
+                        .collect::<Option<Vec<_>>>()
+                })
+                .ok_or("Xargo.toml: `build.crates` must be an array of strings")?;
+
+            if crates.is_empty() {
+                Err("Xargo.toml: `build.crates` must list at least one crate")?
+            }
+
+            Ok(Some(crates))
+        }
+        None => Ok(None),
+    }
+}
+
+/// The sysroot built when `Xargo.toml` doesn't list any dependency: `core` and
+/// `compiler_builtins`
+fn default_dependencies() -> Table {
+    let mut t = Map::new();
+    let mut core = Map::new();
+    core.insert("stage".to_owned(), Value::Integer(0));
+    t.insert("core".to_owned(), Value::Table(core));
+    let mut cb = Map::new();
+    cb.insert(
+        "features".to_owned(),
+        Value::Array(vec![Value::String("mem".to_owned())]),
+    );
+    // reference compiler-builtins with `version = "*"`,
+    // the corresponding version of compiler_builtins matching the used std
+    // is selected because of the copied `Cargo.lock`-file from std.
+    cb.insert("version".to_owned(), Value::String("*".to_owned()));
+    cb.insert("stage".to_owned(), Value::Integer(1));
+    t.insert("compiler_builtins".to_owned(), Value::Table(cb));
+    t
+}
+
+/// `default_dependencies`, and `alloc`, for WebAssembly targets
+fn wasm_dependencies() -> Table {
+    let mut t = default_dependencies();
+    let mut alloc = Map::new();
+    alloc.insert("stage".to_owned(), Value::Integer(0));
+    t.insert("alloc".to_owned(), Value::Table(alloc));
+    t
+}
+
 /// Per stage dependencies
 #[derive(Debug)]
 pub struct Stage {
@@ -385,7 +1487,7 @@ pub struct Stage {
 
 /// Which mode to invoke `cargo` in when building the sysroot
 /// Can be either `cargo build` or `cargo check`
-#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[derive(Copy, Clone, Debug, Eq, Hash, PartialEq)]
 pub enum XargoMode {
     Build,
     Check,
@@ -395,6 +1497,10 @@ pub enum XargoMode {
 #[derive(Debug)]
 pub struct Blueprint {
     stages: BTreeMap<i64, Stage>,
+    /// The `[patch]` section of `Xargo.toml`, with absolute paths
+    patch: Table,
+    /// The `-j` of the Cargo that builds the stages, see `sysroot_jobs`
+    jobs: Option<u32>,
 }
 
 trait AsTableMut {
@@ -425,6 +1531,8 @@ impl Blueprint {
     fn new() -> Self {
         Blueprint {
             stages: BTreeMap::new(),
+            patch: Table::new(),
+            jobs: None,
         }
     }
 
@@ -450,7 +1558,262 @@ impl Blueprint {
         Ok(())
     }
 
-    fn from(toml: Option<&xargo::Toml>, target: &str, base_path: &Path, src: &Src) -> Result<Self> {
+    /// Drops `panic_unwind` from the default features of the `std` crate at
+    /// `path`, as `krate` doesn't need it with `panic = "abort"`
+    ///
+    /// The other default features are kept. Nothing changes if `krate` picks
+    /// the default features itself.
+    fn without_panic_unwind(krate: &mut Table, path: &str) -> Result<()> {
+        if krate.contains_key("default-features") {
+            return Ok(());
+        }
+
+        let ctoml = util::parse(&Path::new(path).join("Cargo.toml"))?;
+        let defaults = ctoml
+            .get("features")
+            .and_then(|f| f.get("default"))
+            .and_then(Value::as_array)
+            .cloned()
+            .unwrap_or_default();
+        let is_unwind =
+            |f: &Value| f.as_str() == Some("panic_unwind") || f.as_str() == Some("panic-unwind");
+
+        if !defaults.iter().any(&is_unwind) {
+            return Ok(());
+        }
+
+        let features = krate
+            .entry("features")
+            .or_insert_with(|| Value::Array(vec![]));
+        if let Value::Array(ref mut features) = *features {
+            features.extend(defaults.into_iter().filter(|f| !is_unwind(f)));
+        } else {
+            Err("Xargo.toml: `dependencies.std.features` must be an array")?
+        }
+        krate.insert("default-features".to_owned(), Value::Boolean(false));
+
+        Ok(())
+    }
+
+    /// Whether the dependency `spec` only picks features, i.e. only has
+    /// `features` and `default-features`
+    fn is_features_only(spec: &Value) -> bool {
+        spec.as_table()
+            .map(|t| t.keys().all(|k| k == "features" || k == "default-features"))
+            .unwrap_or(false)
+    }
+
+    /// Replaces a `*` in the `features` of `krate` with every feature the
+    /// `std` crate at `path` declares, but `default`
+    ///
+    /// The features are spelled out, rather than hashed as `*`, so that `std`
+    /// gaining a feature rebuilds the sysroot.
+    fn expand_std_features(krate: &mut Table, path: &str) -> Result<()> {
+        let features = match krate.get_mut("features") {
+            Some(Value::Array(features)) if features.iter().any(|f| f.as_str() == Some("*")) => {
+                features
+            }
+            _ => return Ok(()),
+        };
+
+        let ctoml = util::parse(&Path::new(path).join("Cargo.toml"))?;
+        let all = ctoml
+            .get("features")
+            .and_then(Value::as_table)
+            .map(|f| f.keys().filter(|k| *k != "default").cloned().collect())
+            .unwrap_or_else(Vec::new);
+
+        features.retain(|f| f.as_str() != Some("*"));
+        for feature in all.into_iter().map(Value::String) {
+            if !features.contains(&feature) {
+                features.push(feature);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Checks that the `std` crate at `path` has the `features` of `krate`
+    ///
+    /// Optional dependencies count as features, like for Cargo, and so do
+    /// the ones of other crates (`crate/feature`), which aren't checked.
+    fn check_std_features(krate: &Table, path: &str) -> Result<()> {
+        let features = match krate.get("features") {
+            Some(Value::Array(features)) => features,
+            Some(_) => Err("Xargo.toml: `dependencies.std.features` must be an array")?,
+            None => return Ok(()),
+        };
+
+        let ctoml = util::parse(&Path::new(path).join("Cargo.toml"))?;
+        let mut valid = ctoml
+            .get("features")
+            .and_then(Value::as_table)
+            .map(|f| f.keys().cloned().collect::<Vec<_>>())
+            .unwrap_or_default();
+        if let Some(deps) = ctoml.get("dependencies").and_then(Value::as_table) {
+            valid.extend(
+                deps.iter()
+                    .filter(|(_, v)| v.get("optional").and_then(Value::as_bool) == Some(true))
+                    .map(|(k, _)| k.clone()),
+            );
+        }
+        valid.sort();
+
+        for feature in features {
+            let feature = feature
+                .as_str()
+                .ok_or("Xargo.toml: `dependencies.std.features` must be an array of strings")?;
+
+            if !feature.contains('/') && !valid.iter().any(|v| v == feature) {
+                Err(format!(
+                    "unknown `std` feature `{}`; valid features are: {}",
+                    feature,
+                    valid.join(", ")
+                ))?
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Picks `crates`, in that order, out of `deps`
+    ///
+    /// A crate `deps` doesn't mention keeps its default settings, if it has
+    /// any, and is otherwise looked up in the Rust source like any other.
+    fn select(mut deps: Table, crates: &[String], src: &Src) -> Result<Vec<(String, Value)>> {
+        let mut valid = deps.keys().cloned().collect::<Vec<_>>();
+        valid.push("compiler_builtins".to_owned());
+        if let Ok(entries) = fs::read_dir(src.path()) {
+            for entry in entries.filter_map(|e| e.ok()) {
+                if !entry.path().join("Cargo.toml").is_file() {
+                    continue;
+                }
+
+                if let Some(name) = entry.file_name().to_str() {
+                    // Older sysroots call them "libstd" (etc)
+                    valid.push(name.strip_prefix("lib").unwrap_or(name).to_owned());
+                }
+            }
+        }
+        valid.sort();
+        valid.dedup();
+
+        let mut defaults = default_dependencies();
+        let mut selected = vec![];
+        for krate in crates {
+            if !valid.contains(krate) {
+                Err(format!(
+                    "unknown sysroot crate `{}`; valid crates are: {}",
+                    krate,
+                    valid.join(", ")
+                ))?
+            }
+
+            if selected.iter().any(|(k, _)| k == krate) {
+                Err(format!("sysroot crate `{}` is listed twice", krate))?
+            }
+
+            let spec = deps
+                .remove(krate)
+                .or_else(|| defaults.remove(krate))
+                .unwrap_or_else(|| Value::Table(Table::new()));
+            selected.push((krate.clone(), spec));
+        }
+
+        Ok(selected)
+    }
+
+    /// Leaves out the crates of `deps` that have `required-features` the
+    /// sysroot crates don't enable
+    ///
+    /// A required feature is either `krate/feature`, for a feature of that
+    /// sysroot crate, or just `feature`, for a feature of any of them. Only
+    /// the `features` given in `Xargo.toml` count. A crate from the list of
+    /// `build.crates` (`listed`) can't be left out.
+    fn gate(mut deps: Vec<(String, Value)>, listed: bool) -> Result<Vec<(String, Value)>> {
+        let strings = |v: &Value| -> Option<Vec<String>> {
+            v.as_array()?
+                .iter()
+                .map(|s| s.as_str().map(String::from))
+                .collect()
+        };
+
+        let mut required = BTreeMap::new();
+        for (k, v) in &deps {
+            if let Some(value) = v.get("required-features") {
+                let features = strings(value).ok_or_else(|| {
+                    format!(
+                        "dependencies.{}.required-features must be an array of strings",
+                        k
+                    )
+                })?;
+                required.insert(k.clone(), features);
+            }
+        }
+
+        loop {
+            // The features enabled by the crates that are still in, as
+            // (crate, feature)
+            let mut enabled = vec![];
+            for (k, v) in &deps {
+                for feature in v.get("features").and_then(&strings).unwrap_or_default() {
+                    match feature.find('/') {
+                        Some(i) => {
+                            enabled.push((feature[..i].to_owned(), feature[i + 1..].to_owned()))
+                        }
+                        None => enabled.push((k.clone(), feature)),
+                    }
+                }
+            }
+            let is_enabled = |feature: &str| match feature.find('/') {
+                Some(i) => enabled
+                    .iter()
+                    .any(|(k, f)| *k == feature[..i] && *f == feature[i + 1..]),
+                None => enabled.iter().any(|(_, f)| f == feature),
+            };
+
+            let missing = deps.iter().find_map(|(k, _)| {
+                let features = required.get(k)?;
+                let missing = features
+                    .iter()
+                    .filter(|f| !is_enabled(f))
+                    .map(|f| format!("`{}`", f))
+                    .collect::<Vec<_>>();
+
+                if missing.is_empty() {
+                    None
+                } else {
+                    Some((k.clone(), missing))
+                }
+            });
+
+            match missing {
+                Some((krate, features)) => {
+                    if listed {
+                        Err(format!(
+                            "sysroot crate `{}` is listed in `build.crates`, but its \
+                             required features {} aren't enabled",
+                            krate,
+                            features.join(", ")
+                        ))?
+                    }
+
+                    deps.retain(|(k, _)| *k != krate);
+                }
+                None => return Ok(deps),
+            }
+        }
+    }
+
+    fn from(
+        toml: Option<&xargo::Toml>,
+        cmode: &CompilationMode,
+        base_path: &Path,
+        src: &Src,
+        panic_abort: bool,
+    ) -> Result<Self> {
+        let target = cmode.triple();
+
         fn make_path_absolute<F, R>(
             crate_spec: &mut Table,
             base_path: &Path,
@@ -499,76 +1862,158 @@ impl Blueprint {
                 make_path_absolute(krate, base_path, || format!("patch.{}.{}", k1, k2))?;
             }
         }
+        let user_patch = patch.clone();
 
         Blueprint::add_patch(&mut patch, src.path(), "rustc-std-workspace-core")?;
         Blueprint::add_patch(&mut patch, src.path(), "rustc-std-workspace-alloc")?;
         Blueprint::add_patch(&mut patch, src.path(), "rustc-std-workspace-std")?;
 
         // Compose dependency sections
-        let deps = match (
-            toml.and_then(|t| t.dependencies()),
-            toml.and_then(|t| t.target_dependencies(target)),
-        ) {
-            (Some(value), Some(tvalue)) => {
-                let mut deps = value
-                    .as_table()
-                    .cloned()
-                    .ok_or_else(|| format!("Xargo.toml: `dependencies` must be a table"))?;
-
-                let more_deps = tvalue.as_table().ok_or_else(|| {
-                    format!(
-                        "Xargo.toml: `target.{}.dependencies` must be \
-                         a table",
-                        target
-                    )
-                })?;
-                for (k, v) in more_deps {
-                    if deps.insert(k.to_owned(), v.clone()).is_some() {
-                        Err(format!(
-                            "found duplicate dependency name {}, \
-                             but all dependencies must have a \
-                             unique name",
-                            k
-                        ))?
+        let mut tdeps = vec![];
+        if let Some(value) = toml.and_then(|t| t.target_dependencies(target)) {
+            tdeps.push((target.to_owned(), value));
+        }
+
+        let cfg_deps = toml.map(xargo::Toml::cfg_dependencies).unwrap_or_default();
+        if !cfg_deps.is_empty() {
+            let cfgs = cmode.cfg(Verbosity::Normal)?;
+
+            for (key, value) in cfg_deps {
+                if let Some(expr) =
+                    Expr::from_key(key).chain_err(|| "Xargo.toml: invalid `target` key")?
+                {
+                    if expr.matches(&cfgs) {
+                        tdeps.push((format!("'{}'", key), value));
                     }
                 }
-
-                deps
             }
-            (Some(value), None) | (None, Some(value)) => {
-                if let Some(table) = value.as_table() {
-                    table.clone()
-                } else {
-                    Err(format!(
-                        "Xargo.toml: target.{}.dependencies must be \
+        }
+
+        let mut deps = match toml.and_then(|t| t.dependencies()) {
+            Some(value) => value
+                .as_table()
+                .cloned()
+                .ok_or_else(|| format!("Xargo.toml: `dependencies` must be a table"))?,
+            // WebAssembly programs almost always allocate, and the `std` of
+            // e.g. `wasm32-unknown-unknown` is mostly stubs
+            None if tdeps.is_empty() && cmode.is_wasm()? => wasm_dependencies(),
+            None if tdeps.is_empty() => default_dependencies(),
+            None => Table::new(),
+        };
+
+        for (target, tvalue) in tdeps {
+            let more_deps = tvalue.as_table().ok_or_else(|| {
+                format!(
+                    "Xargo.toml: `target.{}.dependencies` must be \
                      a table",
-                        target
+                    target
+                )
+            })?;
+            for (k, v) in more_deps {
+                // A target may pick its own features of the `std` set for all
+                // targets
+                if k == "std" && Blueprint::is_features_only(v) {
+                    if let (Some(Value::Table(std)), Some(features)) =
+                        (deps.get_mut("std"), v.as_table())
+                    {
+                        for (key, value) in features {
+                            std.insert(key.clone(), value.clone());
+                        }
+                        continue;
+                    }
+                }
+
+                // A target may toggle the optional crates set for all targets
+                let toggle = OPTIONAL_CRATES.contains(&&**k) && v.is_bool();
+
+                if deps.insert(k.to_owned(), v.clone()).is_some() && !toggle {
+                    Err(format!(
+                        "found duplicate dependency name {}, \
+                         but all dependencies must have a \
+                         unique name",
+                        k
                     ))?
                 }
             }
-            (None, None) => {
-                // If no dependencies were listed, we assume `core` and `compiler_builtins` as the
-                // dependencies
-                let mut t = Map::new();
-                let mut core = Map::new();
-                core.insert("stage".to_owned(), Value::Integer(0));
-                t.insert("core".to_owned(), Value::Table(core));
-                let mut cb = Map::new();
-                cb.insert(
-                    "features".to_owned(),
-                    Value::Array(vec![Value::String("mem".to_owned())]),
-                );
-                // reference compiler-builtins with `version = "*"`,
-                // the corresponding version of compiler_builtins matching the used std
-                // is selected because of the copied `Cargo.lock`-file from std.
-                cb.insert("version".to_owned(), Value::String("*".to_owned()));
-                cb.insert("stage".to_owned(), Value::Integer(1));
-                t.insert("compiler_builtins".to_owned(), Value::Table(cb));
-                t
+        }
+
+        // `proc_macro = true` is short for `[dependencies.proc_macro]`, and
+        // `proc_macro = false` leaves the crate out
+        let mut removed = false;
+        for krate in OPTIONAL_CRATES {
+            match deps.get(*krate).and_then(Value::as_bool) {
+                Some(true) => {
+                    deps.insert(krate.to_string(), Value::Table(Table::new()));
+                }
+                Some(false) => {
+                    deps.remove(*krate);
+                    removed = true;
+                }
+                None => {}
+            }
+        }
+
+        // Leaving out `test` shouldn't leave out `core` as well
+        if removed && deps.is_empty() {
+            deps = default_dependencies();
+        }
+
+        if let Some((k, _)) = deps.iter().find(|&(_, v)| v.is_bool()) {
+            Err(format!(
+                "Xargo.toml: dependency `{}` can't be `true` or `false`, only \
+                 {} can",
+                k,
+                OPTIONAL_CRATES
+                    .iter()
+                    .map(|k| format!("`{}`", k))
+                    .collect::<Vec<_>>()
+                    .join(" and ")
+            ))?
+        }
+
+        let crates = crate_list(toml)?;
+
+        // `std`, and the crates built on top of it, can't be compiled for a
+        // target without an operating system. Unless the user provides their
+        // own implementation, or lists the crates to build, leave them out of
+        // the sysroot.
+        if crates.is_none() && !cmode.supports_std()? {
+            let skipped = deps
+                .iter()
+                .filter(|&(k, v)| {
+                    ["std", "proc_macro", "test"].contains(&&**k)
+                        && v.get("path").is_none()
+                        && v.get("git").is_none()
+                })
+                .map(|(k, _)| k.clone())
+                .collect::<Vec<_>>();
+
+            for krate in skipped {
+                writeln!(
+                    io::stderr(),
+                    "warning: not building `{}` because {} doesn't support `std`",
+                    krate,
+                    target
+                )
+                .ok();
+                deps.remove(&krate);
+            }
+
+            if deps.is_empty() {
+                deps = default_dependencies();
             }
+        }
+
+        let listed = crates.is_some();
+        let deps = match crates {
+            Some(crates) => Blueprint::select(deps, &crates, src)?,
+            None => deps.into_iter().collect(),
         };
+        let deps = Blueprint::gate(deps, listed)?;
 
         let mut blueprint = Blueprint::new();
+        blueprint.patch = user_patch;
+        blueprint.jobs = sysroot_jobs(toml)?;
         for (k, v) in deps {
             if let Value::Table(mut map) = v {
                 let stage = if let Some(value) = map.remove("stage") {
@@ -579,6 +2024,8 @@ impl Blueprint {
                     0
                 };
 
+                map.remove("required-features");
+
                 make_path_absolute(&mut map, base_path, || format!("dependencies.{}", k))?;
 
                 if !map.contains_key("path") && !map.contains_key("git") {
@@ -588,6 +2035,26 @@ impl Blueprint {
                     let paths = [src.path().join(&k), src.path().join(format!("lib{}", k))];
                     if let Some(path) = paths.iter().find(|p| p.exists()) {
                         map.insert("path".to_owned(), Value::String(path.display().to_string()));
+                    } else if (k == "compiler_builtins" || k == "compiler-builtins")
+                        && !map.contains_key("version")
+                    {
+                        // Same as in `default_dependencies`, so that only the
+                        // features need to be given in `Xargo.toml`
+                        map.insert("version".to_owned(), Value::String("*".to_owned()));
+                    }
+                }
+
+                if k == "std" {
+                    if let Some(path) = map.get("path").and_then(Value::as_str).map(String::from) {
+                        Blueprint::expand_std_features(&mut map, &path)?;
+                        Blueprint::check_std_features(&map, &path)?;
+                    }
+                }
+
+                // With `panic = "abort"`, `panic_abort` is all `std` needs
+                if panic_abort && k == "std" {
+                    if let Some(path) = map.get("path").and_then(Value::as_str).map(String::from) {
+                        Blueprint::without_panic_unwind(&mut map, &path)?;
                     }
                 }
 
@@ -615,15 +2082,69 @@ impl Blueprint {
         stage.crates.push(krate);
     }
 
-    fn hash<H>(&self, hasher: &mut H)
-    where
-        H: Hasher,
-    {
+    fn hash(&self, inputs: &mut HashInputs) -> Result<()> {
         for stage in self.stages.values() {
             for (k, v) in stage.dependencies.iter() {
-                k.hash(hasher);
-                v.to_string().hash(hasher);
+                let v = v.to_string();
+                let shown = if v.is_empty() { "{}" } else { &*v };
+                inputs.add(&format!("dependency `{}`", k), &(k, &v), shown);
+            }
+        }
+
+        // A patched crate is usually a local checkout being worked on, so
+        // editing it must rebuild the sysroot even though `Xargo.toml` stays
+        // the same. A git patch is hashed by its `rev` / `tag` / `branch`.
+        for (registry, crates) in self.patch.iter() {
+            for (k, v) in crates.as_table().into_iter().flatten() {
+                let spec = v.to_string();
+                inputs.add(
+                    &format!("patch `{}.{}`", registry, k),
+                    &(registry, k, &spec),
+                    &spec,
+                );
+
+                if let Some(path) = v.get("path").and_then(Value::as_str) {
+                    let digest = source_digest(Path::new(path))?;
+                    inputs.add(
+                        &format!("patched source `{}`", k),
+                        &digest,
+                        format!("{:016x} ({})", digest, path),
+                    );
+                }
             }
         }
+
+        Ok(())
+    }
+}
+
+/// Hashes the files of the crate at `path`, along with their paths
+///
+/// `target` and hidden directories like `.git` are skipped: they don't hold
+/// the source of the crate.
+fn source_digest(path: &Path) -> Result<u64> {
+    let mut hasher = DefaultHasher::new();
+
+    let entries = WalkDir::new(path)
+        .sort_by(|a, b| a.file_name().cmp(b.file_name()))
+        .into_iter()
+        .filter_entry(|e| {
+            let name = e.file_name().to_string_lossy();
+            e.depth() == 0 || !(name == "target" || name.starts_with('.'))
+        });
+
+    for e in entries {
+        let e = e.chain_err(|| format!("couldn't read {}", path.display()))?;
+
+        if e.file_type().is_file() {
+            let relative = e.path().strip_prefix(path).unwrap_or_else(|_| e.path());
+            let contents =
+                fs::read(e.path()).chain_err(|| format!("couldn't read {}", e.path().display()))?;
+
+            relative.hash(&mut hasher);
+            contents.hash(&mut hasher);
+        }
     }
+
+    Ok(hasher.finish())
 }
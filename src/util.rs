@@ -1,7 +1,8 @@
-use std::fs;
+use std::ffi::OsStr;
 use std::fs::File;
 use std::io::{Read, Write};
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::{env, fs};
 
 use toml::Value;
 use walkdir::WalkDir;
@@ -93,6 +94,40 @@ pub fn search<'p>(mut path: &'p Path, file: &str) -> Option<&'p Path> {
     }
 }
 
+/// Searches `program` in `PATH`, like a shell would
+///
+/// If `program` is a path, e.g. `./rustc`, it's only checked for existence.
+pub fn which(program: &OsStr) -> Option<PathBuf> {
+    let program = Path::new(program);
+
+    if program.components().count() > 1 {
+        return if program.is_file() {
+            Some(program.to_owned())
+        } else {
+            None
+        };
+    }
+
+    let paths = env::var_os("PATH")?;
+    for dir in env::split_paths(&paths) {
+        let candidate = dir.join(program);
+
+        if candidate.is_file() {
+            return Some(candidate);
+        }
+
+        if cfg!(windows) {
+            let exe = candidate.with_extension("exe");
+
+            if exe.is_file() {
+                return Some(exe);
+            }
+        }
+    }
+
+    None
+}
+
 pub fn write(path: &Path, contents: &str) -> Result<()> {
     let p = path.display();
     File::create(path)
@@ -100,3 +135,19 @@ pub fn write(path: &Path, contents: &str) -> Result<()> {
         .write_all(contents.as_bytes())
         .chain_err(|| format!("couldn't write to {}", p))
 }
+
+/// Like `write`, but readers of `path` never see a partially written file
+///
+/// The contents are written next to `path` first and then moved over it.
+pub fn write_atomic(path: &Path, contents: &str) -> Result<()> {
+    let name = path
+        .file_name()
+        .ok_or_else(|| format!("{} is not a file path", path.display()))?;
+    let mut tmp = name.to_owned();
+    tmp.push(".tmp");
+    let tmp = path.with_file_name(tmp);
+
+    write(&tmp, contents)?;
+    fs::rename(&tmp, path)
+        .chain_err(|| format!("couldn't move {} to {}", tmp.display(), path.display()))
+}
@@ -1,6 +1,6 @@
 use std::io::{self, Write};
 use std::path::{Display, Path, PathBuf};
-use std::process::ExitStatus;
+use std::process::{Command, ExitStatus};
 use std::{env, mem};
 
 use rustc_version::VersionMeta;
@@ -9,8 +9,10 @@ use toml::Value;
 use cargo::{Config, Root, Rustflags, Subcommand};
 use cli::Args;
 use errors::*;
-use extensions::CommandExt;
+use extensions::{CommandExt, Verbosity};
 use flock::{FileLock, Filesystem};
+use rustc::Sysroot;
+use sysroot::XargoMode;
 use CompilationMode;
 use {cargo, util};
 
@@ -21,30 +23,79 @@ pub fn run(
     home: &Home,
     meta: &VersionMeta,
     config: Option<&Config>,
-    verbose: bool,
+    verbosity: Verbosity,
 ) -> Result<ExitStatus> {
+    // With `--force-custom`, rustc must get the target specification file
+    let target = cmode.target_arg();
+    let args = if target != cmode.triple() {
+        args.with_target(&target)
+    } else {
+        args.clone()
+    };
+    let mut cmd = command(&args, cmode.triple(), rustflags, home, config, verbosity)?;
+
+    // The target specification may not be where rustc looks for it, e.g.
+    // when it's given inline in `Cargo.toml`
+    if let CompilationMode::Cross(ref target) = *cmode {
+        if let Some(path) = target.target_path()? {
+            cmd.env("RUST_TARGET_PATH", path);
+        }
+    }
+
+    let locks = (home.lock_ro(&meta.host), home.lock_ro(cmode.triple()));
+
+    let status = cmd.run_and_get_status(verbosity)?;
+
+    mem::drop(locks);
+
+    Ok(status)
+}
+
+/// Runs Cargo with the sysroot at `home`, as it is, for `triple`
+///
+/// This is `--no-sysroot`: there's no target to resolve, and nothing to build
+/// or lock.
+pub fn run_prebuilt(
+    args: &Args,
+    triple: &str,
+    home: &Home,
+    config: Option<&Config>,
+    verbosity: Verbosity,
+) -> Result<ExitStatus> {
+    let rustflags = cargo::rustflags(config, triple)?;
+
+    command(args, triple, rustflags, home, config, verbosity)?.run_and_get_status(verbosity)
+}
+
+/// The Cargo command of `args`, with the flags of `triple` pointing to the
+/// sysroot at `home`
+///
+/// Apart from the flags, the environment is inherited as it is, so e.g.
+/// `CARGO_TARGET_DIR` applies to the application, unlike to the sysroot.
+fn command(
+    args: &Args,
+    triple: &str,
+    rustflags: Rustflags,
+    home: &Home,
+    config: Option<&Config>,
+    verbosity: Verbosity,
+) -> Result<Command> {
     let mut cmd = cargo::command();
     cmd.args(args.all());
 
     if args.subcommand() == Some(Subcommand::Doc) {
         cmd.env(
             "CARGO_ENCODED_RUSTDOCFLAGS",
-            cargo::rustdocflags(config, cmode.triple())?.encode(home),
+            cargo::rustdocflags(config, triple)?.encode(home),
         );
     }
 
-    if verbose {
+    if verbosity.is_verbose() {
         writeln!(io::stderr(), "+ RUSTFLAGS={}", rustflags).ok();
     }
     cmd.env("CARGO_ENCODED_RUSTFLAGS", rustflags.encode(home));
 
-    let locks = (home.lock_ro(&meta.host), home.lock_ro(cmode.triple()));
-
-    let status = cmd.run_and_get_status(verbose)?;
-
-    mem::drop(locks);
-
-    Ok(status)
+    Ok(cmd)
 }
 
 pub struct Home {
@@ -56,6 +107,49 @@ impl Home {
         self.path.display()
     }
 
+    /// The sysroot Xargo builds, i.e. what gets passed to `rustc --sysroot`
+    pub fn sysroot(&self) -> Sysroot {
+        Sysroot::new(self.path.as_path_unlocked().to_owned())
+    }
+
+    /// The directory holding the sysroot of `triple`, `lib/rustlib/$triple`
+    pub fn rustlib(&self, triple: &str) -> PathBuf {
+        self.path(triple).as_path_unlocked().to_owned()
+    }
+
+    /// Where the sysroot of `triple` is built with `XARGO_INCREMENTAL=1`
+    pub fn build_dir(&self, triple: &str) -> PathBuf {
+        self.path.as_path_unlocked().join("build").join(triple)
+    }
+
+    /// The directories in `lib/rustlib` that hold a sysroot Xargo built
+    ///
+    /// Xargo locks every sysroot it builds, so these are the directories that
+    /// have a lock file.
+    pub fn rustlibs(&self) -> Result<Vec<PathBuf>> {
+        let dir = self.path.as_path_unlocked().join("lib").join("rustlib");
+
+        if !dir.is_dir() {
+            return Ok(vec![]);
+        }
+
+        let mut rustlibs = vec![];
+        for entry in dir
+            .read_dir()
+            .chain_err(|| format!("couldn't read {}", dir.display()))?
+        {
+            let path = entry
+                .chain_err(|| format!("couldn't read {}", dir.display()))?
+                .path();
+
+            if path.join(".sentinel").is_file() {
+                rustlibs.push(path);
+            }
+        }
+
+        Ok(rustlibs)
+    }
+
     fn path(&self, triple: &str) -> Filesystem {
         self.path.join("lib").join("rustlib").join(triple)
     }
@@ -75,24 +169,185 @@ impl Home {
     }
 }
 
-pub fn home(cmode: &CompilationMode) -> Result<Home> {
-    let mut p = if let Some(h) = env::var_os("XARGO_HOME") {
-        PathBuf::from(h)
+/// `$XARGO_HOME`, or `~/.xargo` if unset
+///
+/// A relative `XARGO_HOME` is resolved against the root of the project, or
+/// against the current directory when there's no project.
+fn base(root: Option<&Root>) -> Result<PathBuf> {
+    Ok(if let Some(h) = env::var_os("XARGO_HOME") {
+        let h = PathBuf::from(h);
+
+        if h.is_absolute() {
+            h
+        } else if let Some(root) = root {
+            root.path().join(h)
+        } else {
+            env::current_dir()
+                .chain_err(|| "couldn't get the current directory")?
+                .join(h)
+        }
     } else {
         dirs::home_dir()
             .ok_or_else(|| "couldn't find your home directory. Is $HOME set?")?
             .join(".xargo")
-    };
+    })
+}
+
+/// Where the sysroots of `root` are cached: the directory of
+/// `--sysroot-cache-dir`, or else of `build.sysroot-cache-dir` in `Xargo.toml`,
+/// or else Xargo's home
+///
+/// The directory is laid out like Xargo's home, and locked the same way, so
+/// any number of projects can share one, or each have their own.
+fn cache(root: &Root, parent: Option<&Path>, xtoml: Option<&Toml>) -> Result<PathBuf> {
+    if let Some(dir) = root.sysroot_cache_dir() {
+        return Ok(dir.to_owned());
+    }
+
+    match xtoml.and_then(Toml::sysroot_cache_dir) {
+        Some(value) => {
+            let dir = value
+                .as_str()
+                .ok_or("Xargo.toml: `build.sysroot-cache-dir` must be a string")?;
+
+            Ok(parent.unwrap_or_else(|| root.path()).join(dir))
+        }
+        None => base(Some(root)),
+    }
+}
+
+pub fn home(cmode: &CompilationMode, root: &Root, cargo_mode: XargoMode) -> Result<Home> {
+    let (parent, xtoml) = toml(root)?;
+    let mut p = cache(root, parent, xtoml.as_ref())?;
 
     if cmode.is_native() {
         p.push("HOST");
     }
 
+    // `xargo-check` has its own sysroots, so alternating it with `xargo`
+    // doesn't rebuild either
+    if cargo_mode == XargoMode::Check {
+        p.push("check");
+    }
+
+    // The `release` sysroot stays where it was before `build.per-profile`
+    let profile = sysroot_profile(xtoml.as_ref(), root)?;
+    if profile != "release" {
+        p.push("profile");
+        p.push(profile);
+    }
+
     Ok(Home {
         path: Filesystem::new(p),
     })
 }
 
+/// The home of `--sysroot-dir`, which is the same for native and cross
+/// compilation
+///
+/// Unless `force` is set, `dir` must be empty or only hold sysroots Xargo
+/// built, so other files don't end up mixed with the sysroot.
+pub fn home_in(dir: &Path, force: bool) -> Result<Home> {
+    let dir = env::current_dir()
+        .chain_err(|| "couldn't get the current directory")?
+        .join(dir);
+    let home = Home {
+        path: Filesystem::new(dir.clone()),
+    };
+
+    if dir.exists() {
+        let mut entries = dir
+            .read_dir()
+            .chain_err(|| format!("couldn't read {}", dir.display()))?;
+
+        if !force && entries.next().is_some() && home.rustlibs()?.is_empty() {
+            bail!(
+                "{} is not empty. Pass `--force` to build the sysroot in it anyway.",
+                dir.display()
+            )
+        }
+    }
+
+    Ok(home)
+}
+
+/// The home of a sysroot that was built beforehand, which is used as it is
+pub fn prebuilt(dir: &Path) -> Result<Home> {
+    let dir = env::current_dir()
+        .chain_err(|| "couldn't get the current directory")?
+        .join(dir);
+
+    if !dir.is_dir() {
+        bail!("the prebuilt sysroot {} doesn't exist", dir.display())
+    }
+
+    Ok(Home {
+        path: Filesystem::new(dir),
+    })
+}
+
+/// Where the Rust source of `XARGO_RUST_SRC_URL` is downloaded, in Xargo's
+/// home
+pub fn src_cache(root: Option<&Root>) -> Result<PathBuf> {
+    Ok(base(root)?.join("src"))
+}
+
+/// All the homes: the one of cross compilation and the one of native
+/// compilation, then the ones of `xargo-check`, and then the ones of the
+/// other profiles of `build.per-profile`
+pub fn homes(root: Option<&Root>) -> Result<Vec<Home>> {
+    let p = match root {
+        Some(root) => {
+            let (parent, xtoml) = toml(root)?;
+            cache(root, parent, xtoml.as_ref())?
+        }
+        None => base(root)?,
+    };
+
+    let mut dirs = vec![p.join("HOST"), p.clone()];
+    dirs.extend([p.join("HOST/check"), p.join("check")]);
+    for dir in dirs.clone() {
+        let profiles = dir.join("profile");
+
+        if profiles.is_dir() {
+            for entry in profiles
+                .read_dir()
+                .chain_err(|| format!("couldn't read {}", profiles.display()))?
+            {
+                dirs.push(
+                    entry
+                        .chain_err(|| format!("couldn't read {}", profiles.display()))?
+                        .path(),
+                );
+            }
+        }
+    }
+
+    Ok(dirs
+        .into_iter()
+        .map(|dir| Home {
+            path: Filesystem::new(dir),
+        })
+        .collect())
+}
+
+/// The Cargo profile the sysroot is built with: the one of the crate with
+/// `build.per-profile`, and `release` otherwise
+pub fn sysroot_profile<'a>(xtoml: Option<&Toml>, root: &'a Root) -> Result<&'a str> {
+    let per_profile = match xtoml.and_then(Toml::per_profile) {
+        Some(value) => value
+            .as_bool()
+            .ok_or("Xargo.toml: `build.per-profile` must be a boolean")?,
+        None => false,
+    };
+
+    Ok(if per_profile {
+        root.profile()
+    } else {
+        "release"
+    })
+}
+
 pub struct Toml {
     table: Value,
 }
@@ -111,21 +366,336 @@ impl Toml {
             .and_then(|t| t.get("dependencies"))
     }
 
+    /// The triples of the `[target.<triple>]` tables of `Xargo.toml`, without
+    /// the `[target.'cfg(..)']` ones
+    pub fn targets(&self) -> Vec<&str> {
+        self.table
+            .get("target")
+            .and_then(Value::as_table)
+            .map(|t| {
+                t.keys()
+                    .filter(|k| !k.trim().starts_with("cfg("))
+                    .map(|k| &**k)
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// Returns the `target.'cfg(..)'.dependencies` parts of `Xargo.toml`,
+    /// along with their `cfg(..)` key
+    pub fn cfg_dependencies(&self) -> Vec<(&str, &Value)> {
+        self.table
+            .get("target")
+            .and_then(Value::as_table)
+            .map(|t| {
+                t.iter()
+                    .filter(|&(k, _)| k.trim().starts_with("cfg("))
+                    .filter_map(|(k, v)| v.get("dependencies").map(|d| (&**k, d)))
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
     /// Returns the `patch` part of `Xargo.toml`
     pub fn patch(&self) -> Option<&Value> {
         self.table.get("patch")
     }
+
+    /// Returns the `build.profile` part of `Xargo.toml`
+    pub fn profile(&self) -> Option<&Value> {
+        self.table.get("build").and_then(|t| t.get("profile"))
+    }
+
+    /// Returns the `build.per-profile` part of `Xargo.toml`
+    pub fn per_profile(&self) -> Option<&Value> {
+        self.table.get("build").and_then(|t| t.get("per-profile"))
+    }
+
+    /// Returns the `build.sysroot-cache-dir` part of `Xargo.toml`
+    pub fn sysroot_cache_dir(&self) -> Option<&Value> {
+        self.table
+            .get("build")
+            .and_then(|t| t.get("sysroot-cache-dir"))
+    }
+
+    /// Returns the `build.codegen-units` part of `Xargo.toml`
+    pub fn codegen_units(&self) -> Option<&Value> {
+        self.table.get("build").and_then(|t| t.get("codegen-units"))
+    }
+
+    /// Returns the `build.jobs` part of `Xargo.toml`
+    pub fn jobs(&self) -> Option<&Value> {
+        self.table.get("build").and_then(|t| t.get("jobs"))
+    }
+
+    /// Returns the `build.rustflags` part of `Xargo.toml`
+    pub fn rustflags(&self) -> Option<&Value> {
+        self.table.get("build").and_then(|t| t.get("rustflags"))
+    }
+
+    /// Returns the `build.deny-warnings` part of `Xargo.toml`
+    pub fn deny_warnings(&self) -> Option<&Value> {
+        self.table.get("build").and_then(|t| t.get("deny-warnings"))
+    }
+
+    /// Returns the `build.unstable-flags` part of `Xargo.toml`
+    pub fn unstable_flags(&self) -> Option<&Value> {
+        self.table
+            .get("build")
+            .and_then(|t| t.get("unstable-flags"))
+    }
+
+    /// Returns the `build.sanitizer` part of `Xargo.toml`
+    pub fn sanitizer(&self) -> Option<&Value> {
+        self.table.get("build").and_then(|t| t.get("sanitizer"))
+    }
+
+    /// Returns the `build.crates` part of `Xargo.toml`
+    pub fn crates(&self) -> Option<&Value> {
+        self.table.get("build").and_then(|t| t.get("crates"))
+    }
+
+    /// Returns the `build.target-dir-specs` part of `Xargo.toml`
+    pub fn target_dir_specs(&self) -> Option<&Value> {
+        self.table
+            .get("build")
+            .and_then(|t| t.get("target-dir-specs"))
+    }
+
+    /// Returns the `build.post-sysroot-hook` part of `Xargo.toml`
+    pub fn post_sysroot_hook(&self) -> Option<&Value> {
+        self.table
+            .get("build")
+            .and_then(|t| t.get("post-sysroot-hook"))
+    }
+
+    /// Returns the `target.{}.linker` part of `Xargo.toml`
+    pub fn target_linker(&self, target: &str) -> Option<&Value> {
+        self.table
+            .get("target")
+            .and_then(|t| t.get(target))
+            .and_then(|t| t.get("linker"))
+    }
+
+    /// Returns the `target.{}.profile` part of `Xargo.toml`
+    pub fn target_profile(&self, target: &str) -> Option<&Value> {
+        self.table
+            .get("target")
+            .and_then(|t| t.get(target))
+            .and_then(|t| t.get("profile"))
+    }
 }
 
 /// Returns the closest directory containing a 'Xargo.toml' and the parsed
-/// content of this 'Xargo.toml'
+/// content of this 'Xargo.toml', merged over the global one
+///
+/// `${VAR}` in string values is replaced with the environment variable `VAR`.
+/// The global `Xargo.toml` is the one in Xargo's home. Its relative paths are
+/// relative to Xargo's home, so they're made absolute before the merge.
 pub fn toml(root: &Root) -> Result<(Option<&Path>, Option<Toml>)> {
-    if let Some(p) = util::search(root.path(), "Xargo.toml") {
-        Ok((
-            Some(p),
-            util::parse(&p.join("Xargo.toml")).map(|t| Some(Toml { table: t }))?,
-        ))
-    } else {
-        Ok((None, None))
+    let global = base(Some(root))?.join("Xargo.toml");
+    let project = util::search(root.path(), "Xargo.toml");
+
+    let mut table = None;
+    // The project may itself be in Xargo's home
+    if global.is_file() && project.map(|p| p.join("Xargo.toml")) != Some(global.clone()) {
+        let mut global_table = parse(&global)?;
+        if let Some(dir) = global.parent() {
+            absolute_paths(&mut global_table, dir);
+        }
+        table = Some(global_table);
+    }
+
+    if let Some(p) = project {
+        let project_table = parse(&p.join("Xargo.toml"))?;
+
+        table = Some(match table {
+            Some(mut table) => {
+                merge(&mut table, project_table);
+                table
+            }
+            None => project_table,
+        });
+    }
+
+    if let Some(ref mut table) = table {
+        features(table, root)?;
+    }
+
+    Ok((project, table.map(|table| Toml { table })))
+}
+
+/// Merges the `features.<name>` tables of `table` into it, in the order of
+/// their names, for the features of the crate that are enabled
+///
+/// They may hold any part of `Xargo.toml`, e.g. `dependencies` or
+/// `build.profile`, so they're part of the sysroot hash through those.
+fn features(table: &mut Value, root: &Root) -> Result<()> {
+    let features = match table.get("features") {
+        Some(Value::Table(features)) => features.clone(),
+        Some(_) => bail!("Xargo.toml: `features` must be a table"),
+        None => return Ok(()),
+    };
+
+    for (name, over) in features {
+        if !over.is_table() {
+            bail!("Xargo.toml: `features.{}` must be a table", name)
+        }
+
+        if root.feature_enabled(&name)? {
+            merge(table, over);
+        }
+    }
+
+    Ok(())
+}
+
+/// Parses the `Xargo.toml` at `path`, with its `${VAR}`s replaced
+fn parse(path: &Path) -> Result<Value> {
+    let mut table = util::parse(path)?;
+    expand(&mut table, "")?;
+
+    Ok(table)
+}
+
+/// Merges `over` into `table`, replacing any value of `table` that's not a
+/// table on both sides
+fn merge(table: &mut Value, over: Value) {
+    match (table, over) {
+        (&mut Value::Table(ref mut table), Value::Table(over)) => {
+            for (k, v) in over {
+                match table.get_mut(&k) {
+                    Some(value) => merge(value, v),
+                    None => {
+                        table.insert(k, v);
+                    }
+                }
+            }
+        }
+        (table, over) => *table = over,
+    }
+}
+
+/// Makes the relative paths of `value`, an `Xargo.toml` in `dir`, absolute:
+/// the `path` of crates, `build.target-dir-specs`, and the `target.*.linker`s
+/// and `build.post-sysroot-hook` that aren't just a program name
+fn absolute_paths(value: &mut Value, dir: &Path) {
+    fn absolute(value: &mut Value, dir: &Path) {
+        if let Value::String(ref mut s) = *value {
+            if Path::new(s.as_str()).is_relative() {
+                *s = dir.join(&*s).display().to_string();
+            }
+        }
+    }
+
+    fn crate_paths(value: &mut Value, dir: &Path) {
+        if let Value::Table(ref mut table) = *value {
+            for (k, v) in table.iter_mut() {
+                if k == "path" {
+                    absolute(v, dir);
+                } else {
+                    crate_paths(v, dir);
+                }
+            }
+        }
+    }
+
+    crate_paths(value, dir);
+
+    if let Some(specs) = value
+        .get_mut("build")
+        .and_then(|b| b.get_mut("target-dir-specs"))
+    {
+        match *specs {
+            Value::Array(ref mut specs) => specs.iter_mut().for_each(|s| absolute(s, dir)),
+            ref mut spec => absolute(spec, dir),
+        }
+    }
+
+    if let Some(cache) = value
+        .get_mut("build")
+        .and_then(|b| b.get_mut("sysroot-cache-dir"))
+    {
+        absolute(cache, dir);
+    }
+
+    if let Some(hook) = value
+        .get_mut("build")
+        .and_then(|b| b.get_mut("post-sysroot-hook"))
+    {
+        if hook.as_str().map(linker_is_path) == Some(true) {
+            absolute(hook, dir);
+        }
     }
+
+    if let Some(targets) = value.get_mut("target").and_then(Value::as_table_mut) {
+        for linker in targets.iter_mut().filter_map(|(_, t)| t.get_mut("linker")) {
+            if linker.as_str().map(linker_is_path) == Some(true) {
+                absolute(linker, dir);
+            }
+        }
+    }
+}
+
+/// Whether `linker` is a path rather than a program to look up in `PATH`, in
+/// which case it's relative to the `Xargo.toml` it's in, like in Cargo's
+/// configuration
+///
+/// `build.post-sysroot-hook` follows the same rule.
+pub fn linker_is_path(linker: &str) -> bool {
+    Path::new(linker).components().count() > 1
+}
+
+/// Replaces `${VAR}` with the value of the environment variable `VAR` in all
+/// the strings of `value`, which is at `key` in `Xargo.toml`
+///
+/// An unset variable is an error, rather than an empty string that would
+/// likely turn into a confusing path.
+fn expand(value: &mut Value, key: &str) -> Result<()> {
+    match *value {
+        Value::String(ref mut s) => {
+            let mut expanded = String::new();
+            let mut rest = &s[..];
+
+            while let Some(start) = rest.find("${") {
+                let end = rest[start..]
+                    .find('}')
+                    .ok_or_else(|| format!("Xargo.toml: unterminated `${{` in `{}`", key))?;
+                let var = &rest[start + 2..start + end];
+                let val = env::var(var).map_err(|_| {
+                    format!(
+                        "Xargo.toml: `{}` uses the environment variable `{}`, which is \
+                         not set",
+                        key, var
+                    )
+                })?;
+
+                expanded.push_str(&rest[..start]);
+                expanded.push_str(&val);
+                rest = &rest[start + end + 1..];
+            }
+
+            if rest.len() != s.len() {
+                expanded.push_str(rest);
+                *s = expanded;
+            }
+        }
+        Value::Array(ref mut values) => {
+            for (i, v) in values.iter_mut().enumerate() {
+                expand(v, &format!("{}[{}]", key, i))?;
+            }
+        }
+        Value::Table(ref mut table) => {
+            for (k, v) in table.iter_mut() {
+                if key.is_empty() {
+                    expand(v, k)?;
+                } else {
+                    expand(v, &format!("{}.{}", key, k))?;
+                }
+            }
+        }
+        _ => {}
+    }
+
+    Ok(())
 }
@@ -7,12 +7,14 @@ extern crate lazy_static;
 extern crate dirs;
 extern crate parking_lot;
 extern crate rustc_version;
+extern crate serde_json;
 extern crate tempdir;
+extern crate xargo;
 
 use std::fs::OpenOptions;
 use std::io::Write;
 use std::path::{Path, PathBuf};
-use std::process::Command;
+use std::process::{Command, Stdio};
 use std::{env, fs};
 
 use parking_lot::{Mutex, MutexGuard};
@@ -46,13 +48,16 @@ fn home() -> Result<PathBuf> {
 }
 
 fn cleanup(target: &str) -> Result<()> {
-    let p = home()?.join("lib/rustlib").join(target);
+    for home in [home()?, home()?.join("check")] {
+        let p = home.join("lib/rustlib").join(target);
 
-    if p.exists() {
-        fs::remove_dir_all(&p).chain_err(|| format!("couldn't clean sysroot for {}", target))
-    } else {
-        Ok(())
+        if p.exists() {
+            fs::remove_dir_all(&p)
+                .chain_err(|| format!("couldn't clean sysroot for {}", target))?;
+        }
     }
+
+    Ok(())
 }
 
 fn exists(krate: &str, target: &str) -> Result<bool> {
@@ -132,6 +137,7 @@ fn xargo_check() -> Result<Command> {
 trait CommandExt {
     fn run(&mut self) -> Result<()>;
     fn run_and_get_stderr(&mut self) -> Result<String>;
+    fn run_and_get_stdout(&mut self) -> Result<String>;
 }
 
 impl CommandExt for Command {
@@ -173,6 +179,23 @@ impl CommandExt for Command {
             ))?
         }
     }
+
+    fn run_and_get_stdout(&mut self) -> Result<String> {
+        let out = self
+            .output()
+            .chain_err(|| format!("couldn't execute `{:?}`", self))?;
+
+        if out.status.success() {
+            String::from_utf8(out.stdout).chain_err(|| format!("`{:?}` output was not UTF-8", self))
+        } else {
+            print!("{}", String::from_utf8_lossy(&out.stderr));
+            Err(format!(
+                "`{:?}` failed with exit code: {:?}",
+                self,
+                out.status.code()
+            ))?
+        }
+    }
 }
 
 struct Project {
@@ -288,6 +311,15 @@ impl Project {
         write(&self.td.path().join(".cargo/config"), false, contents)
     }
 
+    /// Calls `xargo check`
+    fn check(&self, target: &str) -> Result<()> {
+        xargo()?
+            .args(["check", "--target", target])
+            .current_dir(self.td.path())
+            .run_and_get_stderr()?;
+        Ok(())
+    }
+
     /// Calls `xargo doc`
     fn doc(&self, target: &str) -> Result<()> {
         xargo()?
@@ -301,6 +333,28 @@ impl Project {
     fn xargo_toml(&self, toml: &str) -> Result<()> {
         write(&self.td.path().join("Xargo.toml"), false, toml)
     }
+
+    /// The `xargo --print-hash` command of the project's target, with `args`
+    fn print_hash_command(&self, args: &[&str]) -> Result<Command> {
+        let mut cmd = xargo()?;
+        cmd.args(["--print-hash", "--target", self.name])
+            .args(args)
+            .current_dir(self.td.path());
+        Ok(cmd)
+    }
+
+    /// Calls `xargo --print-hash` and returns the hash it prints
+    fn print_hash(&self, args: &[&str]) -> Result<String> {
+        self.print_hash_command(args)?.run_and_get_stdout()
+    }
+
+    /// Calls `xargo` with `args` and collects STDOUT
+    fn xargo_stdout(&self, args: &[&str]) -> Result<String> {
+        xargo()?
+            .args(args)
+            .current_dir(self.td.path())
+            .run_and_get_stdout()
+    }
 }
 
 impl Drop for Project {
@@ -460,22 +514,27 @@ path = "stage1"
     run!()
 }
 
-/// Test building a dependency specified as `dependencies` in Xargo.toml
+/// Test building dependencies specified as `target.'cfg(..)'.dependencies` in
+/// Xargo.toml
 #[test]
-fn dependencies() {
+fn cfg_dependencies() {
     fn run() -> Result<()> {
-        // need this exact target name to get the right gcc flags
-        const TARGET: &'static str = "thumbv6m-none-eabi";
+        const TARGET: &str = "thumbv6m-cfg_dependencies-eabi";
 
         let project = Project::new(TARGET)?;
         project.xargo_toml(
             r#"
-[dependencies.alloc]
+[dependencies.core]
+
+[target.'cfg(all(target_arch = "arm", target_os = "none"))'.dependencies.alloc]
+
+[target.'cfg(unix)'.dependencies.std]
 "#,
         )?;
         project.build(TARGET)?;
         assert!(exists("core", TARGET)?);
         assert!(exists("alloc", TARGET)?);
+        assert!(!exists("std", TARGET)?);
 
         Ok(())
     }
@@ -483,40 +542,70 @@ fn dependencies() {
     run!()
 }
 
-/// Test that `xargo build` can be executed from outside project dir
+/// Check that a target can leave out the `test` crate set for all targets
 #[test]
-fn build_outside_project_dir() {
+fn optional_crates() {
     fn run() -> Result<()> {
-        // need this exact target name to get the right gcc flags
-        const TARGET: &'static str = "thumbv6m-workdir-eabi";
+        const TARGET: &str = "thumbv6m-optional_crates-eabi";
 
         let project = Project::new(TARGET)?;
-        project.xargo_toml(
+        project.xargo_toml(&format!(
             r#"
-[dependencies.alloc]
-"#,
-        )?;
-        // This `workdir` is from where `xargo build` is invoked. It is different from the project dir.
-        let workdir =
-            TempDir::new("xargo_workdir").chain_err(|| "couldn't create a temporary directory")?;
+[dependencies]
+alloc = {{}}
+test = true
 
-        project.build_from_workdir(TARGET, &workdir.path())?;
+[target.{}.dependencies]
+test = false
+"#,
+            TARGET
+        ))?;
+        let stderr = project.build_and_get_stderr(Some(TARGET))?;
+        assert!(exists("alloc", TARGET)?);
+        assert!(!exists("test", TARGET)?);
+        assert!(!stderr.contains("not building `test`"));
 
         Ok(())
     }
-    let r = run();
-    assert!(r.is_ok());
+
+    run!()
 }
 
-/// Test `xargo doc`
+/// Check that a target may pick the features of `std`, which must exist
 #[test]
-fn doc() {
+fn target_std_features() {
     fn run() -> Result<()> {
-        const TARGET: &'static str = "thumbv6m-doc-eabi";
+        const TARGET: &str = "thumbv6m-target_std_features-eabi";
 
         let project = Project::new(TARGET)?;
-        project.doc(TARGET)?;
-        assert!(exists("core", TARGET)?);
+        let host = host();
+        let dry_run = |features: &str| -> Result<(bool, String)> {
+            project.xargo_toml(&format!(
+                r#"
+[dependencies.std]
+[target.{}.dependencies.std]
+default-features = false
+features = [{}]
+"#,
+                host, features
+            ))?;
+            let out = xargo()?
+                .args(["build", "--dry-run", "--target", &host])
+                .current_dir(project.td.path())
+                .output()
+                .chain_err(|| "couldn't execute `xargo build --dry-run`")?;
+
+            Ok((
+                out.status.success(),
+                String::from_utf8(out.stderr).chain_err(|| "stderr was not UTF-8")?,
+            ))
+        };
+
+        assert!(dry_run("\"panic_immediate_abort\"")?.0);
+
+        let (success, stderr) = dry_run("\"no_such_feature\"")?;
+        assert!(!success);
+        assert!(stderr.contains("unknown `std` feature `no_such_feature`"));
 
         Ok(())
     }
@@ -524,20 +613,29 @@ fn doc() {
     run!()
 }
 
-/// Check that calling `xargo build` a second time doesn't rebuild the sysroot
+/// Check that `features = ["*"]` enables, and hashes, all the features of
+/// `std`
 #[test]
-fn twice() {
+fn std_features_wildcard() {
     fn run() -> Result<()> {
-        const TARGET: &'static str = "thumbv6m-twice-eabi";
+        const TARGET: &str = "thumbv6m-std_features_wildcard-eabi";
 
         let project = Project::new(TARGET)?;
-        let stderr = project.build_and_get_stderr(Some(TARGET))?;
-
-        assert!(sysroot_was_built(&stderr, TARGET));
-
-        let stderr = project.build_and_get_stderr(Some(TARGET))?;
+        let host = host();
+        project.xargo_toml(&format!(
+            r#"
+[target.{}.dependencies.std]
+features = ["*"]
+"#,
+            host
+        ))?;
+        let stderr = xargo()?
+            .args(["--print-hash", "-v", "--target", &host])
+            .current_dir(project.td.path())
+            .run_and_get_stderr()?;
 
-        assert!(!sysroot_was_built(&stderr, TARGET));
+        assert!(stderr.contains("panic_immediate_abort"), "{}", stderr);
+        assert!(!stderr.contains("\"*\""), "{}", stderr);
 
         Ok(())
     }
@@ -545,24 +643,42 @@ fn twice() {
     run!()
 }
 
-/// Check that if `build.target` is set in `.cargo/config`, that target will be
-/// used to build the sysroot
+/// Check that a sysroot crate with `required-features` is only built when
+/// the features are enabled
 #[test]
-fn build_target() {
+fn required_features() {
     fn run() -> Result<()> {
-        const TARGET: &'static str = "thumbv6m-build_target-eabi";
+        const TARGET: &str = "thumbv6m-required_features-eabi";
 
         let project = Project::new(TARGET)?;
-        project.config(
+        let stages = |xargo_toml: &str| -> Result<String> {
+            project.xargo_toml(xargo_toml)?;
+            Ok(project
+                .xargo_stdout(&["build", "--dry-run", "--target", TARGET])?
+                .lines()
+                .filter(|l| l.starts_with("stage"))
+                .collect::<Vec<_>>()
+                .join("\n"))
+        };
+
+        let without = stages(
             r#"
-[build]
-target = "thumbv6m-build_target-eabi"
+[dependencies.core]
+[dependencies.alloc]
+required-features = ["core/panic_immediate_abort"]
 "#,
         )?;
+        assert!(!without.contains("alloc"));
 
-        let stderr = project.build_and_get_stderr(None)?;
-
-        assert!(sysroot_was_built(&stderr, TARGET));
+        let with = stages(
+            r#"
+[dependencies.core]
+features = ["panic_immediate_abort"]
+[dependencies.alloc]
+required-features = ["core/panic_immediate_abort"]
+"#,
+        )?;
+        assert!(with.contains("alloc"));
 
         Ok(())
     }
@@ -570,23 +686,41 @@ target = "thumbv6m-build_target-eabi"
     run!()
 }
 
-/// Check that `--target` overrides `build.target`
+/// Check that `build.crates` picks the sysroot crates, and that an unknown
+/// crate in `XARGO_SYSROOT_CRATES` is rejected
 #[test]
-fn override_build_target() {
+fn sysroot_crates() {
     fn run() -> Result<()> {
-        const TARGET: &'static str = "thumbv6m-override_build_target-eabi";
+        const TARGET: &str = "thumbv6m-sysroot_crates-eabi";
 
         let project = Project::new(TARGET)?;
-        project.config(
+        project.xargo_toml(
             r#"
 [build]
-target = "BAD"
+crates = ["core", "alloc", "compiler_builtins"]
+
+[dependencies]
+std = {}
 "#,
         )?;
+        project.build(TARGET)?;
+        assert!(exists("alloc", TARGET)?);
+        assert!(!exists("std", TARGET)?);
 
-        let stderr = project.build_and_get_stderr(Some(TARGET))?;
+        let out = xargo()?
+            .env("XARGO_SYSROOT_CRATES", "core,allocc")
+            .args(["build", "--target", TARGET])
+            .current_dir(project.td.path())
+            .output()
+            .chain_err(|| "couldn't execute `xargo build`")?;
+        let stderr = String::from_utf8_lossy(&out.stderr);
 
-        assert!(sysroot_was_built(&stderr, TARGET));
+        assert!(!out.status.success());
+        assert!(
+            stderr.contains("unknown sysroot crate `allocc`; valid crates are:"),
+            "unexpected stderr:\n{}",
+            stderr
+        );
 
         Ok(())
     }
@@ -594,27 +728,22 @@ target = "BAD"
     run!()
 }
 
-/// We shouldn't rebuild the sysroot if `profile.release.lto` changed
+/// Test building a dependency specified as `dependencies` in Xargo.toml
 #[test]
-fn lto_changed() {
+fn dependencies() {
     fn run() -> Result<()> {
-        const TARGET: &'static str = "thumbv6m-lto_changed-eabi";
+        // need this exact target name to get the right gcc flags
+        const TARGET: &'static str = "thumbv6m-none-eabi";
 
         let project = Project::new(TARGET)?;
-        let stderr = project.build_and_get_stderr(Some(TARGET))?;
-
-        assert!(sysroot_was_built(&stderr, TARGET));
-
-        project.cargo_toml(
+        project.xargo_toml(
             r#"
-[profile.release]
-lto = true
+[dependencies.alloc]
 "#,
         )?;
-
-        let stderr = project.build_and_get_stderr(Some(TARGET))?;
-
-        assert!(!sysroot_was_built(&stderr, TARGET));
+        project.build(TARGET)?;
+        assert!(exists("core", TARGET)?);
+        assert!(exists("alloc", TARGET)?);
 
         Ok(())
     }
@@ -622,24 +751,39 @@ lto = true
     run!()
 }
 
-/// Modifying RUSTFLAGS should trigger a rebuild of the sysroot
+/// Changing the features of `compiler_builtins` should trigger a rebuild of
+/// the sysroot
 #[test]
-fn rustflags_changed() {
+fn compiler_builtins_features_changed() {
     fn run() -> Result<()> {
-        const TARGET: &'static str = "thumbv6m-rustflags_changed-eabi";
+        const TARGET: &str = "thumbv6m-compiler_builtins_features_changed-eabi";
 
         let project = Project::new(TARGET)?;
+        project.xargo_toml(
+            r#"
+[dependencies.core]
+stage = 0
+
+[dependencies.compiler_builtins]
+features = ["mem"]
+stage = 1
+"#,
+        )?;
         let stderr = project.build_and_get_stderr(Some(TARGET))?;
 
         assert!(sysroot_was_built(&stderr, TARGET));
+        assert!(exists("compiler_builtins", TARGET)?);
 
-        project.config(
+        project.xargo_toml(
             r#"
-[build]
-rustflags = ["--cfg", "xargo"]
+[dependencies.core]
+stage = 0
+
+[dependencies.compiler_builtins]
+default-features = false
+stage = 1
 "#,
         )?;
-
         let stderr = project.build_and_get_stderr(Some(TARGET))?;
 
         assert!(sysroot_was_built(&stderr, TARGET));
@@ -650,28 +794,27 @@ rustflags = ["--cfg", "xargo"]
     run!()
 }
 
-/// Check that RUSTFLAGS are passed to all `rustc`s, and that they can contain spaces.
+/// Test that `std` is left out of the sysroot of a target without an OS
 #[test]
-fn rustflags() {
+fn no_os_skips_std() {
     fn run() -> Result<()> {
-        const TARGET: &'static str = "thumbv6m-rustflags-eabi";
+        const TARGET: &str = "thumbv6m-no_os_skips_std-eabi";
 
         let project = Project::new(TARGET)?;
-
-        project.config(
+        project.xargo_toml(
             r#"
-[build]
-rustflags = ["--cfg", 'xargo="y e s"']
+[dependencies.std]
+[dependencies.alloc]
 "#,
         )?;
-
         let stderr = project.build_and_get_stderr(Some(TARGET))?;
-
         assert!(
-            stderr.contains(r#"'xargo="y e s"'"#),
+            stderr.contains("not building `std`"),
             "unexpected stderr:\n{}",
             stderr
         );
+        assert!(exists("alloc", TARGET)?);
+        assert!(!exists("libstd", TARGET)?);
 
         Ok(())
     }
@@ -679,57 +822,56 @@ rustflags = ["--cfg", 'xargo="y e s"']
     run!()
 }
 
-/// Check that `-C panic=abort` is passed to `rustc` when `panic = "abort"` is
-/// set in `profile.release`
+/// Test that `xargo build` can be executed from outside project dir
 #[test]
-fn panic_abort() {
+fn build_outside_project_dir() {
     fn run() -> Result<()> {
-        const TARGET: &'static str = "thumbv6m-panic_abort-eabi";
+        // need this exact target name to get the right gcc flags
+        const TARGET: &'static str = "thumbv6m-workdir-eabi";
 
         let project = Project::new(TARGET)?;
-
-        project.cargo_toml(
+        project.xargo_toml(
             r#"
-[profile.release]
-panic = "abort"
+[dependencies.alloc]
 "#,
         )?;
+        // This `workdir` is from where `xargo build` is invoked. It is different from the project dir.
+        let workdir =
+            TempDir::new("xargo_workdir").chain_err(|| "couldn't create a temporary directory")?;
 
-        let stderr = project.build_and_get_stderr(Some(TARGET))?;
-
-        assert!(stderr
-            .lines()
-            .filter(|l| !l.starts_with("+") && l.contains("--release"))
-            .all(|l| l.contains("-C") && l.contains("panic=abort")));
+        project.build_from_workdir(TARGET, &workdir.path())?;
 
         Ok(())
     }
-
-    run!()
+    let r = run();
+    assert!(r.is_ok());
 }
 
-/// Check that adding linker arguments doesn't trigger a sysroot rebuild
+/// Test that `RUST_TARGET_PATH` can hold several directories
 #[test]
-fn link_arg() {
+fn rust_target_path_list() {
     fn run() -> Result<()> {
-        const TARGET: &'static str = "thumbv6m-link_arg-eabi";
+        const TARGET: &str = "thumbv6m-rust_target_path_list-eabi";
 
         let project = Project::new(TARGET)?;
+        let workdir =
+            TempDir::new("xargo_workdir").chain_err(|| "couldn't create a temporary directory")?;
 
-        let stderr = project.build_and_get_stderr(Some(TARGET))?;
-
-        assert!(sysroot_was_built(&stderr, TARGET));
-
-        project.config(
-            r#"
-[target.__link_arg]
-rustflags = ["-C", "link-arg=-lfoo"]
-"#,
-        )?;
-
-        let stderr = project.build_and_get_stderr(Some(TARGET))?;
+        // The empty entry must be skipped, not resolved against `workdir`
+        let paths = env::join_paths(&[
+            PathBuf::new(),
+            workdir.path().to_path_buf(),
+            project.td.path().to_path_buf(),
+        ])
+        .chain_err(|| "couldn't join paths")?;
 
-        assert!(!sysroot_was_built(&stderr, TARGET));
+        xargo()?
+            .env("RUST_TARGET_PATH", paths)
+            .args(["build", "--target", TARGET, "--manifest-path"])
+            .arg(project.td.path().join("Cargo.toml"))
+            .current_dir(workdir.path())
+            .run_and_get_stderr()?;
+        assert!(exists("core", TARGET)?);
 
         Ok(())
     }
@@ -737,44 +879,3079 @@ rustflags = ["-C", "link-arg=-lfoo"]
     run!()
 }
 
-/// The sysroot should be rebuilt if the target specification changed
+/// Check that the target specification in the project comes before the one
+/// in `RUST_TARGET_PATH`, which is used when the project has none
 #[test]
-fn specification_changed() {
+fn target_spec_precedence() {
     fn run() -> Result<()> {
-        const JSON: &'static str = r#"
-{
-    "arch": "arm",
-    "data-layout": "e-m:e-p:32:32-i64:64-v128:64:128-a:0:32-n32-S64",
-    "linker-flavor": "gcc",
-    "llvm-target": "thumbv6m-none-eabi",
-    "max-atomic-width": 0,
-    "os": "none",
-    "panic-strategy": "abort",
-    "target-c-int-width": "32",
-    "target-endian": "little",
-    "target-pointer-width": "32"
-}
-"#;
-        const TARGET: &'static str = "thumbv6m-specification_changed-eabi";
+        const TARGET: &str = "thumbv6m-target_spec_precedence-eabi";
 
         let project = Project::new(TARGET)?;
+        let file = format!("{}.json", TARGET);
+        let specs = project.td.path().join("specs");
+        mkdir(&specs)?;
+        fs::copy(project.td.path().join(&file), specs.join(&file))
+            .chain_err(|| "couldn't copy the target specification")?;
+
+        let spec_path = || -> Result<String> {
+            let out = xargo()?
+                .args(["info", "--target", TARGET, "--format", "json"])
+                .env("RUST_TARGET_PATH", &specs)
+                .current_dir(project.td.path())
+                .output()
+                .chain_err(|| "couldn't execute `xargo info`")?;
+            assert!(out.status.success());
+            let info: serde_json::Value = serde_json::from_slice(&out.stdout)
+                .chain_err(|| "`xargo info` didn't print JSON")?;
+
+            Ok(info["spec_path"].as_str().unwrap_or("").to_owned())
+        };
+
+        let root = fs::canonicalize(project.td.path()).chain_err(|| "couldn't canonicalize")?;
+        assert_eq!(Path::new(&spec_path()?).parent(), Some(&*root));
+
+        fs::remove_file(project.td.path().join(&file))
+            .chain_err(|| "couldn't remove the target specification")?;
+        assert_eq!(
+            Path::new(&spec_path()?).parent(),
+            Some(&*root.join("specs"))
+        );
 
-        let stderr = project.build_and_get_stderr(Some(TARGET))?;
+        Ok(())
+    }
 
-        assert!(sysroot_was_built(&stderr, TARGET));
+    run!()
+}
 
-        write(
-            &project
-                .td
-                .path()
-                .join("thumbv6m-specification_changed-eabi.json"),
-            false,
-            JSON,
-        )?;
+/// Check that the name of a target specification doesn't need to match the
+/// case of the triple where file systems ignore case
+#[cfg(any(windows, target_os = "macos"))]
+#[test]
+fn case_insensitive_spec() {
+    fn run() -> Result<()> {
+        const TARGET: &str = "thumbv6m-case_insensitive_spec-eabi";
 
-        let stderr = project.build_and_get_stderr(Some(TARGET))?;
+        let project = Project::new(TARGET)?;
+        let json = project.td.path().join(format!("{}.json", TARGET));
+        fs::rename(
+            &json,
+            json.with_file_name(format!("{}.json", TARGET.to_uppercase())),
+        )
+        .chain_err(|| format!("couldn't rename {}", json.display()))?;
 
-        assert!(sysroot_was_built(&stderr, TARGET));
+        project.print_hash(&[])?;
+
+        Ok(())
+    }
+
+    run!()
+}
+
+/// Check that an unknown target is reported along with where its
+/// specification was searched
+#[test]
+fn target_not_found() {
+    fn run() -> Result<()> {
+        const TARGET: &str = "thumbv6m-target_not_found-eabi";
+
+        let project = Project::new(TARGET)?;
+        fs::remove_file(project.td.path().join(format!("{}.json", TARGET)))
+            .chain_err(|| "couldn't remove the target specification")?;
+        let specs = project.td.path().join("specs");
+        mkdir(&specs)?;
+
+        let out = xargo()?
+            .args(["build", "--target", TARGET])
+            .env("RUST_TARGET_PATH", &specs)
+            .current_dir(project.td.path())
+            .output()
+            .chain_err(|| "couldn't execute `xargo build`")?;
+        let stderr = String::from_utf8_lossy(&out.stderr);
+
+        assert!(!out.status.success());
+        assert!(stderr.contains("is not a builtin target"), "{}", stderr);
+        assert!(stderr.contains(&*specs.display().to_string()), "{}", stderr);
+
+        Ok(())
+    }
+
+    run!()
+}
+
+/// Check that a `data-layout` with other pointers than `target-pointer-width`
+/// is warned about
+#[test]
+fn pointer_width_mismatch() {
+    fn run() -> Result<()> {
+        const TARGET: &str = "thumbv6m-pointer_width_mismatch-eabi";
+
+        let project = Project::new(TARGET)?;
+        let json = project.td.path().join(format!("{}.json", TARGET));
+        let spec = fs::read_to_string(&json).chain_err(|| "couldn't read the specification")?;
+        write(&json, false, &spec.replace("p:32:32", "p:64:64"))?;
+
+        let out = project
+            .print_hash_command(&[])?
+            .output()
+            .chain_err(|| "couldn't execute `xargo --print-hash`")?;
+        let stderr = String::from_utf8_lossy(&out.stderr);
+
+        assert!(
+            stderr.contains("`data-layout` makes pointers 64 bits wide"),
+            "{}",
+            stderr
+        );
+        assert!(
+            !stderr.contains("`target-pointer-width` makes"),
+            "{}",
+            stderr
+        );
+
+        Ok(())
+    }
+
+    run!()
+}
+
+/// Check that the default sysroot of a WebAssembly target has `alloc`, unless
+/// `Xargo.toml` has dependencies
+#[test]
+fn wasm_default_crates() {
+    fn run() -> Result<()> {
+        const TARGET: &str = "wasm32-unknown-unknown";
+
+        let project = Project::new(TARGET)?;
+        let print_hash = || project.print_hash_command(&["-v"])?.run_and_get_stderr();
+
+        let stderr = print_hash()?;
+        assert!(stderr.contains("dependency `core`"), "{}", stderr);
+        assert!(stderr.contains("dependency `alloc`"), "{}", stderr);
+
+        project.xargo_toml("[dependencies.core]\n")?;
+        let stderr = print_hash()?;
+        assert!(!stderr.contains("dependency `alloc`"), "{}", stderr);
+
+        Ok(())
+    }
+
+    run!()
+}
+
+/// Check that `std` is left out of the sysroot of `wasm32-unknown-unknown`,
+/// which has no operating system, and that `xargo info` says so too
+#[test]
+fn wasm_no_std() {
+    fn run() -> Result<()> {
+        const TARGET: &str = "wasm32-unknown-unknown";
+
+        let project = Project::new(TARGET)?;
+        project.xargo_toml("[dependencies.std]\n")?;
+
+        let stderr = project.print_hash_command(&[])?.run_and_get_stderr()?;
+        assert!(
+            stderr.contains(
+                "not building `std` because wasm32-unknown-unknown doesn't support `std`"
+            ),
+            "{}",
+            stderr
+        );
+
+        let out = xargo()?
+            .args(["info", "--target", TARGET, "--format", "json"])
+            .current_dir(project.td.path())
+            .output()
+            .chain_err(|| "couldn't execute `xargo info`")?;
+        assert!(out.status.success());
+        let info: serde_json::Value =
+            serde_json::from_slice(&out.stdout).chain_err(|| "`xargo info` didn't print JSON")?;
+        assert_eq!(info["supports_std"], false);
+
+        Ok(())
+    }
+
+    run!()
+}
+
+/// Check that a target specification named after a builtin target is ignored
+/// with a warning
+#[test]
+fn builtin_shadowed() {
+    fn run() -> Result<()> {
+        const TARGET: &str = "thumbv7em-none-eabi";
+
+        let project = Project::new(TARGET)?;
+        let stderr = project.build_and_get_stderr(Some(TARGET))?;
+
+        assert!(stderr.contains(&format!(
+            "is ignored because `{}` is a builtin target",
+            TARGET
+        )));
+        assert!(exists("core", TARGET)?);
+
+        Ok(())
+    }
+
+    run!()
+}
+
+/// Check that `--force-custom` loads a target specification named after a
+/// builtin target, and that it's an error without one
+#[test]
+fn force_custom() {
+    fn run() -> Result<()> {
+        const TARGET: &str = "thumbv7em-none-eabi";
+
+        let project = Project::new(TARGET)?;
+        let print_hash = || {
+            project
+                .print_hash_command(&["-v", "--force-custom"])?
+                .output()
+                .chain_err(|| "couldn't execute `xargo --print-hash`")
+        };
+
+        let out = print_hash()?;
+        let stderr = String::from_utf8_lossy(&out.stderr);
+        assert!(out.status.success(), "{}", stderr);
+        assert!(stderr.contains("target specification"), "{}", stderr);
+        assert!(!stderr.contains("is ignored"), "{}", stderr);
+
+        fs::remove_file(project.td.path().join(format!("{}.json", TARGET)))
+            .chain_err(|| "couldn't remove the target specification")?;
+        let out = print_hash()?;
+        let stderr = String::from_utf8_lossy(&out.stderr);
+        assert!(!out.status.success());
+        assert!(stderr.contains("--force-custom"), "{}", stderr);
+
+        Ok(())
+    }
+
+    run!()
+}
+
+/// Test `xargo build` with more than one `--target`
+#[test]
+fn multiple_targets() {
+    fn run() -> Result<()> {
+        const TARGET: &str = "thumbv6m-multiple_targets-eabi";
+        const TARGET2: &str = "thumbv6m-multiple_targets2-eabi";
+
+        let project = Project::new(TARGET)?;
+        let td = project.td.path();
+        fs::copy(
+            td.join(format!("{}.json", TARGET)),
+            td.join(format!("{}.json", TARGET2)),
+        )
+        .chain_err(|| "couldn't copy the target specification")?;
+
+        let stderr = xargo()?
+            .args(["build", "--target", TARGET, "--target", TARGET2, "-v"])
+            .current_dir(td)
+            .run_and_get_stderr()?;
+        assert!(sysroot_was_built(&stderr, TARGET));
+        assert!(sysroot_was_built(&stderr, TARGET2));
+        assert!(exists("core", TARGET)?);
+        assert!(exists("core", TARGET2)?);
+
+        cleanup(TARGET2)
+    }
+
+    run!()
+}
+
+/// Test building the sysroots of several targets in parallel
+#[test]
+fn multiple_targets_jobs() {
+    fn run() -> Result<()> {
+        const TARGET: &str = "thumbv6m-multiple_targets_jobs-eabi";
+        const TARGET2: &str = "thumbv6m-multiple_targets_jobs2-eabi";
+
+        let project = Project::new(TARGET)?;
+        let td = project.td.path();
+        fs::copy(
+            td.join(format!("{}.json", TARGET)),
+            td.join(format!("{}.json", TARGET2)),
+        )
+        .chain_err(|| "couldn't copy the target specification")?;
+
+        let stderr = xargo()?
+            .args(["build", "--target", TARGET, "--target", TARGET2, "-j", "2"])
+            .current_dir(td)
+            .run_and_get_stderr()?;
+        assert!(stderr.contains(&format!("xargo: sysroot for target {}", TARGET)));
+        assert!(stderr.contains(&format!("xargo: sysroot for target {}", TARGET2)));
+        assert!(exists("core", TARGET)?);
+        assert!(exists("core", TARGET2)?);
+
+        cleanup(TARGET2)
+    }
+
+    run!()
+}
+
+/// Check that the sysroots built in parallel get all of Xargo's flags, so
+/// they're the ones Cargo is then run with, and aren't built again, not even
+/// with `--force-rebuild`
+#[test]
+fn multiple_targets_jobs_flags() {
+    fn run() -> Result<()> {
+        const TARGET: &str = "thumbv6m-multiple_targets_jobs_flags-eabi";
+        const TARGET2: &str = "thumbv6m-multiple_targets_jobs_flags2-eabi";
+
+        let project = Project::new(TARGET)?;
+        let td = project.td.path();
+        fs::copy(
+            td.join(format!("{}.json", TARGET)),
+            td.join(format!("{}.json", TARGET2)),
+        )
+        .chain_err(|| "couldn't copy the target specification")?;
+
+        let build = |flags: &[&str]| -> Result<()> {
+            let stderr = xargo()?
+                .args(["build", "-v", "-j", "2"])
+                .args(flags)
+                .args(["--target", TARGET, "--target", TARGET2])
+                .current_dir(td)
+                .run_and_get_stderr()?;
+            for target in [TARGET, TARGET2] {
+                let builds = stderr
+                    .lines()
+                    .filter(|l| sysroot_was_built(l, target))
+                    .count();
+                assert_eq!(builds, 1, "{}", stderr);
+            }
+
+            Ok(())
+        };
+
+        build(&["--reproducible", "--strict-src"])?;
+        build(&["--reproducible", "--strict-src", "--force-rebuild"])?;
+
+        cleanup(TARGET2)
+    }
+
+    run!()
+}
+
+/// Check that `xargo clean --sysroot` only removes the stale sysroots of the
+/// project's triples, and `--all` all of them, but never anything Xargo
+/// didn't build
+#[test]
+fn clean_sysroot() {
+    fn run() -> Result<()> {
+        const TARGET: &str = "thumbv6m-clean_sysroot-eabi";
+
+        let project = Project::new(TARGET)?;
+        let home = TempDir::new("xargo").chain_err(|| "couldn't create a temporary directory")?;
+        let rustlib = home.path().join("lib/rustlib");
+        let xargo_home = || -> Result<Command> {
+            let mut cmd = xargo()?;
+            cmd.env("XARGO_HOME", home.path())
+                .current_dir(project.td.path());
+            Ok(cmd)
+        };
+
+        xargo_home()?.args(["build", "--target", TARGET]).run()?;
+
+        fs::create_dir_all(rustlib.join("stale"))
+            .chain_err(|| "couldn't create a stale sysroot")?;
+        write(&rustlib.join("stale/.sentinel"), false, "")?;
+        fs::create_dir_all(rustlib.join("foreign"))
+            .chain_err(|| "couldn't create a foreign directory")?;
+
+        xargo_home()?
+            .args(["clean", "--sysroot", "--target", TARGET])
+            .run()?;
+        assert!(rustlib.join(TARGET).exists());
+        // Not a triple of this project, so it may be another project's
+        assert!(rustlib.join("stale").exists());
+        assert!(rustlib.join("foreign").exists());
+
+        write(&rustlib.join(TARGET).join(".hash"), false, "0")?;
+        xargo_home()?
+            .args(["clean", "--sysroot", "--target", TARGET])
+            .run()?;
+        assert!(!rustlib.join(TARGET).exists());
+        assert!(rustlib.join("stale").exists());
+
+        xargo_home()?.args(["clean", "--sysroot", "--all"]).run()?;
+        assert!(!rustlib.join("stale").exists());
+        assert!(rustlib.join("foreign").exists());
+
+        Ok(())
+    }
+
+    run!()
+}
+
+/// Check that `xargo clean --sysroot` keeps the sysroots of any target and
+/// profile of the project, and refuses to guess outside of one
+#[test]
+fn clean_sysroot_configurations() {
+    fn run() -> Result<()> {
+        const TARGET: &str = "thumbv6m-clean_sysroot_configurations-eabi";
+
+        let project = Project::new(TARGET)?;
+        project.xargo_toml("[build]\nper-profile = true\n")?;
+        let home = TempDir::new("xargo").chain_err(|| "couldn't create a temporary directory")?;
+        let xargo_home = |dir: &Path| -> Result<Command> {
+            let mut cmd = xargo()?;
+            cmd.env("XARGO_HOME", home.path()).current_dir(dir);
+            Ok(cmd)
+        };
+        let dev = home.path().join("profile/dev/lib/rustlib").join(TARGET);
+        let release = home.path().join("lib/rustlib").join(TARGET);
+
+        let td = project.td.path();
+        xargo_home(td)?.args(["build", "--target", TARGET]).run()?;
+        xargo_home(td)?
+            .args(["build", "--release", "--target", TARGET])
+            .run()?;
+        assert!(dev.exists());
+        assert!(release.exists());
+
+        xargo_home(td)?.args(["clean", "--sysroot"]).run()?;
+        assert!(dev.exists());
+        assert!(release.exists());
+
+        let elsewhere =
+            TempDir::new("xargo").chain_err(|| "couldn't create a temporary directory")?;
+        let out = xargo_home(elsewhere.path())?
+            .args(["clean", "--sysroot"])
+            .output()
+            .chain_err(|| "couldn't execute `xargo clean`")?;
+        assert!(!out.status.success());
+        assert!(String::from_utf8_lossy(&out.stderr).contains("--all"));
+        assert!(dev.exists());
+        assert!(release.exists());
+
+        Ok(())
+    }
+
+    run!()
+}
+
+/// Check that `xargo clean --list` lists the sysroots Xargo built, without
+/// removing them
+#[test]
+fn clean_list() {
+    fn run() -> Result<()> {
+        const TARGET: &str = "thumbv6m-clean_list-eabi";
+
+        let project = Project::new(TARGET)?;
+        let home = TempDir::new("xargo").chain_err(|| "couldn't create a temporary directory")?;
+        let rustlib = home.path().join("lib/rustlib");
+        let xargo_stdout = |args: &[&str]| -> Result<String> {
+            xargo()?
+                .args(args)
+                .env("XARGO_HOME", home.path())
+                .current_dir(project.td.path())
+                .run_and_get_stdout()
+        };
+
+        xargo_stdout(&["build", "--target", TARGET])?;
+        fs::create_dir_all(rustlib.join("foreign"))
+            .chain_err(|| "couldn't create a foreign directory")?;
+
+        // A sysroot found up to date is stamped, but its `.hash` stays as is
+        let hfile = rustlib.join(TARGET).join(".hash");
+        let hash = fs::read_to_string(&hfile).chain_err(|| "couldn't read .hash")?;
+        write(&rustlib.join(TARGET).join(".last-used"), false, "1")?;
+        xargo_stdout(&["build", "--target", TARGET])?;
+        assert_eq!(
+            fs::read_to_string(&hfile).chain_err(|| "couldn't read .hash")?,
+            hash
+        );
+
+        let json = xargo_stdout(&["clean", "--list", "--format", "json"])?;
+        let list: serde_json::Value =
+            serde_json::from_str(&json).chain_err(|| "`xargo clean --list` didn't print JSON")?;
+        let list = list
+            .as_array()
+            .ok_or("`xargo clean --list` didn't print an array")?;
+        let sysroot = list
+            .iter()
+            .find(|s| s["triple"] == TARGET)
+            .ok_or("the sysroot is not listed")?;
+
+        assert_eq!(
+            sysroot["hash"].as_str(),
+            Some(
+                project
+                    .print_hash_command(&[])?
+                    .env("XARGO_HOME", home.path())
+                    .run_and_get_stdout()?
+                    .trim()
+            )
+        );
+        assert!(sysroot["rustc_version"].is_string());
+        assert!(sysroot["size"].as_u64().unwrap_or(0) > 0);
+        assert!(sysroot["last_used"].as_u64().unwrap_or(0) > 1);
+        assert!(!list.iter().any(|s| s["triple"] == "foreign"));
+        assert!(rustlib.join(TARGET).exists());
+
+        let table = xargo_stdout(&["clean", "--list"])?;
+        assert!(table.starts_with("TRIPLE"), "{}", table);
+        assert!(table.contains(TARGET), "{}", table);
+
+        Ok(())
+    }
+
+    run!()
+}
+
+/// Check that `CARGO_TARGET_DIR` applies to the crate, but not to the sysroot
+#[test]
+fn cargo_target_dir() {
+    fn run() -> Result<()> {
+        const TARGET: &str = "thumbv6m-cargo_target_dir-eabi";
+
+        let project = Project::new(TARGET)?;
+        let target_dir = project.td.path().join("shared");
+        xargo()?
+            .args(["build", "--target", TARGET])
+            .env("CARGO_TARGET_DIR", &target_dir)
+            .current_dir(project.td.path())
+            .run()?;
+
+        assert!(target_dir.join(TARGET).join("debug").is_dir());
+        assert!(!project.td.path().join("target").exists());
+        assert!(exists("core", TARGET)?);
+
+        Ok(())
+    }
+
+    run!()
+}
+
+/// Test `xargo doc`
+#[test]
+fn doc() {
+    fn run() -> Result<()> {
+        const TARGET: &'static str = "thumbv6m-doc-eabi";
+
+        let project = Project::new(TARGET)?;
+        project.doc(TARGET)?;
+        assert!(exists("core", TARGET)?);
+
+        Ok(())
+    }
+
+    run!()
+}
+
+/// Test `xargo check`
+#[test]
+fn check() {
+    fn run() -> Result<()> {
+        const TARGET: &str = "thumbv6m-check-eabi";
+
+        let project = Project::new(TARGET)?;
+        project.check(TARGET)?;
+        assert!(exists("core", TARGET)?);
+
+        // The application is only checked, not compiled
+        let deps = project.td.path().join("target").join(TARGET).join("debug");
+        let rlib = format!("lib{}.rlib", TARGET.replace('-', "_"));
+        assert!(!deps.join(rlib).exists());
+
+        Ok(())
+    }
+
+    run!()
+}
+
+/// Check that `xargo --print-sysroot` builds the sysroot and prints its path
+#[test]
+fn print_sysroot() {
+    fn run() -> Result<()> {
+        const TARGET: &str = "thumbv6m-print_sysroot-eabi";
+
+        let project = Project::new(TARGET)?;
+        let stdout = project.xargo_stdout(&["--print-sysroot", "--target", TARGET])?;
+        let home = home()?;
+        assert_eq!(
+            Path::new(stdout.trim()),
+            home.canonicalize().unwrap_or(home)
+        );
+        assert!(exists("core", TARGET)?);
+
+        Ok(())
+    }
+
+    run!()
+}
+
+/// Check that target specifications are found in `build.target-dir-specs`,
+/// and that conflicting ones are rejected
+#[test]
+fn target_dir_specs() {
+    fn run() -> Result<()> {
+        const TARGET: &str = "thumbv6m-target_dir_specs-eabi";
+
+        let project = Project::new(TARGET)?;
+        let file = format!("{}.json", TARGET);
+        for dir in &["boards", "more-boards"] {
+            mkdir(&project.td.path().join(dir))?;
+        }
+        fs::rename(
+            project.td.path().join(&file),
+            project.td.path().join("boards").join(&file),
+        )
+        .chain_err(|| "couldn't move the target specification")?;
+        project.xargo_toml(
+            r#"
+[build]
+target-dir-specs = ["boards", "more-boards"]
+"#,
+        )?;
+        project.build(TARGET)?;
+        assert!(exists("core", TARGET)?);
+
+        write(
+            &project.td.path().join("more-boards").join(&file),
+            false,
+            "{}",
+        )?;
+        let out = xargo()?
+            .args(["build", "--target", TARGET])
+            .current_dir(project.td.path())
+            .output()
+            .chain_err(|| "couldn't execute `xargo build`")?;
+        let stderr = String::from_utf8_lossy(&out.stderr);
+
+        assert!(!out.status.success());
+        assert!(
+            stderr.contains("found conflicting target specifications"),
+            "unexpected stderr:\n{}",
+            stderr
+        );
+
+        Ok(())
+    }
+
+    run!()
+}
+
+/// Check that a target specification can be given inline in `Cargo.toml`
+#[test]
+fn inline_target_spec() {
+    fn run() -> Result<()> {
+        const TARGET: &str = "thumbv6m-inline_target_spec-eabi";
+
+        let project = Project::new(TARGET)?;
+        fs::remove_file(project.td.path().join(format!("{}.json", TARGET)))
+            .chain_err(|| "couldn't remove the target specification")?;
+        project.cargo_toml(&format!(
+            r#"
+[package.metadata.xargo.target.{}]
+arch = "arm"
+data-layout = "e-m:e-p:32:32-i64:64-v128:64:128-a:0:32-n32-S64"
+linker-flavor = "gcc"
+llvm-target = "thumbv6m-none-eabi"
+max-atomic-width = 0
+os = "none"
+target-c-int-width = "32"
+target-endian = "little"
+target-pointer-width = "32"
+"#,
+            TARGET
+        ))?;
+        project.build(TARGET)?;
+        assert!(exists("core", TARGET)?);
+
+        Ok(())
+    }
+
+    run!()
+}
+
+/// Check that `--sysroot-env-file` writes the path of the sysroot to a file
+#[test]
+fn sysroot_env_file() {
+    fn run() -> Result<()> {
+        const TARGET: &str = "thumbv6m-sysroot_env_file-eabi";
+
+        let project = Project::new(TARGET)?;
+        let file = project.td.path().join("sysroot.env");
+        xargo()?
+            .args(["build", "--target", TARGET, "--sysroot-env-file"])
+            .arg(&file)
+            .current_dir(project.td.path())
+            .run()?;
+
+        let home = home()?;
+        let home = home.canonicalize().unwrap_or(home);
+        let env = fs::read_to_string(&file).chain_err(|| "couldn't read the env file")?;
+        assert!(env.contains(&format!("XARGO_SYSROOT={}\n", home.display())));
+        assert!(env.contains("RUSTFLAGS="));
+
+        Ok(())
+    }
+
+    run!()
+}
+
+/// Check that `xargo::build_sysroot` builds the same sysroot as the CLI,
+/// without changing the environment of the program
+#[test]
+fn build_sysroot() {
+    fn run() -> Result<()> {
+        const TARGET: &str = "thumbv6m-build_sysroot-eabi";
+
+        let project = Project::new(TARGET)?;
+        let config = xargo::Config::new().manifest_path(project.td.path().join("Cargo.toml"));
+        let vars = || ["RUSTC", "CARGO", "RUSTDOC"].map(env::var_os);
+        let before = vars();
+        let sysroot = xargo::build_sysroot(TARGET, &config, false).map_err(|e| e.to_string())?;
+
+        assert_eq!(sysroot.path(), home()?);
+        assert!(exists("core", TARGET)?);
+        assert_eq!(vars(), before);
+
+        Ok(())
+    }
+
+    run!()
+}
+
+/// Check that `xargo::is_fresh` tells whether the sysroot would be rebuilt
+#[test]
+fn is_fresh() {
+    fn run() -> Result<()> {
+        const TARGET: &str = "thumbv6m-is_fresh-eabi";
+
+        let project = Project::new(TARGET)?;
+        let config = xargo::Config::new().manifest_path(project.td.path().join("Cargo.toml"));
+        let is_fresh = || xargo::is_fresh(TARGET, &config, false).map_err(|e| e.to_string());
+
+        assert!(!is_fresh()?);
+        assert!(!exists("core", TARGET)?);
+
+        xargo::build_sysroot(TARGET, &config, false).map_err(|e| e.to_string())?;
+        assert!(is_fresh()?);
+
+        project.xargo_toml("[dependencies.alloc]\n")?;
+        assert!(!is_fresh()?);
+
+        Ok(())
+    }
+
+    run!()
+}
+
+/// Check that `xargo::commit_hash` is the commit of `rustc -vV`
+#[test]
+fn commit_hash() {
+    fn run() -> Result<()> {
+        let hash = xargo::commit_hash(xargo::Verbosity::Normal).map_err(|e| e.to_string())?;
+
+        assert_eq!(hash, rustc_version::version_meta().unwrap().commit_hash);
+
+        Ok(())
+    }
+
+    run!()
+}
+
+/// Check that `CARGO_MESSAGE_FORMAT=json` makes the sysroot build emit JSON
+/// messages on stdout
+#[test]
+fn message_format_json() {
+    fn run() -> Result<()> {
+        const TARGET: &str = "thumbv6m-message_format_json-eabi";
+
+        let project = Project::new(TARGET)?;
+        let stdout = xargo()?
+            .args(["build", "--target", TARGET])
+            .env("CARGO_MESSAGE_FORMAT", "json")
+            .current_dir(project.td.path())
+            .run_and_get_stdout()?;
+        assert!(stdout
+            .lines()
+            .filter(|l| !l.is_empty())
+            .all(|l| l.starts_with('{')));
+        assert!(stdout
+            .lines()
+            .any(|l| l.contains("\"reason\":\"compiler-artifact\"") && l.contains("\"core\"")));
+
+        Ok(())
+    }
+
+    run!()
+}
+
+/// Check that `xargo --print-hash` prints the hash of the sysroot, without
+/// building it
+#[test]
+fn print_hash() {
+    fn run() -> Result<()> {
+        const TARGET: &str = "thumbv6m-print_hash-eabi";
+
+        let project = Project::new(TARGET)?;
+        let print_hash = |rustflags: &str| -> Result<String> {
+            project
+                .print_hash_command(&[])?
+                .env("RUSTFLAGS", rustflags)
+                .run_and_get_stdout()
+        };
+
+        let hash = print_hash("")?;
+        assert_eq!(hash.trim().len(), 16);
+        assert_eq!(print_hash("")?, hash);
+        assert_ne!(print_hash("-C opt-level=s")?, hash);
+        assert!(!home()?.join("lib/rustlib").join(TARGET).exists());
+
+        Ok(())
+    }
+
+    run!()
+}
+
+/// Check that `--deny-sysroot-warnings` and `build.deny-warnings` pass
+/// `-D warnings` to the sysroot build, and change its hash
+#[test]
+fn deny_sysroot_warnings() {
+    fn run() -> Result<()> {
+        const TARGET: &str = "thumbv6m-deny_sysroot_warnings-eabi";
+
+        let project = Project::new(TARGET)?;
+        let hash = project.print_hash(&[])?;
+        let denied = project.print_hash(&["--deny-sysroot-warnings"])?;
+        assert_ne!(denied, hash);
+
+        project.xargo_toml("[build]\ndeny-warnings = true\n")?;
+        assert_eq!(project.print_hash(&[])?, denied);
+
+        // The inputs of the hash go to stderr
+        let stderr = project.print_hash_command(&["-v"])?.run_and_get_stderr()?;
+        assert!(stderr.contains("-D warnings"), "{}", stderr);
+
+        Ok(())
+    }
+
+    run!()
+}
+
+/// Check that with `build.per-profile` the `dev` and `release` sysroots live
+/// side by side, and switching profiles doesn't rebuild either
+#[test]
+fn per_profile() {
+    fn run() -> Result<()> {
+        const TARGET: &str = "thumbv6m-per_profile-eabi";
+
+        let project = Project::new(TARGET)?;
+        project.xargo_toml("[build]\nper-profile = true\n")?;
+        let build = |release: bool| -> Result<String> {
+            let mut cmd = xargo()?;
+            cmd.args(["build", "-v", "--target", TARGET]);
+            if release {
+                cmd.arg("--release");
+            }
+
+            cmd.current_dir(project.td.path()).run_and_get_stderr()
+        };
+
+        let stderr = build(false)?;
+        assert!(sysroot_was_built(&stderr, TARGET));
+        assert!(stderr.contains("\"--profile\" \"dev\""), "{}", stderr);
+        assert!(home()?
+            .join("profile/dev/lib/rustlib")
+            .join(TARGET)
+            .join(".hash")
+            .is_file());
+
+        assert!(sysroot_was_built(&build(true)?, TARGET));
+        assert!(exists("core", TARGET)?);
+
+        assert!(!sysroot_was_built(&build(false)?, TARGET));
+        assert!(!sysroot_was_built(&build(true)?, TARGET));
+
+        Ok(())
+    }
+
+    run!()
+}
+
+/// Check that `build.jobs` and `XARGO_SYSROOT_JOBS` set the `-j` of the
+/// sysroot build only, and leave its hash alone
+#[test]
+fn sysroot_jobs() {
+    fn run() -> Result<()> {
+        const TARGET: &str = "thumbv6m-sysroot_jobs-eabi";
+
+        let project = Project::new(TARGET)?;
+        let dry_run = |jobs: Option<&str>| -> Result<std::process::Output> {
+            let mut cmd = xargo()?;
+            cmd.args(["build", "--dry-run", "-v", "-j", "8", "--target", TARGET]);
+            if let Some(jobs) = jobs {
+                cmd.env("XARGO_SYSROOT_JOBS", jobs);
+            }
+
+            cmd.current_dir(project.td.path())
+                .output()
+                .chain_err(|| "couldn't execute `xargo build`")
+        };
+        let stages = |out: &std::process::Output| -> Vec<String> {
+            String::from_utf8_lossy(&out.stdout)
+                .lines()
+                .filter(|l| l.contains("\"-p\""))
+                .map(String::from)
+                .collect()
+        };
+        let hash = |out: &std::process::Output| -> Option<String> {
+            String::from_utf8_lossy(&out.stdout)
+                .lines()
+                .find(|l| l.starts_with("hash: "))
+                .map(String::from)
+        };
+
+        let default = dry_run(None)?;
+        assert!(default.status.success());
+        assert!(!stages(&default).is_empty());
+        assert!(stages(&default).iter().all(|l| !l.contains("\"-j\"")));
+
+        project.xargo_toml("[build]\njobs = 2\n")?;
+        let configured = dry_run(None)?;
+        assert!(configured.status.success());
+        assert!(stages(&configured)
+            .iter()
+            .all(|l| l.contains("\"-j\" \"2\"")));
+        assert_eq!(hash(&configured), hash(&default));
+
+        let overridden = dry_run(Some("3"))?;
+        assert!(stages(&overridden)
+            .iter()
+            .all(|l| l.contains("\"-j\" \"3\"")));
+
+        project.xargo_toml("[build]\njobs = 0\n")?;
+        let invalid = dry_run(None)?;
+        assert!(!invalid.status.success());
+        let stderr = String::from_utf8_lossy(&invalid.stderr);
+        assert!(
+            stderr.contains("`build.jobs` must be a positive integer"),
+            "{}",
+            stderr
+        );
+
+        Ok(())
+    }
+
+    run!()
+}
+
+/// Check that `build.codegen-units` and `--reproducible` go into the profile
+/// of the sysroot, and its hash
+#[test]
+fn sysroot_codegen_units() {
+    fn run() -> Result<()> {
+        const TARGET: &str = "thumbv6m-sysroot_codegen_units-eabi";
+
+        let project = Project::new(TARGET)?;
+        let print_hash = |args: &[&str]| -> Result<std::process::Output> {
+            project
+                .print_hash_command(&["-v"])?
+                .args(args)
+                .output()
+                .chain_err(|| "couldn't execute `xargo --print-hash`")
+        };
+
+        let default = print_hash(&[])?;
+        let reproducible = print_hash(&["--reproducible"])?;
+        assert!(reproducible.status.success());
+        assert_ne!(reproducible.stdout, default.stdout);
+        let stderr = String::from_utf8_lossy(&reproducible.stderr);
+        assert!(stderr.contains("codegen-units = 1"), "{}", stderr);
+        assert!(stderr.contains("incremental = false"), "{}", stderr);
+
+        project.xargo_toml("[build]\ncodegen-units = 4\n")?;
+        let four = print_hash(&["--reproducible"])?;
+        assert_ne!(four.stdout, reproducible.stdout);
+        assert!(String::from_utf8_lossy(&four.stderr).contains("codegen-units = 4"));
+
+        project.xargo_toml("[build]\ncodegen-units = 0\n")?;
+        let out = print_hash(&[])?;
+        assert!(!out.status.success());
+        assert!(String::from_utf8_lossy(&out.stderr)
+            .contains("`build.codegen-units` must be a positive integer"));
+
+        Ok(())
+    }
+
+    run!()
+}
+
+/// Check that `build.sanitizer` and `XARGO_SANITIZER` instrument the sysroot,
+/// which then gets another hash
+#[test]
+fn sysroot_sanitizer() {
+    fn run() -> Result<()> {
+        const TARGET: &str = "thumbv6m-sysroot_sanitizer-eabi";
+
+        let project = Project::new(TARGET)?;
+        let print_hash = |sanitizer: &str| -> Result<std::process::Output> {
+            project
+                .print_hash_command(&["-v"])?
+                .env("XARGO_SANITIZER", sanitizer)
+                .output()
+                .chain_err(|| "couldn't execute `xargo --print-hash`")
+        };
+
+        let plain = print_hash("")?;
+        let address = print_hash("address")?;
+        assert!(address.status.success());
+        assert_ne!(address.stdout, plain.stdout);
+        assert!(String::from_utf8_lossy(&address.stderr).contains("sanitizer=address"));
+
+        project.xargo_toml("[build]\nsanitizer = \"address\"\n")?;
+        assert_eq!(print_hash("")?.stdout, address.stdout);
+
+        let out = print_hash("adress")?;
+        assert!(!out.status.success());
+        assert!(String::from_utf8_lossy(&out.stderr).contains("unknown sanitizer `adress`"));
+
+        Ok(())
+    }
+
+    run!()
+}
+
+/// Check that `[features.<name>]` in `Xargo.toml` only applies, and changes
+/// the sysroot hash, when the feature of the crate is enabled
+#[test]
+fn sysroot_features() {
+    fn run() -> Result<()> {
+        const TARGET: &str = "thumbv6m-sysroot_features-eabi";
+
+        let project = Project::new(TARGET)?;
+        project.cargo_toml(
+            r#"
+[features]
+default = []
+defmt = []
+full = ["defmt"]
+"#,
+        )?;
+        project.xargo_toml(
+            r#"
+[dependencies.core]
+stage = 0
+
+[features.defmt.build.profile]
+opt-level = "s"
+"#,
+        )?;
+        let hash = project.print_hash(&[])?;
+        let defmt = project.print_hash(&["--features", "defmt"])?;
+        assert_ne!(defmt, hash);
+        assert_eq!(project.print_hash(&["--features=full"])?, defmt);
+        assert_eq!(project.print_hash(&["--all-features"])?, defmt);
+        assert_eq!(project.print_hash(&["--no-default-features"])?, hash);
+
+        Ok(())
+    }
+
+    run!()
+}
+
+/// Check that `xargo info --format json` describes the target and its
+/// sysroot, without building it
+#[test]
+fn info() {
+    fn run() -> Result<()> {
+        const TARGET: &str = "thumbv6m-info-eabi";
+
+        let project = Project::new(TARGET)?;
+
+        let json = project.xargo_stdout(&["info", "--target", TARGET, "--format", "json"])?;
+        let info: serde_json::Value =
+            serde_json::from_str(&json).chain_err(|| "`xargo info` didn't print JSON")?;
+        let field = |key| info[key].as_str().unwrap_or("").to_owned();
+
+        assert_eq!(field("triple"), TARGET);
+        assert_eq!(field("kind"), "custom");
+        assert!(field("spec_path").ends_with(&format!("{}.json", TARGET)));
+        assert_eq!(info["supports_std"], false);
+        assert_eq!(field("hash"), project.print_hash(&[])?.trim());
+        assert!(!field("rustc_version").is_empty());
+        assert!(!home()?.join("lib/rustlib").join(TARGET).exists());
+
+        Ok(())
+    }
+
+    run!()
+}
+
+/// Check that Xargo exits with the exit code of Cargo, 101 for a compile
+/// error, rather than 1
+#[test]
+fn cargo_exit_code() {
+    fn run() -> Result<()> {
+        const TARGET: &str = "thumbv6m-cargo_exit_code-eabi";
+
+        let project = Project::new(TARGET)?;
+        write(
+            &project.td.path().join("src/lib.rs"),
+            true,
+            "pub fn broken() -> u8 { \"not a u8\" }\n",
+        )?;
+
+        let status = xargo()?
+            .args(["build", "--target", TARGET])
+            .current_dir(project.td.path())
+            .status()
+            .chain_err(|| "couldn't execute `xargo build`")?;
+        assert_eq!(status.code(), Some(101));
+
+        Ok(())
+    }
+
+    run!()
+}
+
+/// Check that `--quiet-on-success` prints nothing when the builds succeed, and
+/// everything when one fails
+#[test]
+fn quiet_on_success() {
+    fn run() -> Result<()> {
+        const TARGET: &str = "thumbv6m-quiet_on_success-eabi";
+
+        let project = Project::new(TARGET)?;
+        let build = || -> Result<std::process::Output> {
+            xargo()?
+                .args(["build", "--quiet-on-success", "--target", TARGET])
+                .current_dir(project.td.path())
+                .output()
+                .chain_err(|| "couldn't execute `xargo build`")
+        };
+
+        let out = build()?;
+        assert!(out.status.success());
+        assert_eq!(String::from_utf8_lossy(&out.stderr), "");
+        assert!(exists("core", TARGET)?);
+
+        write(
+            &project.td.path().join("src/lib.rs"),
+            true,
+            "pub fn broken() -> u8 { \"not a u8\" }\n",
+        )?;
+        let out = build()?;
+        let stderr = String::from_utf8_lossy(&out.stderr);
+        assert_eq!(out.status.code(), Some(101));
+        assert!(stderr.contains("mismatched types"), "{}", stderr);
+
+        Ok(())
+    }
+
+    run!()
+}
+
+/// Check that `--sysroot-cache-dir` and `build.sysroot-cache-dir` cache the
+/// sysroot in that directory instead of Xargo's home
+#[test]
+fn sysroot_cache_dir() {
+    fn run() -> Result<()> {
+        const TARGET: &str = "thumbv6m-sysroot_cache_dir-eabi";
+
+        let project = Project::new(TARGET)?;
+        let rustlib = |dir: &Path| dir.join("lib/rustlib").join(TARGET).join(".hash");
+
+        xargo()?
+            .args([
+                "build",
+                "--target",
+                TARGET,
+                "--sysroot-cache-dir",
+                "target/xargo",
+            ])
+            .current_dir(project.td.path())
+            .run()?;
+        let cache = project.td.path().join("target/xargo");
+        assert!(rustlib(&cache).is_file());
+        assert!(!home()?.join("lib/rustlib").join(TARGET).exists());
+
+        project.xargo_toml("[build]\nsysroot-cache-dir = \"target/xargo\"\n")?;
+        let stderr = project.build_and_get_stderr(Some(TARGET))?;
+        assert!(!sysroot_was_built(&stderr, TARGET), "{}", stderr);
+        assert!(!home()?.join("lib/rustlib").join(TARGET).exists());
+
+        Ok(())
+    }
+
+    run!()
+}
+
+/// Check that `--all-configured-targets` builds the sysroots of the targets of
+/// `Xargo.toml` and `build.target-dir-specs`, and reports the ones that failed
+#[test]
+fn all_configured_targets() {
+    fn run() -> Result<()> {
+        const TARGET: &str = "thumbv6m-all_configured_targets-eabi";
+        const SPEC: &str = "thumbv6m-all_configured_targets_spec-eabi";
+
+        let project = Project::new(TARGET)?;
+        let specs = project.td.path().join("specs");
+        mkdir(&specs)?;
+        fs::copy(
+            project.td.path().join(format!("{}.json", TARGET)),
+            specs.join(format!("{}.json", SPEC)),
+        )
+        .chain_err(|| "couldn't copy the target specification")?;
+        project.xargo_toml(&format!(
+            "[build]\ntarget-dir-specs = \"specs\"\n\n\
+             [target.{}]\n\n\
+             [target.thumbv6m-nowhere-eabi]\n",
+            TARGET
+        ))?;
+
+        let out = xargo()?
+            .args(["build", "--all-configured-targets"])
+            .current_dir(project.td.path())
+            .output()
+            .chain_err(|| "couldn't execute `xargo build --all-configured-targets`")?;
+        let stderr = String::from_utf8_lossy(&out.stderr);
+
+        assert!(!out.status.success());
+        assert!(stderr.contains(&format!("ok     {}", TARGET)), "{}", stderr);
+        assert!(stderr.contains(&format!("ok     {}", SPEC)), "{}", stderr);
+        assert!(
+            stderr.contains("failed thumbv6m-nowhere-eabi"),
+            "{}",
+            stderr
+        );
+        assert!(exists("core", TARGET)?);
+        assert!(exists("core", SPEC)?);
+
+        Ok(())
+    }
+
+    run!()
+}
+
+/// Check that the target specification of a workspace member is found in the
+/// root of the workspace
+#[test]
+fn workspace_target_spec() {
+    fn run() -> Result<()> {
+        const TARGET: &str = "thumbv6m-workspace_target_spec-eabi";
+
+        let project = Project::new(TARGET)?;
+        project.cargo_toml("[workspace]\nmembers = [\"member\"]\n")?;
+        let member = project.td.path().join("member");
+        mkdir(&member)?;
+        mkdir(&member.join("src"))?;
+        write(
+            &member.join("Cargo.toml"),
+            false,
+            "[package]\nname = \"member\"\nversion = \"0.1.0\"\n",
+        )?;
+        write(&member.join("src/lib.rs"), false, "#![no_std]\n")?;
+
+        let out = xargo()?
+            .args(["info", "--target", TARGET, "--format", "json"])
+            .current_dir(&member)
+            .output()
+            .chain_err(|| "couldn't execute `xargo info`")?;
+        assert!(
+            out.status.success(),
+            "{}",
+            String::from_utf8_lossy(&out.stderr)
+        );
+        let info: serde_json::Value =
+            serde_json::from_slice(&out.stdout).chain_err(|| "`xargo info` didn't print JSON")?;
+        assert_eq!(info["kind"], "custom");
+        assert!(info["spec_path"]
+            .as_str()
+            .unwrap_or("")
+            .ends_with(&format!("{}.json", TARGET)));
+
+        Ok(())
+    }
+
+    run!()
+}
+
+/// Check that `xargo validate-target` reports what rustc makes of a target
+/// specification, and builds nothing
+#[test]
+fn validate_target() {
+    fn run() -> Result<()> {
+        const TARGET: &str = "thumbv6m-validate_target-eabi";
+
+        let project = Project::new(TARGET)?;
+        let validate = |json: &str| -> Result<std::process::Output> {
+            xargo()?
+                .args(["validate-target", json])
+                .current_dir(project.td.path())
+                .output()
+                .chain_err(|| format!("couldn't execute `xargo validate-target {}`", json))
+        };
+
+        let out = validate(&format!("{}.json", TARGET))?;
+        assert!(out.status.success());
+        assert!(String::from_utf8_lossy(&out.stderr).contains(": ok"));
+
+        write(
+            &project.td.path().join("broken.json"),
+            false,
+            r#"{"arch": 32, "llvm-target": "thumbv6m-none-eabi", "foo": true}"#,
+        )?;
+        let out = validate("broken.json")?;
+        let stderr = String::from_utf8_lossy(&out.stderr);
+        assert!(!out.status.success());
+        assert!(stderr.contains("unknown target specification key `foo`"));
+        assert!(stderr.contains("broken.json is not a valid target specification"));
+        assert!(!home()?.join("lib/rustlib").join(TARGET).exists());
+
+        Ok(())
+    }
+
+    run!()
+}
+
+/// Check that the sysroot build writes `Xargo.lock`, and that it can't resolve
+/// to other versions with `--locked`
+#[test]
+fn xargo_lock() {
+    fn run() -> Result<()> {
+        const TARGET: &str = "thumbv6m-xargo_lock-eabi";
+
+        let project = Project::new(TARGET)?;
+        project.build(TARGET)?;
+
+        let lock = project.td.path().join("Xargo.lock");
+        let contents = fs::read_to_string(&lock).chain_err(|| "couldn't read Xargo.lock")?;
+        assert!(
+            contents.contains("name = \"compiler_builtins\""),
+            "{}",
+            contents
+        );
+
+        // Pin another version, and make the sysroot stale
+        let package = contents
+            .split("[[package]]")
+            .find(|p| p.contains("name = \"compiler_builtins\""))
+            .ok_or("compiler_builtins is not in Xargo.lock")?;
+        let version = package
+            .lines()
+            .find(|l| l.starts_with("version = "))
+            .ok_or("compiler_builtins has no version in Xargo.lock")?;
+        let pinned = package.replace(version, "version = \"0.0.1\"");
+        write(&lock, false, &contents.replace(package, &pinned))?;
+        project.xargo_toml("[build]\nrustflags = [\"-C\", \"opt-level=s\"]\n")?;
+
+        let out = xargo()?
+            .args(["build", "--locked", "--target", TARGET])
+            .current_dir(project.td.path())
+            .output()
+            .chain_err(|| "couldn't execute `xargo build --locked`")?;
+        let stderr = String::from_utf8_lossy(&out.stderr);
+
+        assert!(!out.status.success());
+        assert!(
+            stderr.contains("the dependencies of the sysroot don't match"),
+            "{}",
+            stderr
+        );
+
+        Ok(())
+    }
+
+    run!()
+}
+
+/// Check that editing a crate patched in by `path` changes the sysroot hash
+#[test]
+fn patch_source_hash() {
+    fn run() -> Result<()> {
+        const TARGET: &str = "thumbv6m-patch_source_hash-eabi";
+
+        let project = Project::new(TARGET)?;
+        let krate = project.td.path().join("libc");
+        mkdir(&krate)?;
+        mkdir(&krate.join("src"))?;
+        write(
+            &krate.join("Cargo.toml"),
+            false,
+            "[package]\nname = \"libc\"\nversion = \"0.2.999\"\n",
+        )?;
+        write(&krate.join("src/lib.rs"), false, "#![no_std]\n")?;
+        project.xargo_toml(
+            r#"
+[patch.crates-io.libc]
+path = "libc"
+"#,
+        )?;
+
+        let hash = project.print_hash(&[])?;
+        assert_eq!(project.print_hash(&[])?, hash);
+
+        write(&krate.join("src/lib.rs"), true, "pub fn patched() {}\n")?;
+        assert_ne!(project.print_hash(&[])?, hash);
+
+        Ok(())
+    }
+
+    run!()
+}
+
+/// Check that `target.<triple>.linker` of `Xargo.toml` is passed to the
+/// sysroot build, and not hashed
+#[test]
+fn sysroot_linker() {
+    fn run() -> Result<()> {
+        const TARGET: &str = "thumbv6m-sysroot_linker-eabi";
+
+        let project = Project::new(TARGET)?;
+        let hash = project.print_hash(&[])?;
+        project.xargo_toml(&format!("[target.{}]\nlinker = \"tools/ld\"\n", TARGET))?;
+        assert_eq!(project.print_hash(&[])?, hash);
+
+        let out = xargo()?
+            .args(["build", "--dry-run", "-v", "--target", TARGET])
+            .current_dir(project.td.path())
+            .output()
+            .chain_err(|| "couldn't execute `xargo build --dry-run`")?;
+        assert!(out.status.success());
+        let output = format!(
+            "{}{}",
+            String::from_utf8_lossy(&out.stdout),
+            String::from_utf8_lossy(&out.stderr)
+        );
+        let linker = project.td.path().join("tools/ld");
+        assert!(
+            output.contains(&format!("linker={}", linker.display())),
+            "{}",
+            output
+        );
+
+        Ok(())
+    }
+
+    run!()
+}
+
+/// Check that `build.post-sysroot-hook` runs after the sysroot is built, but
+/// not when it's up to date unless `--always-run-hook` is passed
+#[cfg(unix)]
+#[test]
+fn post_sysroot_hook() {
+    fn run() -> Result<()> {
+        use std::os::unix::fs::PermissionsExt;
+
+        const TARGET: &str = "thumbv6m-post_sysroot_hook-eabi";
+
+        let project = Project::new(TARGET)?;
+        let hook = project.td.path().join("hook.sh");
+        write(
+            &hook,
+            false,
+            "#!/bin/sh\necho \"$XARGO_TARGET $2\" >> hook.log\n",
+        )?;
+        fs::set_permissions(&hook, fs::Permissions::from_mode(0o755))
+            .chain_err(|| "couldn't make the hook executable")?;
+        project.xargo_toml("[build]\npost-sysroot-hook = \"./hook.sh\"\n")?;
+
+        let runs = || -> Result<Vec<String>> {
+            Ok(fs::read_to_string(project.td.path().join("hook.log"))
+                .unwrap_or_default()
+                .lines()
+                .map(String::from)
+                .collect())
+        };
+
+        project.build(TARGET)?;
+        assert_eq!(runs()?, vec![format!("{} {}", TARGET, TARGET)]);
+
+        project.build(TARGET)?;
+        assert_eq!(runs()?.len(), 1);
+
+        xargo()?
+            .args(["build", "--always-run-hook", "--target", TARGET])
+            .current_dir(project.td.path())
+            .run()?;
+        assert_eq!(runs()?.len(), 2);
+
+        Ok(())
+    }
+
+    run!()
+}
+
+/// Check that the hook runs once per target when the sysroots are built in
+/// parallel, with or without `--always-run-hook`
+#[cfg(unix)]
+#[test]
+fn post_sysroot_hook_jobs() {
+    fn run() -> Result<()> {
+        use std::os::unix::fs::PermissionsExt;
+
+        const TARGET: &str = "thumbv6m-post_sysroot_hook_jobs-eabi";
+        const TARGET2: &str = "thumbv6m-post_sysroot_hook_jobs2-eabi";
+
+        let project = Project::new(TARGET)?;
+        let td = project.td.path();
+        fs::copy(
+            td.join(format!("{}.json", TARGET)),
+            td.join(format!("{}.json", TARGET2)),
+        )
+        .chain_err(|| "couldn't copy the target specification")?;
+        let hook = td.join("hook.sh");
+        write(
+            &hook,
+            false,
+            "#!/bin/sh\necho \"$XARGO_TARGET\" >> hook.log\n",
+        )?;
+        fs::set_permissions(&hook, fs::Permissions::from_mode(0o755))
+            .chain_err(|| "couldn't make the hook executable")?;
+        project.xargo_toml("[build]\npost-sysroot-hook = \"./hook.sh\"\n")?;
+
+        let build = |flags: &[&str]| -> Result<()> {
+            xargo()?
+                .args(["build", "-j", "2", "--target", TARGET, "--target", TARGET2])
+                .args(flags)
+                .current_dir(td)
+                .run()
+        };
+        let runs = |target: &str| -> usize {
+            fs::read_to_string(td.join("hook.log"))
+                .unwrap_or_default()
+                .lines()
+                .filter(|l| *l == target)
+                .count()
+        };
+
+        build(&[])?;
+        assert_eq!((runs(TARGET), runs(TARGET2)), (1, 1));
+
+        build(&["--always-run-hook"])?;
+        assert_eq!((runs(TARGET), runs(TARGET2)), (2, 2));
+
+        cleanup(TARGET2)
+    }
+
+    run!()
+}
+
+/// Check that `--explain-rebuild` tells which input of the hash changed
+#[test]
+fn explain_rebuild() {
+    fn run() -> Result<()> {
+        const TARGET: &str = "thumbv6m-explain_rebuild-eabi";
+
+        let project = Project::new(TARGET)?;
+        let build = |rustflags: &str| -> Result<String> {
+            xargo()?
+                .args(["build", "--explain-rebuild", "--target", TARGET])
+                .env("RUSTFLAGS", rustflags)
+                .current_dir(project.td.path())
+                .run_and_get_stderr()
+        };
+
+        assert!(build("")?.contains("no previous build"));
+        assert!(!build("")?.contains("rebuilding the sysroot"));
+
+        let stderr = build("-C opt-level=s")?;
+        assert!(stderr.contains("rebuilding the sysroot"), "{}", stderr);
+        assert!(
+            stderr.contains("rustflags changed:  -> -C opt-level=s"),
+            "{}",
+            stderr
+        );
+
+        Ok(())
+    }
+
+    run!()
+}
+
+/// Check that an interrupted build, or `--force-rebuild`, rebuilds a sysroot
+/// whose hash matches
+#[test]
+fn force_rebuild() {
+    fn run() -> Result<()> {
+        const TARGET: &str = "thumbv6m-force_rebuild-eabi";
+
+        let project = Project::new(TARGET)?;
+        let build = |args: &[&str]| -> Result<String> {
+            xargo()?
+                .args(["build", "--explain-rebuild", "--target", TARGET])
+                .args(args)
+                .current_dir(project.td.path())
+                .run_and_get_stderr()
+        };
+
+        build(&[])?;
+        fs::remove_file(
+            home()?
+                .join("lib/rustlib")
+                .join(TARGET)
+                .join(".xargo-complete"),
+        )
+        .chain_err(|| "couldn't remove .xargo-complete")?;
+        let stderr = build(&[])?;
+        assert!(
+            stderr.contains("the previous build didn't complete"),
+            "{}",
+            stderr
+        );
+
+        let stderr = build(&["--force-rebuild"])?;
+        assert!(
+            stderr.contains("`--force-rebuild` was passed"),
+            "{}",
+            stderr
+        );
+        assert!(!build(&[])?.contains("rebuilding the sysroot"));
+
+        Ok(())
+    }
+
+    run!()
+}
+
+/// Check that the unstable flags of the sysroot build are part of its hash
+#[test]
+fn unstable_flags() {
+    fn run() -> Result<()> {
+        const TARGET: &str = "thumbv6m-unstable_flags-eabi";
+
+        let project = Project::new(TARGET)?;
+        let print_hash = |unstable: &str| -> Result<String> {
+            project
+                .print_hash_command(&["-v"])?
+                .env("XARGO_RUSTC_UNSTABLE", unstable)
+                .run_and_get_stderr()
+        };
+
+        assert!(!print_hash("")?.contains("-Z macro-backtrace"));
+        assert!(print_hash("macro-backtrace")?.contains("-Z macro-backtrace"));
+
+        project.xargo_toml("[build]\nunstable-flags = [\"macro-backtrace\"]\n")?;
+        assert!(print_hash("")?.contains("-Z macro-backtrace"));
+
+        Ok(())
+    }
+
+    run!()
+}
+
+/// Check that `${VAR}` in `Xargo.toml` is replaced with the environment
+/// variable `VAR`
+#[test]
+fn xargo_toml_env() {
+    fn run() -> Result<()> {
+        const TARGET: &str = "thumbv6m-xargo_toml_env-eabi";
+
+        let project = Project::new(TARGET)?;
+        project.xargo_toml("[build]\nrustflags = [\"-C\", \"opt-level=${XARGO_TEST_OPT}\"]\n")?;
+        let print_hash = |opt: Option<&str>| -> Result<(bool, String)> {
+            let mut cmd = project.print_hash_command(&["-v"])?;
+            match opt {
+                Some(opt) => cmd.env("XARGO_TEST_OPT", opt),
+                None => cmd.env_remove("XARGO_TEST_OPT"),
+            };
+            let out = cmd
+                .output()
+                .chain_err(|| "couldn't execute `xargo --print-hash`")?;
+
+            Ok((
+                out.status.success(),
+                String::from_utf8(out.stderr).chain_err(|| "stderr was not UTF-8")?,
+            ))
+        };
+
+        let (success, stderr) = print_hash(Some("s"))?;
+        assert!(success);
+        assert!(stderr.contains("-C opt-level=s"), "{}", stderr);
+
+        let (success, stderr) = print_hash(None)?;
+        assert!(!success);
+        assert!(stderr.contains("`XARGO_TEST_OPT`"), "{}", stderr);
+        assert!(stderr.contains("`build.rustflags[1]`"), "{}", stderr);
+
+        Ok(())
+    }
+
+    run!()
+}
+
+/// Check that `--timings` prints how long each sysroot crate took to build
+#[test]
+fn timings() {
+    fn run() -> Result<()> {
+        const TARGET: &str = "thumbv6m-timings-eabi";
+
+        let project = Project::new(TARGET)?;
+        let stderr = xargo()?
+            .args(["build", "--timings", "--target", TARGET])
+            .current_dir(project.td.path())
+            .run_and_get_stderr()?;
+
+        assert!(stderr.contains(&format!("sysroot build times for {}", TARGET)));
+        assert!(stderr.lines().any(|l| l.trim_start().starts_with("core ")));
+        assert!(stderr.lines().any(|l| l.trim_start().starts_with("total ")));
+
+        Ok(())
+    }
+
+    run!()
+}
+
+/// Check that the `Xargo.toml` of Xargo's home applies to every project, under
+/// the one of the project
+#[test]
+fn global_xargo_toml() {
+    fn run() -> Result<()> {
+        const TARGET: &str = "thumbv6m-global_xargo_toml-eabi";
+
+        let project = Project::new(TARGET)?;
+        let xhome = project.td.path().join("xargo-home");
+        mkdir(&xhome)?;
+        write(
+            &xhome.join("Xargo.toml"),
+            false,
+            "[build]\nrustflags = [\"-C\", \"opt-level=s\"]\n\n[dependencies.alloc]\n",
+        )?;
+        let print_hash = || -> Result<String> {
+            project
+                .print_hash_command(&["-v"])?
+                .env("XARGO_HOME", &xhome)
+                .run_and_get_stderr()
+        };
+
+        let stderr = print_hash()?;
+        assert!(stderr.contains("-C opt-level=s"), "{}", stderr);
+        assert!(stderr.contains("dependency `alloc`"), "{}", stderr);
+
+        project.xargo_toml("[build]\nrustflags = [\"-C\", \"opt-level=z\"]\n")?;
+        let stderr = print_hash()?;
+        assert!(stderr.contains("-C opt-level=z"), "{}", stderr);
+        assert!(!stderr.contains("-C opt-level=s"), "{}", stderr);
+        assert!(stderr.contains("dependency `alloc`"), "{}", stderr);
+
+        Ok(())
+    }
+
+    run!()
+}
+
+/// Check that Cargo builds the sysroot as verbosely as Xargo was asked to, or
+/// at least with `-v` with `--verbose-cargo`
+#[test]
+fn verbosity() {
+    fn run() -> Result<()> {
+        const TARGET: &str = "thumbv6m-verbosity-eabi";
+
+        let project = Project::new(TARGET)?;
+        let dry_run =
+            |flag: &str| project.xargo_stdout(&["build", "--dry-run", "--target", TARGET, flag]);
+
+        let commands = |stdout: &str| -> Vec<String> {
+            stdout
+                .lines()
+                .filter(|l| l.starts_with("+ ") && l.contains("build"))
+                .map(|l| l.to_owned())
+                .collect()
+        };
+
+        for (flag, cargo_flag) in [
+            ("-vv", "-vv"),
+            ("-q", "-q"),
+            ("--quiet", "-q"),
+            ("--verbose-cargo", "-v"),
+        ]
+        .iter()
+        {
+            let stdout = dry_run(flag)?;
+            let commands = commands(&stdout);
+            assert!(!commands.is_empty());
+            assert!(commands.iter().all(|c| c.contains(cargo_flag)));
+        }
+
+        Ok(())
+    }
+
+    run!()
+}
+
+/// Check that `Target::cfg_specs` returns the cfgs of a custom target
+#[test]
+fn cfg_specs() {
+    fn run() -> Result<()> {
+        const TARGET: &str = "thumbv6m-cfg_specs-eabi";
+
+        let project = Project::new(TARGET)?;
+        let json = project.td.path().join(format!("{}.json", TARGET));
+        let target = xargo::Target::from_json_path(&json).map_err(|e| e.to_string())?;
+        let cfgs = target
+            .cfg_specs(xargo::Verbosity::Normal)
+            .map_err(|e| e.to_string())?;
+
+        assert!(cfgs.iter().any(|c| c == "target_pointer_width=\"32\""));
+        assert!(cfgs.iter().any(|c| c == "target_endian=\"little\""));
+
+        Ok(())
+    }
+
+    run!()
+}
+
+/// Check that `build.rustflags` of `Xargo.toml` is part of the hash
+#[test]
+fn sysroot_rustflags() {
+    fn run() -> Result<()> {
+        const TARGET: &str = "thumbv6m-sysroot_rustflags-eabi";
+
+        let project = Project::new(TARGET)?;
+        let xargo_toml = project.td.path().join("Xargo.toml");
+        let hash = project.print_hash(&[])?;
+
+        write(
+            &xargo_toml,
+            false,
+            "[build]\nrustflags = [\"-C\", \"force-unwind-tables=yes\"]\n",
+        )?;
+        assert_ne!(project.print_hash(&[])?, hash);
+
+        write(&xargo_toml, false, "[build]\nrustflags = \"-g\"\n")?;
+        assert!(project.print_hash(&[]).is_err());
+
+        Ok(())
+    }
+
+    run!()
+}
+
+/// Check that `--sysroot-dir` builds the sysroot in a directory of our choice,
+/// and only in a non-empty one with `--force`
+#[test]
+fn sysroot_dir() {
+    fn run() -> Result<()> {
+        const TARGET: &str = "thumbv6m-sysroot_dir-eabi";
+
+        let project = Project::new(TARGET)?;
+        let staging = project.td.path().join("staging");
+        mkdir(&staging)?;
+        write(&staging.join("README"), false, "")?;
+
+        let build = |force: bool| -> Result<bool> {
+            let mut cmd = xargo()?;
+            cmd.args(["build", "--target", TARGET, "--sysroot-dir", "staging"])
+                .current_dir(project.td.path());
+            if force {
+                cmd.arg("--force");
+            }
+
+            Ok(cmd
+                .output()
+                .chain_err(|| "couldn't execute `xargo build`")?
+                .status
+                .success())
+        };
+
+        assert!(!build(false)?);
+        assert!(build(true)?);
+        assert!(staging.join("lib/rustlib").join(TARGET).is_dir());
+        assert!(!home()?.join("lib/rustlib").join(TARGET).exists());
+
+        Ok(())
+    }
+
+    run!()
+}
+
+/// Check that `--no-sysroot` uses a prebuilt sysroot as it is
+#[test]
+fn no_sysroot() {
+    fn run() -> Result<()> {
+        const TARGET: &str = "thumbv6m-no_sysroot-eabi";
+
+        let project = Project::new(TARGET)?;
+        let prebuilt = project.td.path().join("prebuilt");
+        xargo()?
+            .args(["build", "--target", TARGET, "--sysroot-dir", "prebuilt"])
+            .current_dir(project.td.path())
+            .run()?;
+
+        let out = xargo()?
+            .args(["build", "--target", TARGET, "--no-sysroot"])
+            .env_remove("XARGO_SYSROOT")
+            .current_dir(project.td.path())
+            .output()
+            .chain_err(|| "couldn't execute `xargo build`")?;
+        assert!(!out.status.success());
+        assert!(String::from_utf8_lossy(&out.stderr).contains("XARGO_SYSROOT=<dir>"));
+
+        // A change to the inputs of the hash doesn't rebuild the sysroot
+        project.xargo_toml("[dependencies.alloc]\n")?;
+        let stderr = xargo()?
+            .args(["build", "-v", "--target", TARGET, "--no-sysroot"])
+            .env("XARGO_SYSROOT", &prebuilt)
+            .current_dir(project.td.path())
+            .run_and_get_stderr()?;
+        assert!(!sysroot_was_built(&stderr, TARGET));
+
+        Ok(())
+    }
+
+    run!()
+}
+
+/// Check that the toolchain of a `rust-toolchain.toml` file is used, unless
+/// `RUSTC` is set
+#[test]
+fn rust_toolchain_file() {
+    fn run() -> Result<()> {
+        const TARGET: &str = "thumbv6m-rust_toolchain_file-eabi";
+        const TOOLCHAIN: &str = "xargo-no-such-toolchain";
+
+        let project = Project::new(TARGET)?;
+        write(
+            &project.td.path().join("rust-toolchain.toml"),
+            false,
+            &format!("[toolchain]\nchannel = \"{}\"\n", TOOLCHAIN),
+        )?;
+        let print_hash = |rustc: Option<&str>| -> Result<String> {
+            let mut cmd = project.print_hash_command(&[])?;
+            cmd.env_remove("RUSTUP_TOOLCHAIN")
+                .env_remove("XARGO_TOOLCHAIN")
+                .env_remove("RUSTC");
+            if let Some(rustc) = rustc {
+                cmd.env("RUSTC", rustc);
+            }
+            let out = cmd
+                .output()
+                .chain_err(|| "couldn't execute `xargo --print-hash`")?;
+
+            String::from_utf8(out.stderr).chain_err(|| "stderr was not UTF-8")
+        };
+
+        // With `RUSTC`, it's up to the rustup proxy, if any, to use the file
+        let pinned = format!("the `{}` toolchain", TOOLCHAIN);
+        assert!(print_hash(None)?.contains(&pinned));
+        assert!(!print_hash(Some("rustc"))?.contains(&pinned));
+
+        Ok(())
+    }
+
+    run!()
+}
+
+/// Check that `XARGO_INCREMENTAL=1` builds the sysroot in the same directory
+/// every time
+#[test]
+fn incremental() {
+    fn run() -> Result<()> {
+        const TARGET: &str = "thumbv6m-incremental-eabi";
+
+        let project = Project::new(TARGET)?;
+        let build = |rustflags: &str| -> Result<()> {
+            xargo()?
+                .args(["build", "--target", TARGET])
+                .env("XARGO_INCREMENTAL", "1")
+                .env("RUSTFLAGS", rustflags)
+                .current_dir(project.td.path())
+                .run_and_get_stderr()?;
+            Ok(())
+        };
+
+        build("")?;
+        let dir = home()?.join("build").join(TARGET).join("build");
+        assert!(dir.join("stage0").is_dir());
+        assert!(exists("core", TARGET)?);
+
+        // The sysroot only has the `core` of the last build
+        build("-C opt-level=s")?;
+        let lib = home()?.join("lib/rustlib").join(TARGET).join("lib");
+        let cores = fs::read_dir(&lib)
+            .chain_err(|| format!("couldn't read the directory {}", lib.display()))?
+            .filter_map(|e| e.ok())
+            .filter(|e| {
+                let name = e.file_name().to_string_lossy().into_owned();
+                name.starts_with("libcore-") && name.ends_with(".rlib")
+            })
+            .count();
+        assert_eq!(cores, 1);
+
+        fs::remove_dir_all(home()?.join("build").join(TARGET))
+            .chain_err(|| "couldn't remove the build directory")?;
+
+        Ok(())
+    }
+
+    run!()
+}
+
+/// Check that the `rustc` set with `XARGO_SYSROOT_RUSTC` is part of the hash
+#[test]
+fn sysroot_rustc() {
+    fn run() -> Result<()> {
+        const TARGET: &str = "thumbv6m-sysroot_rustc-eabi";
+
+        let project = Project::new(TARGET)?;
+        let print_hash = |rustc: &str| -> Result<String> {
+            project
+                .print_hash_command(&[])?
+                .env("XARGO_SYSROOT_RUSTC", rustc)
+                .run_and_get_stdout()
+        };
+
+        assert_ne!(print_hash("rustc")?, print_hash("")?);
+
+        Ok(())
+    }
+
+    run!()
+}
+
+/// Check that the Rust source is downloaded from `XARGO_RUST_SRC_URL` into
+/// Xargo's home, and that a tarball without a `std` crate is rejected
+#[test]
+fn rust_src_url() {
+    fn run() -> Result<()> {
+        const TARGET: &str = "thumbv6m-rust_src_url-eabi";
+
+        let project = Project::new(TARGET)?;
+        let home = TempDir::new("xargo").chain_err(|| "couldn't create a temporary directory")?;
+        let tar = |tarball: &Path, dir: &Path| -> Result<()> {
+            let parent = dir.parent().ok_or("the directory has no parent")?;
+            let name = dir.file_name().ok_or("the directory has no name")?;
+            Command::new("tar")
+                .arg("-czf")
+                .arg(tarball)
+                .arg("-C")
+                .arg(parent)
+                .arg(name)
+                .run()
+        };
+        let print_hash = |tarball: &Path| -> Result<std::process::Output> {
+            project
+                .print_hash_command(&[])?
+                .env("XARGO_HOME", home.path())
+                .env(
+                    "XARGO_RUST_SRC_URL",
+                    format!("file://{}", tarball.display()),
+                )
+                .output()
+                .chain_err(|| "couldn't execute `xargo --print-hash`")
+        };
+
+        let empty = project.td.path().join("empty.tar.gz");
+        tar(&empty, &project.td.path().join("src"))?;
+        let out = print_hash(&empty)?;
+        let stderr = String::from_utf8_lossy(&out.stderr);
+        assert!(!out.status.success());
+        assert!(
+            stderr.contains("there's no `std/Cargo.toml` nor `libstd/Cargo.toml` in that tarball"),
+            "unexpected stderr:\n{}",
+            stderr
+        );
+
+        let sysroot = Command::new("rustc")
+            .args(["--print", "sysroot"])
+            .output()
+            .chain_err(|| "couldn't execute `rustc --print sysroot`")?;
+        let library = Path::new(String::from_utf8_lossy(&sysroot.stdout).trim())
+            .join("lib/rustlib/src/rust/library");
+        let tarball = project.td.path().join("rust-src.tar.gz");
+        tar(&tarball, &library)?;
+        assert!(print_hash(&tarball)?.status.success());
+
+        let cached = fs::read_dir(home.path().join("src"))
+            .chain_err(|| "the Rust source wasn't downloaded into Xargo's home")?
+            .filter_map(|e| e.ok())
+            .filter(|e| e.path().join(".url").is_file())
+            .count();
+        assert_eq!(cached, 1);
+
+        Ok(())
+    }
+
+    run!()
+}
+
+/// Check that an `XARGO_RUST_SRC` without a `std` crate is ignored, with a
+/// warning, in favor of the `rust-src` component
+#[test]
+fn invalid_rust_src() {
+    fn run() -> Result<()> {
+        const TARGET: &str = "thumbv6m-invalid_rust_src-eabi";
+
+        let project = Project::new(TARGET)?;
+        let out = xargo()?
+            .env("XARGO_RUST_SRC", project.td.path())
+            .args(["build", "--target", TARGET])
+            .current_dir(project.td.path())
+            .output()
+            .chain_err(|| "couldn't execute `xargo build`")?;
+        let stderr = String::from_utf8_lossy(&out.stderr);
+
+        assert!(out.status.success(), "unexpected stderr:\n{}", stderr);
+        assert!(
+            stderr.contains("neither `std/Cargo.toml` nor `libstd/Cargo.toml` exists"),
+            "unexpected stderr:\n{}",
+            stderr
+        );
+        assert!(
+            stderr.contains("Using the `rust-src` component instead"),
+            "unexpected stderr:\n{}",
+            stderr
+        );
+
+        Ok(())
+    }
+
+    run!()
+}
+
+/// Check that a relative `XARGO_HOME` is relative to the project root
+#[test]
+fn relative_xargo_home() {
+    fn run() -> Result<()> {
+        const TARGET: &str = "thumbv6m-relative_xargo_home-eabi";
+
+        let project = Project::new(TARGET)?;
+        xargo()?
+            .args(["build", "--target", TARGET])
+            .env("XARGO_HOME", ".xargo")
+            .current_dir(project.td.path().join("src"))
+            .run_and_get_stderr()?;
+
+        assert!(project
+            .td
+            .path()
+            .join(".xargo/lib/rustlib")
+            .join(TARGET)
+            .join(".sentinel")
+            .exists());
+
+        Ok(())
+    }
+
+    run!()
+}
+
+/// Check that an invalid `XARGO_NET_RETRY` is reported
+#[test]
+fn net_retry_invalid() {
+    fn run() -> Result<()> {
+        const TARGET: &str = "thumbv6m-net_retry_invalid-eabi";
+
+        let project = Project::new(TARGET)?;
+        let out = xargo()?
+            .env("XARGO_NET_RETRY", "twice")
+            .args(["build", "--target", TARGET])
+            .current_dir(project.td.path())
+            .output()
+            .chain_err(|| "couldn't execute `xargo build`")?;
+        let stderr = String::from_utf8_lossy(&out.stderr);
+
+        assert!(!out.status.success());
+        assert!(
+            stderr.contains("XARGO_NET_RETRY must be a number, but it's `twice`"),
+            "unexpected stderr:\n{}",
+            stderr
+        );
+
+        Ok(())
+    }
+
+    run!()
+}
+
+/// Check that `--dry-run` prints the build plan without building anything
+#[test]
+fn dry_run() {
+    fn run() -> Result<()> {
+        const TARGET: &str = "thumbv6m-dry_run-eabi";
+
+        let project = Project::new(TARGET)?;
+        project.xargo_toml(
+            r#"
+[dependencies.core]
+stage = 0
+
+[dependencies.alloc]
+stage = 1
+"#,
+        )?;
+
+        let out = xargo()?
+            .args(["build", "--dry-run", "--target", TARGET])
+            .current_dir(project.td.path())
+            .output()
+            .chain_err(|| "couldn't execute `xargo build`")?;
+        let stdout = String::from_utf8_lossy(&out.stdout);
+
+        assert!(out.status.success());
+        assert!(stdout.contains(&format!("target: {} (custom, ", TARGET)));
+        assert!(stdout.contains("(would be built)"));
+        assert!(stdout.contains("stage 0: core\n"));
+        assert!(stdout.contains("stage 1: alloc\n"));
+        assert!(stdout.contains(r#""-p" "alloc""#));
+        assert!(!home()?.join("lib/rustlib").join(TARGET).exists());
+        assert!(!project.td.path().join("target").exists());
+
+        Ok(())
+    }
+
+    run!()
+}
+
+/// Check that relative paths in the linker arguments of a target specification
+/// are made absolute, and that `$schema` doesn't change the sysroot hash
+#[test]
+fn spec_relative_paths() {
+    fn run() -> Result<()> {
+        const TARGET: &str = "thumbv6m-spec_relative_paths-eabi";
+
+        let project = Project::new(TARGET)?;
+        let specs = project.td.path().join("specs");
+        mkdir(&specs)?;
+        write(&specs.join("link.x"), false, "")?;
+        fs::rename(
+            project.td.path().join(format!("{}.json", TARGET)),
+            specs.join(format!("{}.json", TARGET)),
+        )
+        .chain_err(|| "couldn't move the target specification")?;
+        project.xargo_toml("[build]\ntarget-dir-specs = \"specs\"\n")?;
+
+        let dry_run = || project.xargo_stdout(&["build", "--dry-run", "--target", TARGET]);
+        let hash = |stdout: &str| -> String {
+            stdout
+                .lines()
+                .find(|l| l.starts_with("hash: "))
+                .unwrap_or("")
+                .to_owned()
+        };
+
+        let json = specs.join(format!("{}.json", TARGET));
+        let spec = fs::read_to_string(&json).chain_err(|| "couldn't read the specification")?;
+        let before = hash(&dry_run()?);
+
+        let linked = spec.replacen(
+            "{",
+            r#"{ "$schema": "target.schema.json", "pre-link-args": { "gcc": ["-Tlink.x"] },"#,
+            1,
+        );
+        write(&json, false, &linked)?;
+        let stdout = dry_run()?;
+
+        let loaded = stdout
+            .lines()
+            .find_map(|l| l.strip_prefix(&format!("target: {} (custom, ", TARGET)))
+            .and_then(|l| l.strip_suffix(")"))
+            .ok_or("no target in the output of `--dry-run`")?;
+        let loaded = fs::read_to_string(loaded).chain_err(|| "couldn't read the loaded spec")?;
+        let script = specs.canonicalize().unwrap().join("link.x");
+        assert!(
+            loaded.contains(&format!("-T{}", script.display())),
+            "{}",
+            loaded
+        );
+        assert!(!loaded.contains("$schema"));
+
+        // `$schema` on its own leaves the hash alone
+        write(
+            &json,
+            false,
+            &spec.replacen("{", r#"{ "$schema": "a.json","#, 1),
+        )?;
+        assert_eq!(hash(&dry_run()?), before);
+
+        Ok(())
+    }
+
+    run!()
+}
+
+/// Check that comments and trailing commas in a target specification are
+/// allowed, don't change the sysroot hash, and that syntax errors point at
+/// their line
+#[test]
+fn spec_comments() {
+    fn run() -> Result<()> {
+        const TARGET: &str = "thumbv6m-spec_comments-eabi";
+
+        let project = Project::new(TARGET)?;
+        let dry_run = || -> Result<std::process::Output> {
+            xargo()?
+                .args(["build", "--dry-run", "--target", TARGET])
+                .current_dir(project.td.path())
+                .output()
+                .chain_err(|| "couldn't execute `xargo build`")
+        };
+        let hash = |out: &std::process::Output| -> String {
+            String::from_utf8_lossy(&out.stdout)
+                .lines()
+                .find(|l| l.starts_with("hash: "))
+                .unwrap_or("")
+                .to_owned()
+        };
+
+        let json = project.td.path().join(format!("{}.json", TARGET));
+        let spec = fs::read_to_string(&json).chain_err(|| "couldn't read the specification")?;
+        let before = hash(&dry_run()?);
+
+        let commented = |comment: &str| {
+            spec.replacen(
+                "{",
+                &format!("{{\n  // {}\n  /* \"os\": \"linux\", */", comment),
+                1,
+            )
+            .replacen("\n}", ",\n}", 1)
+        };
+        write(&json, false, &commented("a comment"))?;
+        let out = dry_run()?;
+        assert!(out.status.success());
+        assert_eq!(hash(&out), before);
+
+        let stdout = String::from_utf8_lossy(&out.stdout);
+        let loaded = stdout
+            .lines()
+            .find_map(|l| l.strip_prefix(&format!("target: {} (custom, ", TARGET)))
+            .and_then(|l| l.strip_suffix(")"))
+            .ok_or("no target in the output of `--dry-run`")?;
+        let loaded = fs::read_to_string(loaded).chain_err(|| "couldn't read the loaded spec")?;
+        assert!(!loaded.contains("comment"), "{}", loaded);
+
+        write(&json, false, &commented("another comment"))?;
+        assert_eq!(hash(&dry_run()?), before);
+
+        write(
+            &json,
+            false,
+            "{\n  // fine\n  \"arch\": \"arm\"\n  \"os\": \"none\"\n}",
+        )?;
+        let out = dry_run()?;
+        assert!(!out.status.success());
+        let stderr = String::from_utf8_lossy(&out.stderr);
+        assert!(stderr.contains("line 4 column 3"), "{}", stderr);
+
+        Ok(())
+    }
+
+    run!()
+}
+
+/// Check that `--target -` reads the target specification from stdin
+#[test]
+fn target_from_stdin() {
+    fn run() -> Result<()> {
+        const TARGET: &str = "thumbv6m-target_from_stdin-eabi";
+
+        let project = Project::new(TARGET)?;
+        let json = project.td.path().join(format!("{}.json", TARGET));
+        let spec = fs::read_to_string(&json).chain_err(|| "couldn't read the specification")?;
+        fs::remove_file(&json).chain_err(|| "couldn't remove the specification")?;
+
+        let dry_run = |targets: &[&str]| -> Result<(bool, String)> {
+            let mut cmd = xargo()?;
+            cmd.args(["build", "--dry-run"]);
+            for target in targets {
+                cmd.args(["--target", target]);
+            }
+            let mut child = cmd
+                .current_dir(project.td.path())
+                .stdin(Stdio::piped())
+                .stdout(Stdio::piped())
+                .stderr(Stdio::piped())
+                .spawn()
+                .chain_err(|| "couldn't execute `xargo build`")?;
+            child
+                .stdin
+                .take()
+                .unwrap()
+                .write_all(spec.as_bytes())
+                .chain_err(|| "couldn't write to stdin")?;
+            let out = child
+                .wait_with_output()
+                .chain_err(|| "couldn't wait for `xargo build`")?;
+
+            Ok((
+                out.status.success(),
+                String::from_utf8(out.stdout).chain_err(|| "stdout was not UTF-8")?,
+            ))
+        };
+
+        let (success, stdout) = dry_run(&["-"])?;
+        assert!(success);
+        assert!(stdout.contains("target: thumbv6m-none-eabi-"), "{}", stdout);
+        assert!(stdout.contains("(custom, "), "{}", stdout);
+        assert_eq!(dry_run(&["-"])?.1, stdout);
+
+        assert!(!dry_run(&["-", "thumbv6m-none-eabi"])?.0);
+
+        Ok(())
+    }
+
+    run!()
+}
+
+/// Check that `--config` is passed to the sysroot build
+#[test]
+fn config_override() {
+    fn run() -> Result<()> {
+        const TARGET: &str = "thumbv6m-config_override-eabi";
+
+        let project = Project::new(TARGET)?;
+        let stderr = xargo()?
+            .args([
+                "build",
+                "--target",
+                TARGET,
+                "--config",
+                "net.git-fetch-with-cli=true",
+                "-v",
+            ])
+            .current_dir(project.td.path())
+            .run_and_get_stderr()?;
+
+        assert!(
+            stderr.lines().any(|l| l.contains("\"--release\"")
+                && l.contains("\"--config\" \"net.git-fetch-with-cli=true\"")),
+            "unexpected stderr:\n{}",
+            stderr
+        );
+
+        Ok(())
+    }
+
+    run!()
+}
+
+/// Check that the builtin targets are read from `XARGO_TARGET_LIST` if set
+#[test]
+fn target_list() {
+    fn run() -> Result<()> {
+        const TARGET: &str = "thumbv6m-target_list-eabi";
+
+        let project = Project::new(TARGET)?;
+        let list = project.td.path().join("targets.txt");
+        write(
+            &list,
+            false,
+            &format!("x86_64-unknown-linux-gnu\n{}\n", TARGET),
+        )?;
+
+        let out = xargo()?
+            .env("XARGO_TARGET_LIST", &list)
+            .args(["build", "--target", TARGET])
+            .current_dir(project.td.path())
+            .output()
+            .chain_err(|| "couldn't execute `xargo build`")?;
+        let stderr = String::from_utf8_lossy(&out.stderr);
+
+        // The specification file is shadowed by the "builtin" target
+        assert!(
+            stderr.contains(&format!("because `{}` is a builtin target", TARGET)),
+            "unexpected stderr:\n{}",
+            stderr
+        );
+
+        Ok(())
+    }
+
+    run!()
+}
+
+/// Check that an empty `XARGO_TARGET_LIST` is an error, rather than every
+/// target being taken for a custom one
+#[test]
+fn empty_target_list() {
+    fn run() -> Result<()> {
+        const TARGET: &str = "thumbv6m-empty_target_list-eabi";
+
+        let project = Project::new(TARGET)?;
+        let list = project.td.path().join("targets.txt");
+        write(&list, false, "\n")?;
+
+        let out = xargo()?
+            .env("XARGO_TARGET_LIST", &list)
+            .args(["build", "--target", TARGET])
+            .current_dir(project.td.path())
+            .output()
+            .chain_err(|| "couldn't execute `xargo build`")?;
+        let stderr = String::from_utf8_lossy(&out.stderr);
+
+        assert!(!out.status.success());
+        assert!(
+            stderr.contains("the target list is empty"),
+            "unexpected stderr:\n{}",
+            stderr
+        );
+
+        Ok(())
+    }
+
+    run!()
+}
+
+/// Check that a target name, or an `arch`, that doesn't match the
+/// `llvm-target` of its specification is warned about
+#[test]
+fn llvm_target_mismatch() {
+    fn run() -> Result<()> {
+        // The specification is the one of `thumbv6m-none-eabi`
+        const TARGET: &str = "x86_64-llvm_target_mismatch";
+
+        let project = Project::new(TARGET)?;
+        let json = project.td.path().join(format!("{}.json", TARGET));
+        let print_hash = || -> Result<String> {
+            let out = project
+                .print_hash_command(&[])?
+                .output()
+                .chain_err(|| "couldn't execute `xargo --print-hash`")?;
+
+            Ok(String::from_utf8_lossy(&out.stderr).into_owned())
+        };
+
+        let stderr = print_hash()?;
+        assert!(
+            stderr.contains(
+                "`llvm-target` is `thumbv6m-none-eabi`, which doesn't match the \
+                 architecture of the target name `x86_64-llvm_target_mismatch`"
+            ),
+            "unexpected stderr:\n{}",
+            stderr
+        );
+        assert!(!stderr.contains("`arch`"), "{}", stderr);
+
+        let spec = fs::read_to_string(&json)
+            .chain_err(|| "couldn't read the specification")?
+            .replace(r#""arch": "arm""#, r#""arch": "x86_64""#);
+        write(&json, false, &spec)?;
+        let stderr = print_hash()?;
+        assert!(
+            stderr.contains(
+                "`llvm-target` is `thumbv6m-none-eabi`, whose architecture doesn't match \
+                 the `arch` `x86_64` of the specification"
+            ),
+            "unexpected stderr:\n{}",
+            stderr
+        );
+
+        Ok(())
+    }
+
+    run!()
+}
+
+/// Check that the stderr of a failed `rustc` invocation is reported
+#[test]
+fn rustc_stderr() {
+    fn run() -> Result<()> {
+        const TARGET: &str = "thumbv6m-rustc_stderr-eabi";
+
+        let project = Project::new(TARGET)?;
+        // Fine as JSON, but not as a target specification
+        write(
+            &project.td.path().join(format!("{}.json", TARGET)),
+            false,
+            r#"{ "arch": "arm" }"#,
+        )?;
+        // Needs `rustc --print cfg`
+        project.xargo_toml(
+            r#"
+[target.'cfg(unix)'.dependencies.std]
+"#,
+        )?;
+
+        let out = xargo()?
+            .args(["build", "--target", TARGET])
+            .current_dir(project.td.path())
+            .output()
+            .chain_err(|| "couldn't execute `xargo build`")?;
+        let stderr = String::from_utf8_lossy(&out.stderr);
+
+        assert!(!out.status.success());
+        assert!(
+            stderr
+                .to_lowercase()
+                .contains("caused by: error: error loading target specification"),
+            "unexpected stderr:\n{}",
+            stderr
+        );
+
+        Ok(())
+    }
+
+    run!()
+}
+
+/// Check that a `RUSTC` pointing nowhere is reported as such
+#[test]
+fn rustc_not_found() {
+    fn run() -> Result<()> {
+        const TARGET: &str = "thumbv6m-rustc_not_found-eabi";
+
+        let project = Project::new(TARGET)?;
+        let rustc = project.td.path().join("rustc");
+        let out = xargo()?
+            .env("RUSTC", &rustc)
+            .args(["build", "--target", TARGET])
+            .current_dir(project.td.path())
+            .output()
+            .chain_err(|| "couldn't execute `xargo build`")?;
+        let stderr = String::from_utf8_lossy(&out.stderr);
+
+        assert!(!out.status.success());
+        assert!(
+            stderr.contains(&format!(
+                "rustc not found; `RUSTC` is set to `{}`",
+                rustc.display()
+            )),
+            "unexpected stderr:\n{}",
+            stderr
+        );
+
+        Ok(())
+    }
+
+    run!()
+}
+
+/// Check that `--strict-src` rejects a `XARGO_RUST_SRC` of another Rust
+/// version
+#[test]
+fn strict_src() {
+    fn run() -> Result<()> {
+        const TARGET: &str = "thumbv6m-strict_src-eabi";
+
+        let project = Project::new(TARGET)?;
+        let rust = project.td.path().join("rust");
+        mkdir(&rust)?;
+        mkdir(&rust.join("src"))?;
+        mkdir(&rust.join("library"))?;
+        mkdir(&rust.join("library/std"))?;
+        write(&rust.join("src/version"), false, "1.0.0\n")?;
+        write(&rust.join("library/std/Cargo.toml"), false, "")?;
+
+        let out = xargo()?
+            .env("XARGO_RUST_SRC", rust.join("library"))
+            .args(["build", "--strict-src", "--target", TARGET])
+            .current_dir(project.td.path())
+            .output()
+            .chain_err(|| "couldn't execute `xargo build`")?;
+        let stderr = String::from_utf8_lossy(&out.stderr);
+
+        assert!(!out.status.success());
+        assert!(
+            stderr.contains("is version 1.0.0, but rustc is version"),
+            "unexpected stderr:\n{}",
+            stderr
+        );
+
+        Ok(())
+    }
+
+    run!()
+}
+
+/// Check that calling `xargo build` a second time doesn't rebuild the sysroot
+#[test]
+fn twice() {
+    fn run() -> Result<()> {
+        const TARGET: &'static str = "thumbv6m-twice-eabi";
+
+        let project = Project::new(TARGET)?;
+        let stderr = project.build_and_get_stderr(Some(TARGET))?;
+
+        assert!(sysroot_was_built(&stderr, TARGET));
+
+        let stderr = project.build_and_get_stderr(Some(TARGET))?;
+
+        assert!(!sysroot_was_built(&stderr, TARGET));
+
+        Ok(())
+    }
+
+    run!()
+}
+
+/// Check that if `build.target` is set in `.cargo/config`, that target will be
+/// used to build the sysroot
+#[test]
+fn build_target() {
+    fn run() -> Result<()> {
+        const TARGET: &'static str = "thumbv6m-build_target-eabi";
+
+        let project = Project::new(TARGET)?;
+        project.config(
+            r#"
+[build]
+target = "thumbv6m-build_target-eabi"
+"#,
+        )?;
+
+        let stderr = project.build_and_get_stderr(None)?;
+
+        assert!(sysroot_was_built(&stderr, TARGET));
+
+        Ok(())
+    }
+
+    run!()
+}
+
+/// Check that `build.target` in `.cargo/config.toml` is used to build the
+/// sysroot, also when it's the path to a target specification file
+#[test]
+fn build_target_json() {
+    fn run() -> Result<()> {
+        const TARGET: &str = "thumbv6m-build_target_json-eabi";
+
+        let project = Project::new(TARGET)?;
+        mkdir(&project.td.path().join(".cargo"))?;
+        write(
+            &project.td.path().join(".cargo/config.toml"),
+            false,
+            r#"
+[build]
+target = "thumbv6m-build_target_json-eabi.json"
+"#,
+        )?;
+
+        let stderr = project.build_and_get_stderr(None)?;
+
+        assert!(sysroot_was_built(&stderr, TARGET));
+        assert!(exists("core", TARGET)?);
+
+        Ok(())
+    }
+
+    run!()
+}
+
+/// Check that `--target` overrides `build.target`
+#[test]
+fn override_build_target() {
+    fn run() -> Result<()> {
+        const TARGET: &'static str = "thumbv6m-override_build_target-eabi";
+
+        let project = Project::new(TARGET)?;
+        project.config(
+            r#"
+[build]
+target = "BAD"
+"#,
+        )?;
+
+        let stderr = project.build_and_get_stderr(Some(TARGET))?;
+
+        assert!(sysroot_was_built(&stderr, TARGET));
+
+        Ok(())
+    }
+
+    run!()
+}
+
+/// We shouldn't rebuild the sysroot if `profile.release.lto` changed between
+/// fat and thin LTO
+#[test]
+fn lto_changed() {
+    fn run() -> Result<()> {
+        const TARGET: &'static str = "thumbv6m-lto_changed-eabi";
+
+        let project = Project::new(TARGET)?;
+        project.cargo_toml(
+            r#"
+[profile.release]
+lto = true
+"#,
+        )?;
+
+        let stderr = project.build_and_get_stderr(Some(TARGET))?;
+
+        assert!(sysroot_was_built(&stderr, TARGET));
+
+        project.xargo_toml(
+            r#"
+[build.profile]
+lto = "thin"
+"#,
+        )?;
+
+        let stderr = project.build_and_get_stderr(Some(TARGET))?;
+
+        assert!(!sysroot_was_built(&stderr, TARGET));
+
+        Ok(())
+    }
+
+    run!()
+}
+
+/// Check that the sysroot embeds bitcode when LTO is enabled, unless
+/// `embed-bitcode = false`
+#[test]
+fn lto_embed_bitcode() {
+    fn run() -> Result<()> {
+        const TARGET: &str = "thumbv6m-lto_embed_bitcode-eabi";
+
+        let project = Project::new(TARGET)?;
+        project.cargo_toml(
+            r#"
+[profile.release]
+lto = "thin"
+"#,
+        )?;
+
+        let stderr = project.build_and_get_stderr(Some(TARGET))?;
+
+        assert!(sysroot_was_built(&stderr, TARGET));
+        assert!(stderr.contains("embed-bitcode=yes"));
+
+        project.xargo_toml(
+            r#"
+[build.profile]
+embed-bitcode = false
+"#,
+        )?;
+
+        let stderr = project.build_and_get_stderr(Some(TARGET))?;
+
+        assert!(sysroot_was_built(&stderr, TARGET));
+        assert!(!stderr.contains("embed-bitcode=yes"));
+
+        Ok(())
+    }
+
+    run!()
+}
+
+/// Modifying RUSTFLAGS should trigger a rebuild of the sysroot
+#[test]
+fn rustflags_changed() {
+    fn run() -> Result<()> {
+        const TARGET: &'static str = "thumbv6m-rustflags_changed-eabi";
+
+        let project = Project::new(TARGET)?;
+        let stderr = project.build_and_get_stderr(Some(TARGET))?;
+
+        assert!(sysroot_was_built(&stderr, TARGET));
+
+        project.config(
+            r#"
+[build]
+rustflags = ["--cfg", "xargo"]
+"#,
+        )?;
+
+        let stderr = project.build_and_get_stderr(Some(TARGET))?;
+
+        assert!(sysroot_was_built(&stderr, TARGET));
+
+        Ok(())
+    }
+
+    run!()
+}
+
+/// Check that RUSTFLAGS are passed to all `rustc`s, and that they can contain spaces.
+#[test]
+fn rustflags() {
+    fn run() -> Result<()> {
+        const TARGET: &'static str = "thumbv6m-rustflags-eabi";
+
+        let project = Project::new(TARGET)?;
+
+        project.config(
+            r#"
+[build]
+rustflags = ["--cfg", 'xargo="y e s"']
+"#,
+        )?;
+
+        let stderr = project.build_and_get_stderr(Some(TARGET))?;
+
+        assert!(
+            stderr.contains(r#"'xargo="y e s"'"#),
+            "unexpected stderr:\n{}",
+            stderr
+        );
+
+        Ok(())
+    }
+
+    run!()
+}
+
+/// Check that `CARGO_BUILD_RUSTFLAGS` overrides `build.rustflags` and is
+/// passed to all `rustc`s
+#[test]
+fn cargo_build_rustflags() {
+    fn run() -> Result<()> {
+        const TARGET: &str = "thumbv6m-cargo_build_rustflags-eabi";
+
+        let project = Project::new(TARGET)?;
+
+        project.config(
+            r#"
+[build]
+rustflags = ["--cfg", "config"]
+"#,
+        )?;
+
+        let stderr = xargo()?
+            .args(["build", "--target", TARGET, "-v"])
+            .env("CARGO_BUILD_RUSTFLAGS", "--cfg env")
+            .current_dir(project.td.path())
+            .run_and_get_stderr()?;
+
+        assert!(
+            stderr.contains("--cfg env") && !stderr.contains("--cfg config"),
+            "unexpected stderr:\n{}",
+            stderr
+        );
+
+        Ok(())
+    }
+
+    run!()
+}
+
+/// Check that `-C panic=abort` is passed to `rustc` when `panic = "abort"` is
+/// set in `profile.release`
+#[test]
+fn panic_abort() {
+    fn run() -> Result<()> {
+        const TARGET: &'static str = "thumbv6m-panic_abort-eabi";
+
+        let project = Project::new(TARGET)?;
+
+        project.cargo_toml(
+            r#"
+[profile.release]
+panic = "abort"
+"#,
+        )?;
+
+        let stderr = project.build_and_get_stderr(Some(TARGET))?;
+
+        assert!(stderr
+            .lines()
+            .filter(|l| !l.starts_with("+") && l.contains("--release"))
+            .all(|l| l.contains("-C") && l.contains("panic=abort")));
+
+        Ok(())
+    }
+
+    run!()
+}
+
+/// Check that the profile in `Xargo.toml` is used for, and only for, the sysroot
+#[test]
+fn xargo_profile() {
+    fn run() -> Result<()> {
+        const TARGET: &str = "thumbv6m-xargo_profile-eabi";
+
+        let project = Project::new(TARGET)?;
+        let stderr = project.build_and_get_stderr(Some(TARGET))?;
+
+        assert!(sysroot_was_built(&stderr, TARGET));
+
+        project.xargo_toml(
+            r#"
+[target.thumbv6m-xargo_profile-eabi.profile]
+opt-level = "s"
+"#,
+        )?;
+
+        let stderr = project.build_and_get_stderr(Some(TARGET))?;
+
+        assert!(sysroot_was_built(&stderr, TARGET));
+        assert!(
+            stderr
+                .lines()
+                .filter(|l| l.contains("--crate-name core"))
+                .all(|l| l.contains("opt-level=s")),
+            "unexpected stderr:\n{}",
+            stderr
+        );
+        assert!(
+            stderr
+                .lines()
+                .filter(|l| l.contains("--crate-name thumbv6m_xargo_profile_eabi"))
+                .all(|l| !l.contains("opt-level=s")),
+            "unexpected stderr:\n{}",
+            stderr
+        );
+
+        Ok(())
+    }
+
+    run!()
+}
+
+/// Check that adding linker arguments doesn't trigger a sysroot rebuild
+#[test]
+fn link_arg() {
+    fn run() -> Result<()> {
+        const TARGET: &'static str = "thumbv6m-link_arg-eabi";
+
+        let project = Project::new(TARGET)?;
+
+        let stderr = project.build_and_get_stderr(Some(TARGET))?;
+
+        assert!(sysroot_was_built(&stderr, TARGET));
+
+        project.config(
+            r#"
+[target.__link_arg]
+rustflags = ["-C", "link-arg=-lfoo"]
+"#,
+        )?;
+
+        let stderr = project.build_and_get_stderr(Some(TARGET))?;
+
+        assert!(!sysroot_was_built(&stderr, TARGET));
+
+        Ok(())
+    }
+
+    run!()
+}
+
+/// The sysroot should be rebuilt if the target specification changed
+#[test]
+fn specification_changed() {
+    fn run() -> Result<()> {
+        const JSON: &'static str = r#"
+{
+    "arch": "arm",
+    "data-layout": "e-m:e-p:32:32-i64:64-v128:64:128-a:0:32-n32-S64",
+    "linker-flavor": "gcc",
+    "llvm-target": "thumbv6m-none-eabi",
+    "max-atomic-width": 0,
+    "os": "none",
+    "panic-strategy": "abort",
+    "target-c-int-width": "32",
+    "target-endian": "little",
+    "target-pointer-width": "32"
+}
+"#;
+        const TARGET: &'static str = "thumbv6m-specification_changed-eabi";
+
+        let project = Project::new(TARGET)?;
+
+        let stderr = project.build_and_get_stderr(Some(TARGET))?;
+
+        assert!(sysroot_was_built(&stderr, TARGET));
+
+        write(
+            &project
+                .td
+                .path()
+                .join("thumbv6m-specification_changed-eabi.json"),
+            false,
+            JSON,
+        )?;
+
+        let stderr = project.build_and_get_stderr(Some(TARGET))?;
+
+        assert!(sysroot_was_built(&stderr, TARGET));
 
         Ok(())
     }
@@ -887,6 +4064,51 @@ features = [\"panic_unwind\"]
     run!()
 }
 
+/// Check that `panic_unwind` is left out of the sysroot when
+/// `panic = "abort"` is set
+#[test]
+fn host_panic_abort() {
+    fn run() -> Result<()> {
+        let project = HProject::new(true)?;
+
+        write(
+            &project.td.path().join("Cargo.toml"),
+            true,
+            r#"
+[profile.dev]
+panic = "abort"
+
+[profile.release]
+panic = "abort"
+"#,
+        )?;
+        project.xargo_toml(
+            "
+[dependencies.std]
+",
+        )?;
+
+        project.build("build")?;
+
+        let libs = home()?
+            .join("HOST/lib/rustlib")
+            .join(&project.host)
+            .join("lib");
+        for entry in
+            fs::read_dir(&libs).chain_err(|| format!("couldn't read {}", libs.display()))?
+        {
+            let name = entry
+                .chain_err(|| "couldn't read a directory entry")?
+                .file_name();
+            assert!(!name.to_string_lossy().starts_with("libpanic_unwind-"));
+        }
+
+        Ok(())
+    }
+
+    run!()
+}
+
 /// Check multi stage sysroot builds with `xargo build`
 #[test]
 fn host_liballoc() {
@@ -947,6 +4169,37 @@ tag = "1.0.47"
     }
 }
 
+/// Check that `xargo-check` has sysroots of its own, so alternating it with
+/// `xargo` rebuilds neither
+#[test]
+fn check_mode_home() {
+    fn run() -> Result<()> {
+        const TARGET: &str = "thumbv6m-check_mode_home-eabi";
+
+        let project = Project::new(TARGET)?;
+        assert!(sysroot_was_built(
+            &project.build_and_get_stderr(Some(TARGET))?,
+            TARGET
+        ));
+
+        xargo_check()?
+            .args(["--target", TARGET])
+            .current_dir(project.td.path())
+            .run()?;
+        assert!(home()?.join("check/lib/rustlib").join(TARGET).exists());
+        assert!(exists("core", TARGET)?);
+
+        assert!(!sysroot_was_built(
+            &project.build_and_get_stderr(Some(TARGET))?,
+            TARGET
+        ));
+
+        Ok(())
+    }
+
+    run!()
+}
+
 #[test]
 fn cargo_check_check() {
     fn run() -> Result<()> {